@@ -8,6 +8,8 @@ use std::{
 };
 
 pub mod map;
+#[cfg(feature = "serde")]
+mod serde_impls;
 
 #[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct RawId(u32);
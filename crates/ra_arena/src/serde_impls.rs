@@ -0,0 +1,33 @@
+//! Serialization support, enabled with the `serde` feature. Arenas are
+//! serialized as sequences of values, and ids as their indices.
+
+use std::marker::PhantomData;
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::{Arena, ArenaId, RawId};
+
+impl Serialize for RawId {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.0.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for RawId {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<RawId, D::Error> {
+        u32::deserialize(deserializer).map(RawId)
+    }
+}
+
+impl<ID: ArenaId, T: Serialize> Serialize for Arena<ID, T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.data.serialize(serializer)
+    }
+}
+
+impl<'de, ID: ArenaId, T: Deserialize<'de>> Deserialize<'de> for Arena<ID, T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Arena<ID, T>, D::Error> {
+        let data = Vec::deserialize(deserializer)?;
+        Ok(Arena { data, _ty: PhantomData })
+    }
+}
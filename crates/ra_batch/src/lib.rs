@@ -19,22 +19,24 @@ fn vfs_root_to_id(r: ra_vfs::VfsRoot) -> SourceRootId {
 
 pub fn load_cargo(root: &Path) -> Result<(AnalysisHost, FxHashMap<SourceRootId, PackageRoot>)> {
     let root = std::env::current_dir()?.join(root);
-    let ws = ProjectWorkspace::discover(root.as_ref())?;
+    let ws = ProjectWorkspace::discover(root.as_ref(), &Default::default())?;
     let project_roots = ws.to_roots();
     let (mut vfs, roots) = Vfs::new(
         project_roots
             .iter()
             .map(|pkg_root| {
-                RootEntry::new(
-                    pkg_root.path().clone(),
-                    RustPackageFilterBuilder::default()
-                        .set_member(pkg_root.is_member())
-                        .into_vfs_filter(),
-                )
+                let mut filter =
+                    RustPackageFilterBuilder::default().set_member(pkg_root.is_member());
+                for dir in pkg_root.exclude() {
+                    if let Ok(dir) = dir.strip_prefix(pkg_root.path()) {
+                        filter = filter.exclude_dir(dir);
+                    }
+                }
+                RootEntry::new(pkg_root.path().clone(), filter.into_vfs_filter())
             })
             .collect(),
     );
-    let crate_graph = ws.to_crate_graph(&mut |path: &Path| {
+    let mut crate_graph = ws.to_crate_graph(&mut |path: &Path| {
         let vfs_file = vfs.load(path);
         log::debug!("vfs file {:?} -> {:?}", path, vfs_file);
         vfs_file.map(vfs_file_to_id)
@@ -53,6 +55,11 @@ pub fn load_cargo(root: &Path) -> Result<(AnalysisHost, FxHashMap<SourceRootId,
             (source_root_id, project_root)
         })
         .collect::<FxHashMap<_, _>>();
+    // Paths outside of a crate's sources, like its `OUT_DIR`, are looked up
+    // in the other roots.
+    for (&root, pkg_root) in source_roots.iter() {
+        crate_graph.set_extern_path(pkg_root.path().clone(), root);
+    }
     let host = load(&source_roots, crate_graph, &mut vfs);
     Ok((host, source_roots))
 }
@@ -5,6 +5,11 @@
 /// Note that neither this module, nor any other part of the analyzer's core do
 /// actual IO. See `vfs` and `project_model` in the `ra_lsp_server` crate for how
 /// actual IO is done and lowered to input.
+use std::{
+    collections::VecDeque,
+    path::{Path, PathBuf},
+};
+
 use relative_path::RelativePathBuf;
 use rustc_hash::FxHashMap;
 
@@ -50,7 +55,7 @@ impl SourceRoot {
 
 /// `CrateGraph` is a bit of information which turns a set of text files into a
 /// number of Rust crates. Each crate is defined by the `FileId` of its root module,
-/// the set of cfg flags (not yet implemented) and the set of dependencies. Note
+/// the set of cfg flags and the set of dependencies. Note
 /// that, due to cfg's, there might be several crates for a single `FileId`! As
 /// in the rust-lang proper, a crate does not have a name. Instead, names are
 /// specified on dependency edges. That is, a crate might be known under
@@ -62,6 +67,7 @@ impl SourceRoot {
 #[derive(Debug, Clone, Default, PartialEq, Eq)]
 pub struct CrateGraph {
     arena: FxHashMap<CrateId, CrateData>,
+    extern_source: ExternSource,
 }
 
 #[derive(Debug)]
@@ -85,16 +91,102 @@ impl Edition {
     }
 }
 
+/// The set of cfg options a crate is compiled with: atoms like `unix` or
+/// `test`, and key-value pairs like `feature = "std"`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CfgOptions {
+    atoms: FxHashSet<SmolStr>,
+    key_values: FxHashSet<(SmolStr, SmolStr)>,
+}
+
+impl CfgOptions {
+    pub fn is_atom_enabled(&self, name: &str) -> bool {
+        self.atoms.contains(name)
+    }
+
+    pub fn is_key_value_enabled(&self, key: &str, value: &str) -> bool {
+        self.key_values.contains(&(SmolStr::from(key), SmolStr::from(value)))
+    }
+
+    pub fn insert_atom(&mut self, name: SmolStr) {
+        self.atoms.insert(name);
+    }
+
+    pub fn remove_atom(&mut self, name: &str) {
+        self.atoms.remove(name);
+    }
+
+    pub fn insert_key_value(&mut self, key: SmolStr, value: SmolStr) {
+        self.key_values.insert((key, value));
+    }
+}
+
+/// The compile-time environment of a crate, as observed by `env!`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Env {
+    entries: FxHashMap<String, String>,
+}
+
+impl Env {
+    pub fn set(&mut self, key: &str, value: String) {
+        self.entries.insert(key.to_string(), value);
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.entries.get(key).map(String::as_str)
+    }
+}
+
+/// Source roots keyed by their absolute path, for resolving paths which point
+/// outside of a crate's own sources, like the `OUT_DIR` of a build script.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ExternSource {
+    extern_paths: FxHashMap<PathBuf, SourceRootId>,
+}
+
+impl ExternSource {
+    /// Returns the source root containing `path` and the path relative to it.
+    /// Source roots can be nested, so this picks the nearest one.
+    pub fn extern_path(&self, path: &Path) -> Option<(SourceRootId, RelativePathBuf)> {
+        let (root_path, &root) = self
+            .extern_paths
+            .iter()
+            .filter(|(root_path, _)| path.starts_with(root_path))
+            .max_by_key(|(root_path, _)| root_path.components().count())?;
+        let rel_path = path.strip_prefix(root_path).ok()?;
+        Some((root, RelativePathBuf::from_path(rel_path).ok()?))
+    }
+
+    /// The absolute path of `root`, if it is known.
+    pub fn root_path(&self, root: SourceRootId) -> Option<&Path> {
+        self.extern_paths.iter().find(|(_, &it)| it == root).map(|(path, _)| path.as_path())
+    }
+
+    pub fn set_extern_path(&mut self, root_path: PathBuf, root: SourceRootId) {
+        self.extern_paths.insert(root_path, root);
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 struct CrateData {
     file_id: FileId,
     edition: Edition,
     dependencies: Vec<Dependency>,
+    cfg_options: CfgOptions,
+    env: Env,
+    display_name: Option<SmolStr>,
 }
 
 impl CrateData {
     fn new(file_id: FileId, edition: Edition) -> CrateData {
-        CrateData { file_id, edition, dependencies: Vec::new() }
+        CrateData {
+            file_id,
+            edition,
+            dependencies: Vec::new(),
+            cfg_options: CfgOptions::default(),
+            env: Env::default(),
+            display_name: None,
+        }
     }
 
     fn add_dep(&mut self, name: SmolStr, crate_id: CrateId) {
@@ -151,6 +243,55 @@ impl CrateGraph {
         self.arena[&crate_id].edition
     }
 
+    /// Whether `#[cfg(test)]` is enabled for the crate, which is only the case
+    /// for crates compiled with `--test`.
+    pub fn cfg_test(&self, crate_id: CrateId) -> bool {
+        self.cfg_options(crate_id).is_atom_enabled("test")
+    }
+
+    pub fn set_cfg_test(&mut self, crate_id: CrateId, enabled: bool) {
+        let cfg_options = &mut self.arena.get_mut(&crate_id).unwrap().cfg_options;
+        if enabled {
+            cfg_options.insert_atom("test".into());
+        } else {
+            cfg_options.remove_atom("test");
+        }
+    }
+
+    pub fn cfg_options(&self, crate_id: CrateId) -> &CfgOptions {
+        &self.arena[&crate_id].cfg_options
+    }
+
+    pub fn set_cfg_options(&mut self, crate_id: CrateId, cfg_options: CfgOptions) {
+        self.arena.get_mut(&crate_id).unwrap().cfg_options = cfg_options;
+    }
+
+    pub fn env(&self, crate_id: CrateId) -> &Env {
+        &self.arena[&crate_id].env
+    }
+
+    pub fn set_env(&mut self, crate_id: CrateId, env: Env) {
+        self.arena.get_mut(&crate_id).unwrap().env = env;
+    }
+
+    pub fn extern_source(&self) -> &ExternSource {
+        &self.extern_source
+    }
+
+    pub fn set_extern_path(&mut self, root_path: PathBuf, root: SourceRootId) {
+        self.extern_source.set_extern_path(root_path, root);
+    }
+
+    /// A human-readable name of the crate, for the UI. It doesn't have to be
+    /// a valid identifier.
+    pub fn display_name(&self, crate_id: CrateId) -> Option<&str> {
+        self.arena[&crate_id].display_name.as_ref().map(SmolStr::as_str)
+    }
+
+    pub fn set_display_name(&mut self, crate_id: CrateId, display_name: SmolStr) {
+        self.arena.get_mut(&crate_id).unwrap().display_name = Some(display_name);
+    }
+
     // FIXME: this only finds one crate with the given root; we could have multiple
     pub fn crate_id_for_crate_root(&self, file_id: FileId) -> Option<CrateId> {
         let (&crate_id, _) = self.arena.iter().find(|(_crate_id, data)| data.file_id == file_id)?;
@@ -164,6 +305,32 @@ impl CrateGraph {
         self.arena[&crate_id].dependencies.iter()
     }
 
+    /// Returns all crates which depend on `crate_id`, directly or indirectly,
+    /// ordered by id. These are the crates a change in `crate_id` can affect.
+    pub fn crates_depending_on(&self, crate_id: CrateId) -> Vec<CrateId> {
+        let mut rev_deps: FxHashMap<CrateId, Vec<CrateId>> = FxHashMap::default();
+        for (&krate, data) in self.arena.iter() {
+            for dep in data.dependencies.iter() {
+                rev_deps.entry(dep.crate_id).or_default().push(krate);
+            }
+        }
+
+        let mut res = Vec::new();
+        let mut visited = FxHashSet::default();
+        let mut queue = VecDeque::new();
+        queue.push_back(crate_id);
+        while let Some(krate) = queue.pop_front() {
+            for &rev_dep in rev_deps.get(&krate).into_iter().flatten() {
+                if visited.insert(rev_dep) {
+                    res.push(rev_dep);
+                    queue.push_back(rev_dep);
+                }
+            }
+        }
+        res.sort();
+        res
+    }
+
     /// Extends this crate graph by adding a complete disjoint second crate
     /// graph.
     pub fn extend(&mut self, other: CrateGraph) {
@@ -175,6 +342,7 @@ impl CrateGraph {
             }
             (new_id, data)
         }));
+        self.extern_source.extern_paths.extend(other.extern_source.extern_paths);
     }
 
     fn dfs_find(&self, target: CrateId, from: CrateId, visited: &mut FxHashSet<CrateId>) -> bool {
@@ -220,4 +388,19 @@ mod tests {
         assert!(graph.add_dep(crate1, SmolStr::new("crate2"), crate2).is_ok());
         assert!(graph.add_dep(crate2, SmolStr::new("crate3"), crate3).is_ok());
     }
+
+    #[test]
+    fn reverse_dependencies_are_transitive() {
+        let mut graph = CrateGraph::default();
+        let crate1 = graph.add_crate_root(FileId(1u32), Edition2018);
+        let crate2 = graph.add_crate_root(FileId(2u32), Edition2018);
+        let crate3 = graph.add_crate_root(FileId(3u32), Edition2018);
+        let crate4 = graph.add_crate_root(FileId(4u32), Edition2018);
+        assert!(graph.add_dep(crate1, SmolStr::new("crate2"), crate2).is_ok());
+        assert!(graph.add_dep(crate2, SmolStr::new("crate3"), crate3).is_ok());
+        assert!(graph.add_dep(crate4, SmolStr::new("crate3"), crate3).is_ok());
+        assert_eq!(graph.crates_depending_on(crate3), vec![crate1, crate2, crate4]);
+        assert_eq!(graph.crates_depending_on(crate2), vec![crate1]);
+        assert!(graph.crates_depending_on(crate1).is_empty());
+    }
 }
@@ -10,7 +10,10 @@ use relative_path::RelativePathBuf;
 
 pub use crate::{
     cancellation::Canceled,
-    input::{CrateGraph, CrateId, Dependency, Edition, FileId, SourceRoot, SourceRootId},
+    input::{
+        CfgOptions, CrateGraph, CrateId, Dependency, Edition, Env, ExternSource, FileId,
+        SourceRoot, SourceRootId,
+    },
 };
 pub use ::salsa;
 
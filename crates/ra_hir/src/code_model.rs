@@ -4,7 +4,11 @@ pub(crate) mod docs;
 use std::sync::Arc;
 
 use ra_db::{CrateId, Edition, FileId, SourceRootId};
-use ra_syntax::ast::{self, NameOwner, TypeAscriptionOwner};
+use ra_syntax::{
+    ast::{self, NameOwner, TypeAscriptionOwner},
+    TextRange,
+};
+use rustc_hash::FxHashMap;
 
 use crate::{
     adt::{EnumVariantId, StructFieldId, VariantDef},
@@ -73,6 +77,51 @@ impl Crate {
         crate_graph.edition(self.crate_id)
     }
 
+    /// Module resolution diagnostics of all modules of the crate, grouped by
+    /// the file containing the offending declaration.
+    pub fn diagnostics_by_file(
+        self,
+        db: &impl HirDatabase,
+    ) -> FxHashMap<FileId, Vec<(TextRange, String)>> {
+        let def_map = db.crate_def_map(self);
+        let mut res = FxHashMap::default();
+        {
+            let mut sink = DiagnosticSink::new(|d| {
+                let file_id = d.file().original_file(db);
+                res.entry(file_id).or_insert_with(Vec::new).push((d.highlight_range(), d.message()))
+            });
+            for module_id in def_map.modules() {
+                def_map.add_diagnostics(db, module_id, &mut sink);
+            }
+        }
+        res
+    }
+
+    /// Module resolution diagnostics of all crates in the crate graph, sorted
+    /// by source root, file and position.
+    pub fn all_module_diagnostics(
+        db: &impl HirDatabase,
+    ) -> Vec<(SourceRootId, FileId, TextRange, String)> {
+        let mut res = Vec::new();
+        for crate_id in db.crate_graph().iter() {
+            let krate = Crate { crate_id };
+            for (file_id, diagnostics) in krate.diagnostics_by_file(db) {
+                let source_root = db.file_source_root(file_id);
+                res.extend(
+                    diagnostics
+                        .into_iter()
+                        .map(|(range, message)| (source_root, file_id, range, message)),
+                );
+            }
+        }
+        res.sort_by_key(|(source_root, file_id, range, _)| {
+            (source_root.0, *file_id, range.start())
+        });
+        // A file can be part of several crates.
+        res.dedup();
+        res
+    }
+
     // FIXME: should this be in source_binder?
     pub fn source_root_crates(db: &impl DefDatabase, source_root: SourceRootId) -> Vec<Crate> {
         let crate_ids = db.source_root_crates(source_root);
@@ -207,6 +256,14 @@ impl Module {
         Some(self.krate)
     }
 
+    /// Returns the root modules of all crates in the crate graph.
+    pub fn crate_roots(db: &impl DefDatabase) -> Vec<Module> {
+        let crate_graph = db.crate_graph();
+        let mut crate_ids: Vec<_> = crate_graph.iter().collect();
+        crate_ids.sort();
+        crate_ids.into_iter().filter_map(|crate_id| Crate { crate_id }.root_module(db)).collect()
+    }
+
     /// Topmost parent of this module. Every module has a `crate_root`, but some
     /// might be missing `krate`. This can happen if a module's file is not included
     /// in the module tree of any target in `Cargo.toml`.
@@ -241,13 +298,13 @@ impl Module {
     }
 
     pub fn path_to_root(self, db: &impl HirDatabase) -> Vec<Module> {
-        let mut res = vec![self];
-        let mut curr = self;
-        while let Some(next) = curr.parent(db) {
-            res.push(next);
-            curr = next
-        }
-        res
+        let def_map = db.crate_def_map(self.krate);
+        def_map.ancestors(self.module_id).map(|it| self.with_module_id(it)).collect()
+    }
+
+    /// Number of modules between this module and the crate root.
+    pub fn depth(self, db: &impl DefDatabase) -> usize {
+        db.crate_def_map(self.krate).depth(self.module_id)
     }
 
     /// Returns a `ModuleScope`: a set of items, visible in this module.
@@ -10,7 +10,7 @@ use crate::{
     ids,
     impl_block::{ImplBlock, ImplSourceMap, ModuleImplBlocks},
     lang_item::{LangItemTarget, LangItems},
-    nameres::{CrateDefMap, ImportSourceMap, Namespace, RawItems},
+    nameres::{CrateDefMap, ImportSourceMap, ModuleNameResolverRef, Namespace, RawItems},
     traits::TraitData,
     ty::{
         method_resolution::CrateImplBlocks, CallableDef, FnSig, GenericPredicate, InferenceResult,
@@ -106,6 +106,10 @@ pub trait DefDatabase: InternDatabase {
     #[salsa::invoke(RawItems::raw_items_query)]
     fn raw_items(&self, file_id: HirFileId) -> Arc<RawItems>;
 
+    /// Decides which files `mod foo;` declarations refer to.
+    #[salsa::input]
+    fn module_name_resolver(&self) -> ModuleNameResolverRef;
+
     #[salsa::invoke(CrateDefMap::crate_def_map_query)]
     fn crate_def_map(&self, krate: Crate) -> Arc<CrateDefMap>;
 
@@ -1,6 +1,6 @@
 use std::{any::Any, fmt};
 
-use ra_syntax::{ast, AstNode, AstPtr, SyntaxNode, SyntaxNodePtr, TextRange};
+use ra_syntax::{ast, AstNode, AstPtr, SmolStr, SyntaxNode, SyntaxNodePtr, TextRange};
 use relative_path::RelativePathBuf;
 
 use crate::{HirDatabase, HirFileId, Name};
@@ -120,6 +120,118 @@ impl Diagnostic for UnresolvedModule {
     }
 }
 
+#[derive(Debug)]
+pub struct ModuleCaseMismatch {
+    pub file: HirFileId,
+    pub decl: AstPtr<ast::Module>,
+    pub declared: RelativePathBuf,
+    pub on_disk: RelativePathBuf,
+}
+
+impl Diagnostic for ModuleCaseMismatch {
+    fn message(&self) -> String {
+        format!("module file is named `{}` instead of `{}`", self.on_disk, self.declared)
+    }
+    fn file(&self) -> HirFileId {
+        self.file
+    }
+    fn syntax_node_ptr(&self) -> SyntaxNodePtr {
+        self.decl.into()
+    }
+    fn as_any(&self) -> &(dyn Any + Send + 'static) {
+        self
+    }
+}
+
+#[derive(Debug)]
+pub struct ModuleFileParseError {
+    pub file: HirFileId,
+    pub decl: AstPtr<ast::Module>,
+}
+
+impl Diagnostic for ModuleFileParseError {
+    fn message(&self) -> String {
+        "module file has syntax errors".to_string()
+    }
+    fn file(&self) -> HirFileId {
+        self.file
+    }
+    fn syntax_node_ptr(&self) -> SyntaxNodePtr {
+        self.decl.into()
+    }
+    fn as_any(&self) -> &(dyn Any + Send + 'static) {
+        self
+    }
+}
+
+#[derive(Debug)]
+pub struct ModuleCycle {
+    pub file: HirFileId,
+    pub decl: AstPtr<ast::Module>,
+}
+
+impl Diagnostic for ModuleCycle {
+    fn message(&self) -> String {
+        "circular modules: the module file is already one of its ancestors".to_string()
+    }
+    fn file(&self) -> HirFileId {
+        self.file
+    }
+    fn syntax_node_ptr(&self) -> SyntaxNodePtr {
+        self.decl.into()
+    }
+    fn as_any(&self) -> &(dyn Any + Send + 'static) {
+        self
+    }
+}
+
+#[derive(Debug)]
+pub struct UnresolvedGeneratedModule {
+    pub file: HirFileId,
+    pub decl: AstPtr<ast::Module>,
+    pub path: SmolStr,
+}
+
+impl Diagnostic for UnresolvedGeneratedModule {
+    fn message(&self) -> String {
+        format!("can't resolve module generated in OUT_DIR: {}", self.path)
+    }
+    fn file(&self) -> HirFileId {
+        self.file
+    }
+    fn syntax_node_ptr(&self) -> SyntaxNodePtr {
+        self.decl.into()
+    }
+    fn as_any(&self) -> &(dyn Any + Send + 'static) {
+        self
+    }
+}
+
+/// A module file which exists in another source root, so it isn't part of the
+/// declaring one. Files excluded from the VFS aren't known at all and are
+/// reported as unresolved modules instead.
+#[derive(Debug)]
+pub struct ModuleFileNotIndexed {
+    pub file: HirFileId,
+    pub decl: AstPtr<ast::Module>,
+    pub path: RelativePathBuf,
+}
+
+impl Diagnostic for ModuleFileNotIndexed {
+    fn message(&self) -> String {
+        format!("module file exists in another source root: {}", self.path)
+    }
+    fn file(&self) -> HirFileId {
+        self.file
+    }
+    fn syntax_node_ptr(&self) -> SyntaxNodePtr {
+        self.decl.into()
+    }
+    fn as_any(&self) -> &(dyn Any + Send + 'static) {
+        self
+    }
+}
+
 #[derive(Debug)]
 pub struct MissingFields {
     pub file: HirFileId,
@@ -55,6 +55,14 @@ impl HirFileId {
         }
     }
 
+    /// Returns the id of the file, unless it was produced by a macro.
+    pub(crate) fn as_file_id(self) -> Option<FileId> {
+        match self.0 {
+            HirFileIdRepr::File(file_id) => Some(file_id),
+            HirFileIdRepr::Macro(_) => None,
+        }
+    }
+
     pub(crate) fn parse_or_expand_query(
         db: &impl AstDatabase,
         file_id: HirFileId,
@@ -62,7 +62,10 @@ pub use self::{
     ids::{HirFileId, MacroCallId, MacroCallLoc, MacroDefId, MacroFile},
     impl_block::{ImplBlock, ImplItem},
     name::Name,
-    nameres::{ImportId, Namespace, PerNs},
+    nameres::{
+        DefMapDiff, DefaultModuleNameResolver, ImportId, ModuleKey, ModuleNameResolver,
+        ModuleNameResolverRef, ModuleProblem, Namespace, PerNs,
+    },
     path::{Path, PathKind},
     resolve::Resolution,
     source_binder::{PathResolution, ScopeEntryWithSyntax, SourceAnalyzer},
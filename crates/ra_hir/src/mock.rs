@@ -8,7 +8,10 @@ use relative_path::RelativePathBuf;
 use rustc_hash::FxHashMap;
 use test_utils::{extract_offset, parse_fixture, CURSOR_MARKER};
 
-use crate::{db, diagnostics::DiagnosticSink};
+use crate::{
+    db::{self, DefDatabase},
+    diagnostics::DiagnosticSink,
+};
 
 pub const WORKSPACE: SourceRootId = SourceRootId(0);
 
@@ -202,6 +205,7 @@ impl Default for MockDatabase {
             files: FxHashMap::default(),
         };
         db.set_crate_graph(Default::default());
+        db.set_module_name_resolver(Default::default());
         db
     }
 }
@@ -53,7 +53,7 @@ mod collector;
 #[cfg(test)]
 mod tests;
 
-use std::sync::Arc;
+use std::{fmt, sync::Arc};
 
 use once_cell::sync::Lazy;
 use ra_arena::{impl_arena_id, Arena, RawId};
@@ -61,6 +61,7 @@ use ra_db::{Edition, FileId};
 use ra_prof::profile;
 use ra_syntax::ast;
 use rustc_hash::{FxHashMap, FxHashSet};
+use serde::{Deserialize, Serialize};
 use test_utils::tested_by;
 
 use crate::{
@@ -88,6 +89,10 @@ pub struct CrateDefMap {
     extern_prelude: FxHashMap<Name, ModuleDef>,
     root: CrateModuleId,
     modules: Arena<CrateModuleId, ModuleData>,
+    /// Maps each file that defines a non-inline module to its module. If a file
+    /// is included several times (via `#[path]`), the module which was
+    /// collected first wins.
+    file_to_module: FxHashMap<FileId, CrateModuleId>,
 
     /// Some macros are not well-behavior, which leads to infinite loop
     /// e.g. macro_rules! foo { ($ty:ty) => { foo!($ty); } }
@@ -104,6 +109,78 @@ pub struct CrateDefMap {
     diagnostics: Vec<DefDiagnostic>,
 }
 
+/// Changes between two versions of a `CrateDefMap`, for updating data derived
+/// from it incrementally. `CrateModuleId`s are not stable across
+/// recomputations, so modules are identified by `ModuleKey`s instead.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct DefMapDiff {
+    /// Modules which exist only in the new map. A module is linked to its
+    /// parent by the last segment of its path, so these are the new links too.
+    pub added_modules: Vec<ModuleKey>,
+    pub removed_modules: Vec<ModuleKey>,
+    /// Modules which exist in both maps, but whose diagnostics differ.
+    pub changed_diagnostics: Vec<ModuleKey>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ModuleKey {
+    /// Names of the module and its ancestors, starting below the crate root.
+    /// Empty for the crate root itself.
+    pub path: Vec<Name>,
+    /// The file the module is defined in, `None` for inline modules.
+    pub definition: Option<FileId>,
+}
+
+/// Lists the files which can define `mod foo;`, for build setups which name
+/// module files differently (for example, `foo.gen.rs`).
+pub trait ModuleNameResolver: fmt::Debug + Send + Sync {
+    /// Paths of the file of the submodule `name`, relative to the directory
+    /// owned by the declaring file, in order of preference. Only used for
+    /// declarations in directory owners: crate roots and `mod.rs` files.
+    fn candidates(&self, name: &str) -> Vec<String> {
+        vec![format!("{}.rs", name), format!("{}/mod.rs", name)]
+    }
+}
+
+/// Resolves `mod foo;` to `foo.rs` or `foo/mod.rs`, like rustc.
+#[derive(Debug)]
+pub struct DefaultModuleNameResolver;
+
+impl ModuleNameResolver for DefaultModuleNameResolver {}
+
+/// A `ModuleNameResolver` which can be stored in the database. Resolvers are
+/// compared by identity.
+#[derive(Debug, Clone)]
+pub struct ModuleNameResolverRef(pub Arc<dyn ModuleNameResolver>);
+
+impl Default for ModuleNameResolverRef {
+    fn default() -> ModuleNameResolverRef {
+        ModuleNameResolverRef(Arc::new(DefaultModuleNameResolver))
+    }
+}
+
+impl PartialEq for ModuleNameResolverRef {
+    fn eq(&self, other: &ModuleNameResolverRef) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for ModuleNameResolverRef {}
+
+/// A module resolution diagnostic detached from the database, so that it can
+/// be sent to another process. Files are identified by the raw value of their
+/// `FileId`, and ranges are `[start, end]` offsets of the `mod` declaration.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum ModuleProblem {
+    UnresolvedModule { file: u32, range: [u32; 2], candidate: String },
+    ModuleCaseMismatch { file: u32, range: [u32; 2], declared: String, on_disk: String },
+    ModuleFileParseError { file: u32, range: [u32; 2] },
+    ModuleCycle { file: u32, range: [u32; 2] },
+    UnresolvedGeneratedModule { file: u32, range: [u32; 2], path: String },
+    ModuleFileNotIndexed { file: u32, range: [u32; 2], path: String },
+}
+
 impl std::ops::Index<CrateModuleId> for CrateDefMap {
     type Output = ModuleData;
     fn index(&self, id: CrateModuleId) -> &ModuleData {
@@ -127,11 +204,18 @@ pub(crate) struct ModuleData {
     ///
     /// Note that non-inline modules, by definition, live inside non-macro file.
     pub(crate) definition: Option<FileId>,
+    /// For `#[macro_use]` modules, the position of the declaration among the
+    /// items of the parent module. Macros of such a module are visible in the
+    /// items of the parent which come after it.
+    pub(crate) macro_use: Option<usize>,
 }
 
 #[derive(Debug, Default, PartialEq, Eq, Clone)]
 pub struct ModuleScope {
     items: FxHashMap<Name, Resolution>,
+    /// Names of `items` in the order they were added: declarations in source
+    /// order, followed by imports in the order they were resolved.
+    order: Vec<Name>,
     macros: FxHashMap<Name, MacroDef>,
 }
 
@@ -146,9 +230,19 @@ static BUILTIN_SCOPE: Lazy<FxHashMap<Name, Resolution>> = Lazy::new(|| {
 
 impl ModuleScope {
     pub fn entries<'a>(&'a self) -> impl Iterator<Item = (&'a Name, &'a Resolution)> + 'a {
-        //FIXME: shadowing
-        self.items.iter().chain(BUILTIN_SCOPE.iter())
+        let builtins =
+            BUILTIN_SCOPE.iter().filter(move |(name, _)| !self.items.contains_key(*name));
+        self.items.iter().chain(builtins)
     }
+    /// Like `entries`, but only the items of the module, in the order they
+    /// are declared.
+    pub fn entries_in_source_order<'a>(
+        &'a self,
+    ) -> impl Iterator<Item = (&'a Name, &'a Resolution)> + 'a {
+        self.order.iter().map(move |name| (name, &self.items[name]))
+    }
+    /// Looks up a single name, with items of the module shadowing builtin
+    /// types.
     pub fn get(&self, name: &Name) -> Option<&Resolution> {
         self.items.get(name).or_else(|| BUILTIN_SCOPE.get(name))
     }
@@ -244,6 +338,7 @@ impl CrateDefMap {
                 prelude: None,
                 root,
                 modules,
+                file_to_module: FxHashMap::default(),
                 poison_macros: FxHashSet::default(),
                 diagnostics: Vec::new(),
             }
@@ -272,6 +367,77 @@ impl CrateDefMap {
         &self.extern_prelude
     }
 
+    pub(crate) fn modules(&self) -> impl Iterator<Item = CrateModuleId> + '_ {
+        self.modules.iter().map(|(id, _data)| id)
+    }
+
+    /// Returns `module` and its ancestors, up to and including the crate root.
+    pub(crate) fn ancestors(
+        &self,
+        module: CrateModuleId,
+    ) -> impl Iterator<Item = CrateModuleId> + '_ {
+        std::iter::successors(Some(module), move |&it| self.modules[it].parent)
+    }
+
+    /// Number of ancestors of `module`: zero for the crate root.
+    pub(crate) fn depth(&self, module: CrateModuleId) -> usize {
+        self.ancestors(module).count() - 1
+    }
+
+    /// Computes what changed since the `old` version of this map.
+    pub fn diff(&self, old: &CrateDefMap) -> DefMapDiff {
+        let new_keys = self.module_keys();
+        let old_keys = old.module_keys();
+        let mut diff = DefMapDiff::default();
+        for (key, &module) in new_keys.iter() {
+            let old_module = match old_keys.get(key) {
+                Some(&it) => it,
+                None => {
+                    diff.added_modules.push(key.clone());
+                    continue;
+                }
+            };
+            let new_diagnostics: Vec<_> =
+                self.diagnostics.iter().filter(|it| it.module() == module).collect();
+            let old_diagnostics: Vec<_> =
+                old.diagnostics.iter().filter(|it| it.module() == old_module).collect();
+            let unchanged = new_diagnostics.len() == old_diagnostics.len()
+                && new_diagnostics
+                    .iter()
+                    .zip(old_diagnostics.iter())
+                    .all(|(new, old)| new.eq_ignoring_module(old));
+            if !unchanged {
+                diff.changed_diagnostics.push(key.clone());
+            }
+        }
+        diff.removed_modules =
+            old_keys.keys().filter(|key| !new_keys.contains_key(key)).cloned().collect();
+        diff.added_modules.sort();
+        diff.removed_modules.sort();
+        diff.changed_diagnostics.sort();
+        diff
+    }
+
+    fn module_keys(&self) -> FxHashMap<ModuleKey, CrateModuleId> {
+        self.modules()
+            .map(|module| {
+                let mut path: Vec<Name> = self
+                    .ancestors(module)
+                    .filter_map(|it| {
+                        let parent = self.modules[it].parent?;
+                        let (name, _) = self.modules[parent]
+                            .children
+                            .iter()
+                            .find(|(_, child)| **child == it)?;
+                        Some(name.clone())
+                    })
+                    .collect();
+                path.reverse();
+                (ModuleKey { path, definition: self.modules[module].definition }, module)
+            })
+            .collect()
+    }
+
     pub(crate) fn add_diagnostics(
         &self,
         db: &(impl DefDatabase + AstDatabase),
@@ -281,18 +447,29 @@ impl CrateDefMap {
         self.diagnostics.iter().for_each(|it| it.add_to(db, module, sink))
     }
 
+    /// Diagnostics of all modules in a serializable form.
+    pub fn problems(&self, db: &(impl DefDatabase + AstDatabase)) -> Vec<ModuleProblem> {
+        self.diagnostics.iter().map(|it| it.to_problem(db)).collect()
+    }
+
+    /// Returns the module whose definition is the given file.
+    pub(crate) fn module_for_file(&self, file_id: FileId) -> Option<CrateModuleId> {
+        self.file_to_module.get(&file_id).copied()
+    }
+
     pub(crate) fn find_module_by_source(
         &self,
         file_id: HirFileId,
         decl_id: Option<AstId<ast::Module>>,
     ) -> Option<CrateModuleId> {
-        let (module_id, _module_data) = self.modules.iter().find(|(_module_id, module_data)| {
-            if decl_id.is_some() {
-                module_data.declaration == decl_id
-            } else {
-                module_data.definition.map(|it| it.into()) == Some(file_id)
-            }
-        })?;
+        if decl_id.is_none() {
+            // Macro files don't define modules.
+            return file_id.as_file_id().and_then(|file_id| self.module_for_file(file_id));
+        }
+        let (module_id, _module_data) = self
+            .modules
+            .iter()
+            .find(|(_module_id, module_data)| module_data.declaration == decl_id)?;
         Some(module_id)
     }
 
@@ -508,12 +685,15 @@ impl CrateDefMap {
 }
 
 mod diagnostics {
-    use ra_syntax::{ast, AstPtr};
+    use ra_syntax::{ast, AstNode, AstPtr, SmolStr};
     use relative_path::RelativePathBuf;
 
     use crate::{
-        diagnostics::{DiagnosticSink, UnresolvedModule},
-        nameres::CrateModuleId,
+        diagnostics::{
+            DiagnosticSink, ModuleCaseMismatch, ModuleCycle, ModuleFileNotIndexed,
+            ModuleFileParseError, UnresolvedGeneratedModule, UnresolvedModule,
+        },
+        nameres::{CrateModuleId, ModuleProblem},
         AstDatabase, AstId, DefDatabase,
     };
 
@@ -524,6 +704,30 @@ mod diagnostics {
             declaration: AstId<ast::Module>,
             candidate: RelativePathBuf,
         },
+        ModuleCaseMismatch {
+            module: CrateModuleId,
+            declaration: AstId<ast::Module>,
+            declared: RelativePathBuf,
+            on_disk: RelativePathBuf,
+        },
+        ModuleFileParseError {
+            module: CrateModuleId,
+            declaration: AstId<ast::Module>,
+        },
+        ModuleCycle {
+            module: CrateModuleId,
+            declaration: AstId<ast::Module>,
+        },
+        UnresolvedGeneratedModule {
+            module: CrateModuleId,
+            declaration: AstId<ast::Module>,
+            path: SmolStr,
+        },
+        ModuleFileNotIndexed {
+            module: CrateModuleId,
+            declaration: AstId<ast::Module>,
+            path: RelativePathBuf,
+        },
     }
 
     impl DefDiagnostic {
@@ -545,6 +749,168 @@ mod diagnostics {
                         candidate: candidate.clone(),
                     })
                 }
+                DefDiagnostic::ModuleCaseMismatch { module, declaration, declared, on_disk } => {
+                    if *module != target_module {
+                        return;
+                    }
+                    let decl = declaration.to_node(db);
+                    sink.push(ModuleCaseMismatch {
+                        file: declaration.file_id(),
+                        decl: AstPtr::new(&decl),
+                        declared: declared.clone(),
+                        on_disk: on_disk.clone(),
+                    })
+                }
+                DefDiagnostic::ModuleFileParseError { module, declaration } => {
+                    if *module != target_module {
+                        return;
+                    }
+                    let decl = declaration.to_node(db);
+                    sink.push(ModuleFileParseError {
+                        file: declaration.file_id(),
+                        decl: AstPtr::new(&decl),
+                    })
+                }
+                DefDiagnostic::ModuleCycle { module, declaration } => {
+                    if *module != target_module {
+                        return;
+                    }
+                    let decl = declaration.to_node(db);
+                    sink.push(ModuleCycle { file: declaration.file_id(), decl: AstPtr::new(&decl) })
+                }
+                DefDiagnostic::UnresolvedGeneratedModule { module, declaration, path } => {
+                    if *module != target_module {
+                        return;
+                    }
+                    let decl = declaration.to_node(db);
+                    sink.push(UnresolvedGeneratedModule {
+                        file: declaration.file_id(),
+                        decl: AstPtr::new(&decl),
+                        path: path.clone(),
+                    })
+                }
+                DefDiagnostic::ModuleFileNotIndexed { module, declaration, path } => {
+                    if *module != target_module {
+                        return;
+                    }
+                    let decl = declaration.to_node(db);
+                    sink.push(ModuleFileNotIndexed {
+                        file: declaration.file_id(),
+                        decl: AstPtr::new(&decl),
+                        path: path.clone(),
+                    })
+                }
+            }
+        }
+
+        pub(super) fn to_problem(&self, db: &(impl DefDatabase + AstDatabase)) -> ModuleProblem {
+            let location = |declaration: &AstId<ast::Module>| {
+                let file = declaration.file_id().original_file(db).0;
+                let range = declaration.to_node(db).syntax().text_range();
+                (file, [range.start().to_usize() as u32, range.end().to_usize() as u32])
+            };
+            match self {
+                DefDiagnostic::UnresolvedModule { declaration, candidate, .. } => {
+                    let (file, range) = location(declaration);
+                    ModuleProblem::UnresolvedModule {
+                        file,
+                        range,
+                        candidate: candidate.as_str().to_string(),
+                    }
+                }
+                DefDiagnostic::ModuleCaseMismatch { declaration, declared, on_disk, .. } => {
+                    let (file, range) = location(declaration);
+                    ModuleProblem::ModuleCaseMismatch {
+                        file,
+                        range,
+                        declared: declared.as_str().to_string(),
+                        on_disk: on_disk.as_str().to_string(),
+                    }
+                }
+                DefDiagnostic::ModuleFileParseError { declaration, .. } => {
+                    let (file, range) = location(declaration);
+                    ModuleProblem::ModuleFileParseError { file, range }
+                }
+                DefDiagnostic::ModuleCycle { declaration, .. } => {
+                    let (file, range) = location(declaration);
+                    ModuleProblem::ModuleCycle { file, range }
+                }
+                DefDiagnostic::UnresolvedGeneratedModule { declaration, path, .. } => {
+                    let (file, range) = location(declaration);
+                    ModuleProblem::UnresolvedGeneratedModule { file, range, path: path.to_string() }
+                }
+                DefDiagnostic::ModuleFileNotIndexed { declaration, path, .. } => {
+                    let (file, range) = location(declaration);
+                    ModuleProblem::ModuleFileNotIndexed {
+                        file,
+                        range,
+                        path: path.as_str().to_string(),
+                    }
+                }
+            }
+        }
+
+        pub(super) fn module(&self) -> CrateModuleId {
+            match self {
+                DefDiagnostic::UnresolvedModule { module, .. }
+                | DefDiagnostic::ModuleCaseMismatch { module, .. }
+                | DefDiagnostic::ModuleFileParseError { module, .. }
+                | DefDiagnostic::ModuleCycle { module, .. }
+                | DefDiagnostic::UnresolvedGeneratedModule { module, .. }
+                | DefDiagnostic::ModuleFileNotIndexed { module, .. } => *module,
+            }
+        }
+
+        /// Compares diagnostics of two versions of a `CrateDefMap`, in which
+        /// the ids of the same module might differ.
+        pub(super) fn eq_ignoring_module(&self, other: &DefDiagnostic) -> bool {
+            match (self, other) {
+                (
+                    DefDiagnostic::UnresolvedModule { declaration, candidate, .. },
+                    DefDiagnostic::UnresolvedModule {
+                        declaration: other_declaration,
+                        candidate: other_candidate,
+                        ..
+                    },
+                ) => declaration == other_declaration && candidate == other_candidate,
+                (
+                    DefDiagnostic::ModuleCaseMismatch { declaration, declared, on_disk, .. },
+                    DefDiagnostic::ModuleCaseMismatch {
+                        declaration: other_declaration,
+                        declared: other_declared,
+                        on_disk: other_on_disk,
+                        ..
+                    },
+                ) => {
+                    declaration == other_declaration
+                        && declared == other_declared
+                        && on_disk == other_on_disk
+                }
+                (
+                    DefDiagnostic::ModuleFileParseError { declaration, .. },
+                    DefDiagnostic::ModuleFileParseError { declaration: other_declaration, .. },
+                )
+                | (
+                    DefDiagnostic::ModuleCycle { declaration, .. },
+                    DefDiagnostic::ModuleCycle { declaration: other_declaration, .. },
+                ) => declaration == other_declaration,
+                (
+                    DefDiagnostic::UnresolvedGeneratedModule { declaration, path, .. },
+                    DefDiagnostic::UnresolvedGeneratedModule {
+                        declaration: other_declaration,
+                        path: other_path,
+                        ..
+                    },
+                ) => declaration == other_declaration && path == other_path,
+                (
+                    DefDiagnostic::ModuleFileNotIndexed { declaration, path, .. },
+                    DefDiagnostic::ModuleFileNotIndexed {
+                        declaration: other_declaration,
+                        path: other_path,
+                        ..
+                    },
+                ) => declaration == other_declaration && path == other_path,
+                _ => false,
             }
         }
     }
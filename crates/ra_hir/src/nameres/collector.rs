@@ -1,9 +1,10 @@
 use std::borrow::Cow;
+use std::path::Path;
 use std::sync::Arc;
 
-use ra_db::{FileId, SourceRoot};
+use ra_db::{FileId, SourceRoot, SourceRootId};
 use ra_syntax::{ast, SmolStr};
-use relative_path::RelativePathBuf;
+use relative_path::{Component, RelativePath, RelativePathBuf};
 use rustc_hash::FxHashMap;
 use test_utils::tested_by;
 
@@ -43,6 +44,7 @@ pub(super) fn collect_defs(db: &impl DefDatabase, mut def_map: CrateDefMap) -> C
         unexpanded_macros: Vec::new(),
         global_macro_scope: FxHashMap::default(),
         macro_stack_monitor: MacroStackMonitor::default(),
+        dir_listings: FxHashMap::default(),
     };
     collector.collect();
     collector.finish()
@@ -88,8 +90,14 @@ struct DefCollector<DB> {
     /// Some macro use `$tt:tt which mean we have to handle the macro perfectly
     /// To prevent stack overflow, we add a deep counter here for prevent that.
     macro_stack_monitor: MacroStackMonitor,
+
+    /// Files of a source root by their lowercased directory, for looking up
+    /// module files with mismatched case. Only built if such a lookup happens.
+    dir_listings: FxHashMap<SourceRootId, DirListing>,
 }
 
+type DirListing = FxHashMap<String, Vec<(RelativePathBuf, FileId)>>;
+
 impl<'a, DB> DefCollector<&'a DB>
 where
     DB: DefDatabase,
@@ -100,6 +108,7 @@ where
         let raw_items = self.db.raw_items(file_id.into());
         let module_id = self.def_map.root;
         self.def_map.modules[module_id].definition = Some(file_id);
+        self.def_map.file_to_module.insert(file_id, module_id);
         ModCollector {
             def_collector: &mut *self,
             module_id,
@@ -335,6 +344,9 @@ where
             match res {
                 // item
                 Either::A(res) => {
+                    if !module_items.items.contains_key(name) {
+                        module_items.order.push(name.clone());
+                    }
                     let existing = module_items.items.entry(name.clone()).or_default();
 
                     if existing.def.types.is_none() && res.def.types.is_some() {
@@ -472,6 +484,78 @@ where
         self.macro_stack_monitor.decrease(macro_def_id);
     }
 
+    /// Finds the file of a module declared with
+    /// `#[path = concat!(env!("OUT_DIR"), "/file.rs")]` in the crate's `OUT_DIR`,
+    /// which is only known if the build script was run and its output loaded.
+    fn resolve_generated_module(&self, path: &str) -> Option<FileId> {
+        let krate = self.def_map.krate.crate_id();
+        let crate_graph = self.db.crate_graph();
+        let out_dir = crate_graph.env(krate).get("OUT_DIR")?;
+        let path = Path::new(out_dir).join(path.trim_start_matches('/'));
+        let (source_root_id, path) = crate_graph.extern_source().extern_path(&path)?;
+        self.db.source_root(source_root_id).files.get(&path).copied()
+    }
+
+    /// Whether `candidate`, a path relative to the source root of `file_id`,
+    /// names a file loaded into another source root. Files which the VFS
+    /// excludes aren't known to exist at all.
+    fn exists_outside_source_root(&self, file_id: HirFileId, candidate: &RelativePath) -> bool {
+        let db = self.db;
+        let source_root_id = db.file_source_root(file_id.original_file(db));
+        let crate_graph = db.crate_graph();
+        let extern_source = crate_graph.extern_source();
+        let mut path = match extern_source.root_path(source_root_id) {
+            Some(it) => it.to_path_buf(),
+            None => return false,
+        };
+        for component in candidate.components() {
+            match component {
+                Component::CurDir => {}
+                Component::ParentDir => {
+                    path.pop();
+                }
+                Component::Normal(name) => path.push(name),
+            }
+        }
+        match extern_source.extern_path(&path) {
+            Some((root, path)) => {
+                root != source_root_id && db.source_root(root).files.contains_key(&path)
+            }
+            None => false,
+        }
+    }
+
+    /// On case-insensitive file systems rustc loads `foo.rs` for `mod Foo;`,
+    /// and the code then fails to compile elsewhere. We still resolve such
+    /// modules, so that navigation works, but report the mismatch. Returns the
+    /// file, the candidate it matches and its actual path.
+    fn resolve_ignoring_case(
+        &mut self,
+        file_id: HirFileId,
+        candidates: &[RelativePathBuf],
+    ) -> Option<(FileId, RelativePathBuf, RelativePathBuf)> {
+        let db = self.db;
+        let source_root_id = db.file_source_root(file_id.original_file(db));
+        let dir_listing = self.dir_listings.entry(source_root_id).or_insert_with(|| {
+            let mut dir_listing = DirListing::default();
+            for (path, &file_id) in db.source_root(source_root_id).files.iter() {
+                let dir = path.parent().map_or(String::new(), |it| it.as_str().to_lowercase());
+                dir_listing.entry(dir).or_insert_with(Vec::new).push((path.clone(), file_id));
+            }
+            dir_listing
+        });
+        candidates.iter().find_map(|candidate| {
+            let dir = candidate.parent().map_or(String::new(), |it| it.as_str().to_lowercase());
+            let lowercase = candidate.as_str().to_lowercase();
+            dir_listing
+                .get(&dir)?
+                .iter()
+                .filter(|(path, _file_id)| path.as_str().to_lowercase() == lowercase)
+                .min_by_key(|(path, _file_id)| path.as_str())
+                .map(|(path, &file_id)| (file_id, candidate.clone(), path.clone()))
+        })
+    }
+
     fn finish(self) -> CrateDefMap {
         self.def_map
     }
@@ -491,26 +575,49 @@ where
     DB: DefDatabase,
 {
     fn collect(&mut self, items: &[raw::RawItem]) {
-        for item in items {
+        for (idx, item) in items.iter().enumerate() {
             match *item {
-                raw::RawItem::Module(m) => self.collect_module(&self.raw_items[m]),
-                raw::RawItem::Import(import) => self.def_collector.unresolved_imports.push((
-                    self.module_id,
-                    import,
-                    self.raw_items[import].clone(),
-                )),
-                raw::RawItem::Def(def) => self.define_def(&self.raw_items[def]),
+                raw::RawItem::Module(m) => self.collect_module(idx, &self.raw_items[m], items),
+                raw::RawItem::Import(import) => {
+                    let import_data = &self.raw_items[import];
+                    if self.is_cfg_enabled(import_data.cfg.as_ref()) {
+                        self.def_collector.unresolved_imports.push((
+                            self.module_id,
+                            import,
+                            import_data.clone(),
+                        ))
+                    }
+                }
+                raw::RawItem::Def(def) => {
+                    let def = &self.raw_items[def];
+                    if self.is_cfg_enabled(def.cfg.as_ref()) {
+                        self.define_def(def)
+                    }
+                }
                 raw::RawItem::Macro(mac) => self.collect_macro(&self.raw_items[mac]),
             }
         }
     }
 
-    fn collect_module(&mut self, module: &raw::ModuleData) {
+    fn collect_module(&mut self, idx: usize, module: &raw::ModuleData, siblings: &[raw::RawItem]) {
+        // Module trees can be arbitrarily deep, so make sure we bail out
+        // promptly if the computation is no longer needed.
+        self.def_collector.db.check_canceled();
+        if let Some(predicate) = module.cfg() {
+            if !self.is_module_enabled(predicate, module.name(), siblings) {
+                return;
+            }
+        }
         match module {
             // inline module, just recurse
-            raw::ModuleData::Definition { name, items, ast_id, attr_path } => {
+            raw::ModuleData::Definition {
+                name, items, ast_id, attr_path, is_macro_use, ..
+            } => {
                 let module_id =
                     self.push_child_module(name.clone(), ast_id.with_file_id(self.file_id), None);
+                if *is_macro_use {
+                    self.def_collector.def_map.modules[module_id].macro_use = Some(idx);
+                }
                 let parent_module = ParentModule { name, attr_path: attr_path.as_ref() };
                 ModCollector {
                     def_collector: &mut *self.def_collector,
@@ -522,41 +629,181 @@ where
                 .collect(&*items);
             }
             // out of line module, resolve, parse and recurse
-            raw::ModuleData::Declaration { name, ast_id, attr_path } => {
+            raw::ModuleData::Declaration {
+                name,
+                ast_id,
+                attr_path,
+                cfg_attr_paths,
+                out_dir_path,
+                is_macro_use,
+                ..
+            } => {
                 let ast_id = ast_id.with_file_id(self.file_id);
                 let is_root = self.def_collector.def_map.modules[self.module_id].parent.is_none();
-                match resolve_submodule(
-                    self.def_collector.db,
-                    self.file_id,
-                    name,
-                    is_root,
-                    attr_path.as_ref(),
-                    self.parent_module.as_ref(),
-                ) {
-                    Ok(file_id) => {
-                        let module_id = self.push_child_module(name.clone(), ast_id, Some(file_id));
-                        let raw_items = self.def_collector.db.raw_items(file_id.into());
-                        ModCollector {
-                            def_collector: &mut *self.def_collector,
-                            module_id,
-                            file_id: file_id.into(),
-                            raw_items: &raw_items,
-                            parent_module: None,
+                let resolve = |attr_path| {
+                    resolve_submodule(
+                        self.def_collector.db,
+                        self.file_id,
+                        name,
+                        is_root,
+                        attr_path,
+                        self.parent_module.as_ref(),
+                    )
+                };
+                // An enabled `#[cfg_attr(predicate, path = "...")]` overrides
+                // both `#[path]` and the default file.
+                let krate = self.def_collector.def_map.krate.crate_id();
+                let crate_graph = self.def_collector.db.crate_graph();
+                let cfg_options = crate_graph.cfg_options(krate);
+                let cfg_attr_path = cfg_attr_paths
+                    .iter()
+                    .find(|(predicate, _)| predicate.is_enabled(cfg_options))
+                    .map(|(_, path)| path);
+                let resolved = match out_dir_path {
+                    Some(path) => match self.def_collector.resolve_generated_module(path) {
+                        Some(file_id) => Ok(file_id),
+                        None => {
+                            self.def_collector.def_map.diagnostics.push(
+                                DefDiagnostic::UnresolvedGeneratedModule {
+                                    module: self.module_id,
+                                    declaration: ast_id,
+                                    path: path.clone(),
+                                },
+                            );
+                            return;
+                        }
+                    },
+                    None => resolve(cfg_attr_path.or(attr_path.as_ref())),
+                };
+                let file_id = match resolved {
+                    Ok(file_id) => Some(file_id),
+                    Err(candidates) => {
+                        let candidate = candidates.first().cloned().unwrap_or_default();
+                        match self.def_collector.resolve_ignoring_case(self.file_id, &candidates) {
+                            Some((file_id, declared, on_disk)) => {
+                                self.def_collector.def_map.diagnostics.push(
+                                    DefDiagnostic::ModuleCaseMismatch {
+                                        module: self.module_id,
+                                        declaration: ast_id,
+                                        declared,
+                                        on_disk,
+                                    },
+                                );
+                                Some(file_id)
+                            }
+                            // Files of another source root aren't loaded as
+                            // part of this one.
+                            None if self
+                                .def_collector
+                                .exists_outside_source_root(self.file_id, &candidate) =>
+                            {
+                                self.def_collector.def_map.diagnostics.push(
+                                    DefDiagnostic::ModuleFileNotIndexed {
+                                        module: self.module_id,
+                                        declaration: ast_id,
+                                        path: candidate,
+                                    },
+                                );
+                                None
+                            }
+                            None => {
+                                self.def_collector.def_map.diagnostics.push(
+                                    DefDiagnostic::UnresolvedModule {
+                                        module: self.module_id,
+                                        declaration: ast_id,
+                                        candidate,
+                                    },
+                                );
+                                None
+                            }
                         }
-                        .collect(raw_items.items())
                     }
-                    Err(candidate) => self.def_collector.def_map.diagnostics.push(
-                        DefDiagnostic::UnresolvedModule {
-                            module: self.module_id,
-                            declaration: ast_id,
-                            candidate,
-                        },
-                    ),
                 };
+                // With `#[path]`, a file can declare one of its ancestors as
+                // a submodule: stop there instead of recursing forever.
+                let def_map = &self.def_collector.def_map;
+                let is_cycle = file_id.map_or(false, |file_id| {
+                    def_map
+                        .ancestors(self.module_id)
+                        .any(|it| def_map[it].definition == Some(file_id))
+                });
+                if is_cycle {
+                    self.def_collector.def_map.diagnostics.push(DefDiagnostic::ModuleCycle {
+                        module: self.module_id,
+                        declaration: ast_id,
+                    });
+                }
+                if let Some(file_id) = file_id.filter(|_| !is_cycle) {
+                    let module_id = self.push_child_module(name.clone(), ast_id, Some(file_id));
+                    if *is_macro_use {
+                        self.def_collector.def_map.modules[module_id].macro_use = Some(idx);
+                    }
+                    let raw_items = self.def_collector.db.raw_items(file_id.into());
+                    // Explain why the module is empty.
+                    if raw_items.items().is_empty() && raw_items.has_parse_errors() {
+                        self.def_collector.def_map.diagnostics.push(
+                            DefDiagnostic::ModuleFileParseError {
+                                module: self.module_id,
+                                declaration: ast_id,
+                            },
+                        );
+                    }
+                    ModCollector {
+                        def_collector: &mut *self.def_collector,
+                        module_id,
+                        file_id: file_id.into(),
+                        raw_items: &raw_items,
+                        parent_module: None,
+                    }
+                    .collect(raw_items.items())
+                }
             }
         }
     }
 
+    /// Whether an item with `#[cfg(predicate)]`, or without a predicate, is
+    /// part of the crate. Nothing sets `doctest`, so items only compiled into
+    /// doctests are always left out.
+    fn is_cfg_enabled(&self, predicate: Option<&raw::CfgExpr>) -> bool {
+        let krate = self.def_collector.def_map.krate.crate_id();
+        predicate.map_or(true, |it| {
+            it.is_enabled(self.def_collector.db.crate_graph().cfg_options(krate))
+        })
+    }
+
+    /// Whether a module with `#[cfg(predicate)]` is part of the crate. Unit
+    /// tests are built from the same crate root, so modules which are only
+    /// enabled with `test`, like `#[cfg(test)] mod tests { .. }`, are kept as
+    /// well, unless an enabled sibling of the same name replaces them, as in
+    /// `#[cfg(test)] mod imp;` and `#[cfg(not(test))] mod imp;`.
+    fn is_module_enabled(
+        &self,
+        predicate: &raw::CfgExpr,
+        name: &Name,
+        siblings: &[raw::RawItem],
+    ) -> bool {
+        let krate = self.def_collector.def_map.krate.crate_id();
+        let crate_graph = self.def_collector.db.crate_graph();
+        let cfg_options = crate_graph.cfg_options(krate);
+        if predicate.is_enabled(cfg_options) {
+            return true;
+        }
+        let is_replaced = siblings.iter().any(|item| match *item {
+            raw::RawItem::Module(m) => {
+                let sibling = &self.raw_items[m];
+                sibling.name() == name
+                    && sibling.cfg().map_or(true, |it| it.is_enabled(cfg_options))
+            }
+            _ => false,
+        });
+        if is_replaced {
+            return false;
+        }
+        let mut test_cfg_options = cfg_options.clone();
+        test_cfg_options.insert_atom("test".into());
+        predicate.is_enabled(&test_cfg_options)
+    }
+
     fn push_child_module(
         &mut self,
         name: Name,
@@ -569,6 +816,9 @@ where
         modules[res].declaration = Some(declaration);
         modules[res].definition = definition;
         modules[self.module_id].children.insert(name.clone(), res);
+        if let Some(file_id) = definition {
+            self.def_collector.def_map.file_to_module.entry(file_id).or_insert(res);
+        }
         let resolution = Resolution {
             def: PerNs::types(
                 Module { krate: self.def_collector.def_map.krate, module_id: res }.into(),
@@ -649,7 +899,7 @@ fn resolve_submodule(
     is_root: bool,
     attr_path: Option<&SmolStr>,
     parent_module: Option<&ParentModule>,
-) -> Result<FileId, RelativePathBuf> {
+) -> Result<FileId, Vec<RelativePathBuf>> {
     let file_id = file_id.original_file(db);
     let source_root_id = db.file_source_root(file_id);
     let path = db.file_relative_path(file_id);
@@ -698,18 +948,19 @@ fn resolve_submodule(
         },
         (Some(file_path), None) => {
             let file_path = normalize_attribute_path(file_path);
-            let path = dir_path.join(file_path.as_ref()).normalize();
-            ResolutionMode::OutOfLine(OutOfLineMode::WithAttributePath(path))
+            let file_path = RelativePath::new(file_path.as_ref());
+            return resolve_relative_path(db, file_id, file_path)
+                .ok_or_else(|| vec![dir_path.join(file_path).normalize()]);
         }
         _ => {
+            // Any other file `foo.rs` owns the `foo/` directory. This is
+            // accepted by rustc regardless of the crate's edition, so we
+            // don't need to look at it here.
             let is_dir_owner = is_root || mod_name == "mod";
             if is_dir_owner {
-                let file_mod = dir_path.join(format!("{}.rs", name));
-                let dir_mod = dir_path.join(format!("{}/mod.rs", name));
-                ResolutionMode::OutOfLine(OutOfLineMode::RootOrModRs {
-                    file: file_mod,
-                    directory: dir_mod,
-                })
+                let candidates = db.module_name_resolver().0.candidates(&name.to_string());
+                let candidates = candidates.iter().map(|it| dir_path.join(it)).collect();
+                ResolutionMode::OutOfLine(OutOfLineMode::RootOrModRs(candidates))
             } else {
                 let path = dir_path.join(format!("{}/{}.rs", mod_name, name));
                 ResolutionMode::OutOfLine(OutOfLineMode::FileInDirectory(path))
@@ -720,36 +971,52 @@ fn resolve_submodule(
     resolve_mode.resolve(db.source_root(source_root_id))
 }
 
+/// Resolves `path` relative to the directory of `file_id`, like rustc does
+/// for `#[path]` attributes of out-of-line modules and for `include!`.
+pub(crate) fn resolve_relative_path(
+    db: &impl DefDatabase,
+    file_id: FileId,
+    path: &RelativePath,
+) -> Option<FileId> {
+    let source_root = db.source_root(db.file_source_root(file_id));
+    let file_path = db.file_relative_path(file_id);
+    let root = RelativePathBuf::default();
+    let dir_path = file_path.parent().unwrap_or(&root);
+    source_root.files.get(&dir_path.join(path).normalize()).copied()
+}
+
+/// Converts Windows separators to the ones the VFS uses and strips a leading
+/// `./`, which may itself be written with a Windows separator. The case is
+/// kept: paths which only match a file ignoring case are resolved by
+/// `DefCollector::resolve_ignoring_case`, which also reports them.
 fn normalize_attribute_path(file_path: &SmolStr) -> Cow<str> {
     let current_dir = "./";
     let windows_path_separator = r#"\"#;
-    let current_dir_normalize = if file_path.starts_with(current_dir) {
-        &file_path[current_dir.len()..]
-    } else {
-        file_path.as_str()
-    };
-    if current_dir_normalize.contains(windows_path_separator) {
-        Cow::Owned(current_dir_normalize.replace(windows_path_separator, "/"))
+    if file_path.contains(windows_path_separator) {
+        let file_path = file_path.replace(windows_path_separator, "/");
+        Cow::Owned(file_path.trim_start_matches(current_dir).to_string())
     } else {
-        Cow::Borrowed(current_dir_normalize)
+        Cow::Borrowed(file_path.trim_start_matches(current_dir))
     }
 }
 
 enum OutOfLineMode {
-    RootOrModRs { file: RelativePathBuf, directory: RelativePathBuf },
+    /// Candidates from the `ModuleNameResolver`, which are all returned if
+    /// none exists. The first one is reported.
+    RootOrModRs(Vec<RelativePathBuf>),
     FileInDirectory(RelativePathBuf),
-    WithAttributePath(RelativePathBuf),
 }
 
 impl OutOfLineMode {
-    pub fn resolve(&self, source_root: Arc<SourceRoot>) -> Result<FileId, RelativePathBuf> {
+    pub fn resolve(&self, source_root: Arc<SourceRoot>) -> Result<FileId, Vec<RelativePathBuf>> {
         match self {
-            OutOfLineMode::RootOrModRs { file, directory } => match source_root.files.get(file) {
-                None => resolve_simple_path(source_root, directory).map_err(|_| file.clone()),
-                file_id => resolve_find_result(file_id, file),
-            },
+            OutOfLineMode::RootOrModRs(candidates) => {
+                match candidates.iter().find_map(|it| source_root.files.get(it)) {
+                    Some(&file_id) => Ok(file_id),
+                    None => Err(candidates.clone()),
+                }
+            }
             OutOfLineMode::FileInDirectory(path) => resolve_simple_path(source_root, path),
-            OutOfLineMode::WithAttributePath(path) => resolve_simple_path(source_root, path),
         }
     }
 }
@@ -760,7 +1027,7 @@ enum InsideInlineModuleMode {
 }
 
 impl InsideInlineModuleMode {
-    pub fn resolve(&self, source_root: Arc<SourceRoot>) -> Result<FileId, RelativePathBuf> {
+    pub fn resolve(&self, source_root: Arc<SourceRoot>) -> Result<FileId, Vec<RelativePathBuf>> {
         match self {
             InsideInlineModuleMode::File(path) => resolve_simple_path(source_root, path),
             InsideInlineModuleMode::WithAttributePath(path) => {
@@ -777,7 +1044,7 @@ enum ResolutionMode {
 }
 
 impl ResolutionMode {
-    pub fn resolve(&self, source_root: Arc<SourceRoot>) -> Result<FileId, RelativePathBuf> {
+    pub fn resolve(&self, source_root: Arc<SourceRoot>) -> Result<FileId, Vec<RelativePathBuf>> {
         use self::ResolutionMode::*;
 
         match self {
@@ -791,17 +1058,17 @@ impl ResolutionMode {
 fn resolve_simple_path(
     source_root: Arc<SourceRoot>,
     path: &RelativePathBuf,
-) -> Result<FileId, RelativePathBuf> {
+) -> Result<FileId, Vec<RelativePathBuf>> {
     resolve_find_result(source_root.files.get(path), path)
 }
 
 fn resolve_find_result(
     file_id: Option<&FileId>,
     path: &RelativePathBuf,
-) -> Result<FileId, RelativePathBuf> {
+) -> Result<FileId, Vec<RelativePathBuf>> {
     match file_id {
         Some(file_id) => Ok(file_id.clone()),
-        None => Err(path.clone()),
+        None => Err(vec![path.clone()]),
     }
 }
 
@@ -838,6 +1105,7 @@ mod tests {
             unexpanded_macros: Vec::new(),
             global_macro_scope: FxHashMap::default(),
             macro_stack_monitor: monitor,
+            dir_listings: FxHashMap::default(),
         };
         collector.collect();
         collector.finish()
@@ -859,6 +1127,7 @@ mod tests {
                 prelude: None,
                 root,
                 modules,
+                file_to_module: FxHashMap::default(),
                 poison_macros: FxHashSet::default(),
                 diagnostics: Vec::new(),
             }
@@ -918,4 +1187,27 @@ foo!(Bar);
 
         assert_eq!(def.poison_macros.len(), 0);
     }
+
+    #[test]
+    fn test_resolve_relative_path() {
+        let db = MockDatabase::with_files(
+            "
+            //- /lib.rs
+            mod sub;
+            //- /sub/mod.rs
+            include!(\"data/nested.rs\");
+            //- /sub/data/nested.rs
+            struct Nested;
+            //- /other.rs
+            struct Other;
+            ",
+        );
+        let sub = db.file_id_of("/sub/mod.rs");
+        let resolve = |path: &str| resolve_relative_path(&db, sub, RelativePath::new(path));
+
+        assert_eq!(resolve("data/nested.rs"), Some(db.file_id_of("/sub/data/nested.rs")));
+        assert_eq!(resolve("./data/../data/nested.rs"), Some(db.file_id_of("/sub/data/nested.rs")));
+        assert_eq!(resolve("../other.rs"), Some(db.file_id_of("/other.rs")));
+        assert_eq!(resolve("data/missing.rs"), None);
+    }
 }
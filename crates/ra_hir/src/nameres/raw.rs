@@ -1,9 +1,12 @@
 use std::{ops::Index, sync::Arc};
 
 use ra_arena::{impl_arena_id, map::ArenaMap, Arena, RawId};
+use ra_db::CfgOptions;
 use ra_syntax::{
     ast::{self, AttrsOwner, NameOwner},
-    AstNode, AstPtr, SmolStr, SourceFile,
+    AstNode, AstPtr, SmolStr, SourceFile, SyntaxElement,
+    SyntaxKind::{IDENT, STRING, TOKEN_TREE},
+    SyntaxNode, T,
 };
 use test_utils::tested_by;
 
@@ -24,6 +27,7 @@ pub struct RawItems {
     macros: Arena<Macro, MacroData>,
     /// items for top-level module
     items: Vec<RawItem>,
+    has_parse_errors: bool,
 }
 
 #[derive(Debug, Default, PartialEq, Eq)]
@@ -75,6 +79,9 @@ impl RawItems {
             source_ast_id_map: db.ast_id_map(file_id),
             source_map: ImportSourceMap::default(),
         };
+        if let Some(file_id) = file_id.as_file_id() {
+            collector.raw_items.has_parse_errors = !db.parse(file_id).errors().is_empty();
+        }
         if let Some(node) = db.parse_or_expand(file_id) {
             if let Some(source_file) = ast::SourceFile::cast(node) {
                 collector.process_module(None, source_file);
@@ -86,6 +93,10 @@ impl RawItems {
     pub(super) fn items(&self) -> &[RawItem] {
         &self.items
     }
+
+    pub(super) fn has_parse_errors(&self) -> bool {
+        self.has_parse_errors
+    }
 }
 
 impl Index<Module> for RawItems {
@@ -134,15 +145,63 @@ pub(super) enum ModuleData {
         name: Name,
         ast_id: FileAstId<ast::Module>,
         attr_path: Option<SmolStr>,
+        /// Paths set with `#[cfg_attr(predicate, path = "...")]`.
+        cfg_attr_paths: Vec<(CfgExpr, SmolStr)>,
+        /// The file name in `#[path = concat!(env!("OUT_DIR"), "/file.rs")]`,
+        /// for modules generated by build scripts.
+        out_dir_path: Option<SmolStr>,
+        is_macro_use: bool,
+        /// The predicate of `#[cfg(...)]`, if the module has one.
+        cfg: Option<CfgExpr>,
     },
     Definition {
         name: Name,
         ast_id: FileAstId<ast::Module>,
         items: Vec<RawItem>,
         attr_path: Option<SmolStr>,
+        is_macro_use: bool,
+        cfg: Option<CfgExpr>,
     },
 }
 
+impl ModuleData {
+    pub(super) fn name(&self) -> &Name {
+        match self {
+            ModuleData::Declaration { name, .. } | ModuleData::Definition { name, .. } => name,
+        }
+    }
+
+    pub(super) fn cfg(&self) -> Option<&CfgExpr> {
+        match self {
+            ModuleData::Declaration { cfg, .. } | ModuleData::Definition { cfg, .. } => {
+                cfg.as_ref()
+            }
+        }
+    }
+}
+
+/// A `cfg` predicate, like `all(unix, feature = "foo")`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) enum CfgExpr {
+    Atom(SmolStr),
+    KeyValue { key: SmolStr, value: SmolStr },
+    All(Vec<CfgExpr>),
+    Any(Vec<CfgExpr>),
+    Not(Box<CfgExpr>),
+}
+
+impl CfgExpr {
+    pub(super) fn is_enabled(&self, cfg_options: &CfgOptions) -> bool {
+        match self {
+            CfgExpr::Atom(name) => cfg_options.is_atom_enabled(name),
+            CfgExpr::KeyValue { key, value } => cfg_options.is_key_value_enabled(key, value),
+            CfgExpr::All(preds) => preds.iter().all(|it| it.is_enabled(cfg_options)),
+            CfgExpr::Any(preds) => preds.iter().any(|it| it.is_enabled(cfg_options)),
+            CfgExpr::Not(pred) => !pred.is_enabled(cfg_options),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub struct ImportId(RawId);
 impl_arena_id!(ImportId);
@@ -154,6 +213,8 @@ pub struct ImportData {
     pub(super) is_glob: bool,
     pub(super) is_prelude: bool,
     pub(super) is_extern_crate: bool,
+    /// The predicate of `#[cfg(...)]` on the `use` or `extern crate` item.
+    pub(super) cfg: Option<CfgExpr>,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
@@ -164,6 +225,7 @@ impl_arena_id!(Def);
 pub(super) struct DefData {
     pub(super) name: Name,
     pub(super) kind: DefKind,
+    pub(super) cfg: Option<CfgExpr>,
 }
 
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
@@ -207,17 +269,20 @@ impl RawItemsCollector {
     }
 
     fn add_item(&mut self, current_module: Option<Module>, item: ast::ModuleItem) {
+        // Evaluating the predicate depends on the crate, so it's left to the
+        // collector.
+        let cfg = extract_cfg(item.syntax());
         let (kind, name) = match item.kind() {
             ast::ModuleItemKind::Module(module) => {
-                self.add_module(current_module, module);
+                self.add_module(current_module, module, cfg);
                 return;
             }
             ast::ModuleItemKind::UseItem(use_item) => {
-                self.add_use_item(current_module, use_item);
+                self.add_use_item(current_module, use_item, cfg);
                 return;
             }
             ast::ModuleItemKind::ExternCrateItem(extern_crate) => {
-                self.add_extern_crate_item(current_module, extern_crate);
+                self.add_extern_crate_item(current_module, extern_crate, cfg);
                 return;
             }
             ast::ModuleItemKind::ImplBlock(_) => {
@@ -254,22 +319,38 @@ impl RawItemsCollector {
         };
         if let Some(name) = name {
             let name = name.as_name();
-            let def = self.raw_items.defs.alloc(DefData { name, kind });
+            let def = self.raw_items.defs.alloc(DefData { name, kind, cfg });
             self.push_item(current_module, RawItem::Def(def))
         }
     }
 
-    fn add_module(&mut self, current_module: Option<Module>, module: ast::Module) {
+    fn add_module(
+        &mut self,
+        current_module: Option<Module>,
+        module: ast::Module,
+        cfg: Option<CfgExpr>,
+    ) {
         let name = match module.name() {
             Some(it) => it.as_name(),
             None => return,
         };
 
         let ast_id = self.source_ast_id_map.ast_id(&module);
+        let is_macro_use =
+            module.attrs().any(|attr| attr.as_atom().map_or(false, |name| name == "macro_use"));
         if module.has_semi() {
             let attr_path = extract_mod_path_attribute(&module);
-            let item =
-                self.raw_items.modules.alloc(ModuleData::Declaration { name, ast_id, attr_path });
+            let cfg_attr_paths = extract_mod_cfg_attr_paths(&module);
+            let out_dir_path = extract_mod_out_dir_path(&module);
+            let item = self.raw_items.modules.alloc(ModuleData::Declaration {
+                name,
+                ast_id,
+                attr_path,
+                cfg_attr_paths,
+                out_dir_path,
+                is_macro_use,
+                cfg,
+            });
             self.push_item(current_module, RawItem::Module(item));
             return;
         }
@@ -281,6 +362,8 @@ impl RawItemsCollector {
                 ast_id,
                 items: Vec::new(),
                 attr_path,
+                is_macro_use,
+                cfg,
             });
             self.process_module(Some(item), item_list);
             self.push_item(current_module, RawItem::Module(item));
@@ -289,12 +372,23 @@ impl RawItemsCollector {
         tested_by!(name_res_works_for_broken_modules);
     }
 
-    fn add_use_item(&mut self, current_module: Option<Module>, use_item: ast::UseItem) {
+    fn add_use_item(
+        &mut self,
+        current_module: Option<Module>,
+        use_item: ast::UseItem,
+        cfg: Option<CfgExpr>,
+    ) {
         let is_prelude = use_item.has_atom_attr("prelude_import");
 
         Path::expand_use_item(&use_item, |path, use_tree, is_glob, alias| {
-            let import_data =
-                ImportData { path, alias, is_glob, is_prelude, is_extern_crate: false };
+            let import_data = ImportData {
+                path,
+                alias,
+                is_glob,
+                is_prelude,
+                is_extern_crate: false,
+                cfg: cfg.clone(),
+            };
             self.push_import(current_module, import_data, Either::A(AstPtr::new(use_tree)));
         })
     }
@@ -303,6 +397,7 @@ impl RawItemsCollector {
         &mut self,
         current_module: Option<Module>,
         extern_crate: ast::ExternCrateItem,
+        cfg: Option<CfgExpr>,
     ) {
         if let Some(name_ref) = extern_crate.name_ref() {
             let path = Path::from_name_ref(&name_ref);
@@ -313,6 +408,7 @@ impl RawItemsCollector {
                 is_glob: false,
                 is_prelude: false,
                 is_extern_crate: true,
+                cfg,
             };
             self.push_import(current_module, import_data, Either::B(AstPtr::new(&extern_crate)));
         }
@@ -354,6 +450,128 @@ impl RawItemsCollector {
     }
 }
 
+/// Parses the predicates of the `#[cfg(...)]` attributes of `item`. If there
+/// are several, all of them must be enabled. Malformed ones are skipped.
+fn extract_cfg(item: &SyntaxNode) -> Option<CfgExpr> {
+    let mut preds: Vec<CfgExpr> = item
+        .children()
+        .filter_map(ast::Attr::cast)
+        .filter_map(|attr| {
+            let (name, args) = attr.as_call()?;
+            if name != "cfg" {
+                return None;
+            }
+            let tokens = non_trivia(args.syntax());
+            parse_cfg_expr(tokens.get(1..tokens.len().checked_sub(1)?)?)
+        })
+        .collect();
+    match preds.len() {
+        0 => None,
+        1 => preds.pop(),
+        _ => Some(CfgExpr::All(preds)),
+    }
+}
+
+fn extract_mod_cfg_attr_paths(module: &ast::Module) -> Vec<(CfgExpr, SmolStr)> {
+    module
+        .attrs()
+        .filter_map(|attr| {
+            let (name, args) = attr.as_call()?;
+            if name != "cfg_attr" {
+                return None;
+            }
+            // `(predicate, path = "...")`: the predicate ends at the first
+            // comma outside of nested parentheses.
+            let tokens = non_trivia(args.syntax());
+            let predicate_end = tokens.iter().position(|it| it.kind() == T![,])?;
+            let predicate = parse_cfg_expr(tokens.get(1..predicate_end)?)?;
+            match tokens.get(predicate_end + 1..predicate_end + 4)? {
+                [key, eq, value]
+                    if key.kind() == IDENT
+                        && key.as_token()?.text() == "path"
+                        && eq.kind() == T![=]
+                        && value.kind() == STRING =>
+                {
+                    let value = value.as_token()?.text().trim_matches('"');
+                    Some((predicate, SmolStr::new(value)))
+                }
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+/// Parses the non-trivia tokens of a `cfg` predicate. Returns `None` for
+/// malformed predicates.
+fn parse_cfg_expr(tokens: &[SyntaxElement]) -> Option<CfgExpr> {
+    match tokens {
+        [name] if name.kind() == IDENT => Some(CfgExpr::Atom(name.as_token()?.text().clone())),
+        [key, eq, value] if key.kind() == IDENT && eq.kind() == T![=] && value.kind() == STRING => {
+            let key = key.as_token()?.text().clone();
+            let value = SmolStr::new(value.as_token()?.text().trim_matches('"'));
+            Some(CfgExpr::KeyValue { key, value })
+        }
+        [name, args] if name.kind() == IDENT && args.kind() == TOKEN_TREE => {
+            let args = non_trivia(args.as_node()?);
+            // Skip the parentheses and allow a trailing comma.
+            let mut preds = args
+                .get(1..args.len().checked_sub(1)?)?
+                .split(|it| it.kind() == T![,])
+                .filter(|it| !it.is_empty())
+                .map(parse_cfg_expr)
+                .collect::<Option<Vec<_>>>()?;
+            match name.as_token()?.text().as_str() {
+                "all" => Some(CfgExpr::All(preds)),
+                "any" => Some(CfgExpr::Any(preds)),
+                "not" if preds.len() == 1 => Some(CfgExpr::Not(Box::new(preds.pop()?))),
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+fn extract_mod_out_dir_path(module: &ast::Module) -> Option<SmolStr> {
+    let is_ident = |element: &SyntaxElement, text: &str| {
+        element.kind() == IDENT && element.as_token().map_or(false, |it| it.text() == text)
+    };
+    module.attrs().find_map(|attr| {
+        let concat_args = match non_trivia(attr.value()?.syntax()).as_slice() {
+            [_, key, eq, concat, bang, args, _]
+                if is_ident(key, "path")
+                    && eq.kind() == T![=]
+                    && is_ident(concat, "concat")
+                    && bang.kind() == T![!] =>
+            {
+                args.as_node()?.clone()
+            }
+            _ => return None,
+        };
+        match non_trivia(&concat_args).as_slice() {
+            [_, env, bang, env_args, comma, value, _]
+                if is_ident(env, "env")
+                    && bang.kind() == T![!]
+                    && comma.kind() == T![,]
+                    && value.kind() == STRING =>
+            {
+                match non_trivia(env_args.as_node()?).as_slice() {
+                    [_, var, _] if var.as_token()?.text() == "\"OUT_DIR\"" => {
+                        Some(SmolStr::new(value.as_token()?.text().trim_matches('"')))
+                    }
+                    _ => None,
+                }
+            }
+            _ => None,
+        }
+    })
+}
+
+/// The children of `node`, without whitespace and comments, which is what
+/// attribute arguments are matched against.
+fn non_trivia(node: &SyntaxNode) -> Vec<SyntaxElement> {
+    node.children_with_tokens().filter(|it| !it.kind().is_trivia()).collect()
+}
+
 fn extract_mod_path_attribute(module: &ast::Module) -> Option<SmolStr> {
     module.attrs().into_iter().find_map(|attr| {
         attr.as_key_value().and_then(|(name, value)| {
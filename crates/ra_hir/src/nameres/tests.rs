@@ -7,7 +7,7 @@ mod mods;
 use std::sync::Arc;
 
 use insta::assert_snapshot_matches;
-use ra_db::SourceDatabase;
+use ra_db::{CfgOptions, SourceDatabase};
 use test_utils::covers;
 
 use crate::{
@@ -78,6 +78,14 @@ fn def_map_with_crate_graph(fixture: &str, graph: CrateGraphFixture) -> String {
     render_crate_def_map(&dm)
 }
 
+/// Sorts the modules of a rendered def map by their paths. Sibling modules are
+/// rendered in hash map order, which tests with several of them can't rely on.
+fn sort_modules(rendered: &str) -> String {
+    let mut modules: Vec<&str> = rendered.split("\n\n").collect();
+    modules.sort();
+    modules.join("\n\n")
+}
+
 #[test]
 fn crate_def_map_smoke_test() {
     let map = def_map(
@@ -114,6 +122,63 @@ fn crate_def_map_smoke_test() {
     "###)
 }
 
+#[test]
+fn every_item_kind_in_scope() {
+    let map = def_map(
+        "
+        //- /lib.rs
+        mod m;
+        use self::m::Reexported;
+        struct S;
+        enum E {}
+        union U { f: u32 }
+        trait Tr {}
+        type Alias = S;
+        const C: u32 = 0;
+        static ST: u32 = 0;
+        fn f() {}
+        impl S {}
+        impl Tr for S {}
+
+        //- /m.rs
+        pub struct Reexported;
+        ",
+    );
+    assert_snapshot_matches!(map, @r###"
+        ⋮crate
+        ⋮Alias: t
+        ⋮C: v
+        ⋮E: t
+        ⋮Reexported: t v
+        ⋮S: t v
+        ⋮ST: v
+        ⋮Tr: t
+        ⋮U: t v
+        ⋮f: v
+        ⋮m: t
+        ⋮
+        ⋮crate::m
+        ⋮Reexported: t v
+    "###)
+}
+
+#[test]
+fn module_scope_entries_in_source_order() {
+    let map = compute_crate_def_map(
+        "
+        //- /lib.rs
+        struct Zed;
+        fn alpha() {}
+        mod middle {}
+        use self::middle as after;
+        ",
+        None,
+    );
+    let names: Vec<_> =
+        map[map.root()].scope.entries_in_source_order().map(|(name, _)| name.to_string()).collect();
+    assert_eq!(names, vec!["Zed", "alpha", "middle", "after"]);
+}
+
 #[test]
 fn bogus_paths() {
     covers!(bogus_paths);
@@ -505,3 +570,100 @@ fn values_dont_shadow_extern_crates() {
         ⋮foo: v
     "###);
 }
+
+#[test]
+fn local_modules_shadow_extern_crates() {
+    let map = def_map_with_crate_graph(
+        "
+        //- /main.rs
+        mod serde;
+        // rustc considers this import ambiguous, we prefer the local module
+        use serde::Local;
+        use ::serde::Remote;
+
+        //- /serde.rs
+        pub struct Local;
+
+        //- /serde/lib.rs
+        pub struct Remote;
+        ",
+        crate_graph! {
+            "main": ("/main.rs", ["serde"]),
+            "serde": ("/serde/lib.rs", []),
+        },
+    );
+
+    assert_snapshot_matches!(map, @r###"
+        ⋮crate
+        ⋮Local: t v
+        ⋮Remote: t v
+        ⋮serde: t
+        ⋮
+        ⋮crate::serde
+        ⋮Local: t v
+    "###);
+}
+
+#[test]
+fn cfg_doctest_items_are_excluded() {
+    let map = def_map(
+        "
+        //- /lib.rs
+        #[cfg(doctest)]
+        mod doctests;
+        #[cfg(doctest)]
+        pub struct OnlyInDoctests;
+        #[cfg(not(doctest))]
+        pub struct NotInDoctests;
+        pub struct Always;
+
+        //- /doctests.rs
+        pub struct Doctest;
+        ",
+    );
+    assert_snapshot_matches!(map, @r###"
+        ⋮crate
+        ⋮Always: t v
+        ⋮NotInDoctests: t v
+    "###);
+}
+
+#[test]
+fn cfg_items_are_evaluated_against_crate_cfg_options() {
+    let mut db = MockDatabase::with_files(
+        r#"
+        //- /lib.rs
+        mod foo;
+        #[cfg(feature = "std")]
+        use foo::Std;
+        #[cfg(not(feature = "std"))]
+        use foo::NoStd;
+        #[cfg(all(unix, feature = "std"))]
+        pub struct UnixStd;
+        #[cfg(any(windows, not(unix)))]
+        pub struct NotUnix;
+
+        //- /foo.rs
+        pub struct Std;
+        pub struct NoStd;
+        "#,
+    );
+    let mut cfg_options = CfgOptions::default();
+    cfg_options.insert_atom("unix".into());
+    cfg_options.insert_key_value("feature".into(), "std".into());
+    let mut crate_graph = (*db.crate_graph()).clone();
+    let crate_id = crate_graph.iter().next().unwrap();
+    crate_graph.set_cfg_options(crate_id, cfg_options);
+    db.set_crate_graph(Arc::new(crate_graph));
+    let map = db.crate_def_map(Crate { crate_id });
+    assert_snapshot_matches!(sort_modules(&render_crate_def_map(&map)), @r###"
+        ⋮crate
+        ⋮Std: t v
+        ⋮UnixStd: t v
+        ⋮foo: t
+        ⋮
+        ⋮crate::foo
+        ⋮NoStd: t v
+        ⋮Std: t v
+    "###);
+}
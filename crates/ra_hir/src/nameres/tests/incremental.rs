@@ -1,8 +1,11 @@
 use super::*;
 
-use std::sync::Arc;
+use std::{sync::Arc, thread};
 
-use ra_db::SourceDatabase;
+use ra_db::{
+    salsa::{Database, ParallelDatabase},
+    CheckCanceled, SourceDatabase,
+};
 
 fn check_def_map_is_not_recomputed(initial: &str, file_change: &str) {
     let (mut db, pos) = MockDatabase::with_position(initial);
@@ -52,6 +55,27 @@ fn typing_inside_a_function_should_not_invalidate_def_map() {
     );
 }
 
+#[test]
+fn whitespace_edits_should_not_invalidate_def_map() {
+    check_def_map_is_not_recomputed(
+        "
+        //- /lib.rs
+        mod foo;<|>
+        mod bar;
+        //- /foo.rs
+        pub struct Foo;
+        //- /bar.rs
+        pub struct Bar;
+        ",
+        "
+        mod   foo;
+
+
+        mod bar  ;
+        ",
+    );
+}
+
 #[test]
 fn adding_inner_items_should_not_invalidate_def_map() {
     check_def_map_is_not_recomputed(
@@ -131,3 +155,111 @@ fn typing_inside_a_macro_should_not_invalidate_def_map() {
         assert!(!format!("{:?}", events).contains("crate_def_map"), "{:#?}", events)
     }
 }
+
+#[test]
+fn edits_in_one_source_root_do_not_invalidate_def_maps_of_others() {
+    let (mut db, pos) = MockDatabase::with_position(
+        "
+        //- /a/lib.rs
+        mod foo;<|>
+
+        //- /a/foo.rs
+        pub struct Foo;
+
+        //- root /b/
+        //- /b/lib.rs
+        pub struct Bar;
+        ",
+    );
+    db.set_crate_graph_from_fixture(crate_graph! {
+        "a": ("/a/lib.rs", []),
+        "b": ("/b/lib.rs", []),
+    });
+    let crate_graph = db.crate_graph();
+    let krate = |path| Crate {
+        crate_id: crate_graph.crate_id_for_crate_root(db.file_id_of(path)).unwrap(),
+    };
+    let (a, b) = (krate("/a/lib.rs"), krate("/b/lib.rs"));
+    db.crate_def_map(a);
+    db.crate_def_map(b);
+
+    db.set_file_text(pos.file_id, Arc::new("mod foo;\nstruct Baz;\n".to_string()));
+
+    let events = db.log_executed(|| {
+        db.crate_def_map(b);
+    });
+    assert!(!format!("{:?}", events).contains("crate_def_map"), "{:#?}", events);
+    let events = db.log_executed(|| {
+        db.crate_def_map(a);
+    });
+    assert!(format!("{:?}", events).contains("crate_def_map"), "{:#?}", events);
+}
+
+#[test]
+fn def_map_diff_reports_added_modules() {
+    let (mut db, pos) = MockDatabase::with_position(
+        "
+        //- /lib.rs
+        mod foo;<|>
+        mod baz;
+
+        //- /foo.rs
+        pub struct Foo;
+
+        //- /bar.rs
+        pub struct Bar;
+        ",
+    );
+    let krate = Crate { crate_id: db.crate_graph().iter().next().unwrap() };
+    let old = db.crate_def_map(krate);
+    assert_eq!(old.diff(&old), DefMapDiff::default());
+
+    db.set_file_text(pos.file_id, Arc::new("mod foo;\nmod bar;\n".to_string()));
+    let new = db.crate_def_map(krate);
+
+    let diff = new.diff(&old);
+    let render = |keys: &[ModuleKey]| -> Vec<_> {
+        keys.iter()
+            .map(|key| {
+                let path: Vec<_> = key.path.iter().map(|it| it.to_string()).collect();
+                (path.join("::"), key.definition)
+            })
+            .collect()
+    };
+    assert_eq!(
+        render(&diff.added_modules),
+        vec![("bar".to_string(), Some(db.file_id_of("/bar.rs")))]
+    );
+    assert!(diff.removed_modules.is_empty());
+    // `mod baz;` was unresolved.
+    assert_eq!(render(&diff.changed_diagnostics), vec![(String::new(), Some(pos.file_id))]);
+}
+
+#[test]
+fn edits_cancel_collection_of_deep_module_trees() {
+    let mut fixture = String::from("//- /lib.rs\nmod m0;\n");
+    let mut dir = String::new();
+    for i in 0..32 {
+        fixture += &format!("//- {}/m{}.rs\nmod m{};\n", dir, i, i + 1);
+        dir += &format!("/m{}", i);
+    }
+    let mut db = MockDatabase::with_files(&fixture);
+    let krate = Crate { crate_id: db.crate_graph().iter().next().unwrap() };
+    let lib = db.file_id_of("/lib.rs");
+
+    let snapshot = db.snapshot();
+    let collector = thread::spawn(move || {
+        // Start once the edit is pending, so that collection is canceled at
+        // the first module instead of racing with the edit.
+        while !snapshot.salsa_runtime().is_current_revision_canceled() {
+            thread::yield_now();
+        }
+        snapshot.catch_canceled(|db| db.crate_def_map(krate))
+    });
+    // Waits for the snapshot to be dropped.
+    db.set_file_text(lib, Arc::new("mod m0;\npub struct S;\n".to_string()));
+    assert!(collector.join().unwrap().is_err());
+
+    let map = db.crate_def_map(krate);
+    assert!(render_crate_def_map(&map).contains("\nS: t v"));
+}
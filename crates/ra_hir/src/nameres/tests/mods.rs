@@ -1,4 +1,7 @@
+use ra_db::{CfgOptions, Env, SourceRootId};
+
 use super::*;
+use crate::diagnostics::DiagnosticSink;
 
 #[test]
 fn name_res_works_for_broken_modules() {
@@ -77,6 +80,98 @@ fn module_resolution_works_for_raw_modules() {
     "###);
 }
 
+#[test]
+fn module_resolution_works_for_raw_modules_in_mod_rs() {
+    let map = def_map_with_crate_graph(
+        "
+        //- /library.rs
+        mod r#async;
+        use self::r#async::r#type::Bar;
+
+        //- /async/mod.rs
+        pub mod r#type;
+
+        //- /async/type.rs
+        pub struct Bar;
+        ",
+        crate_graph! {
+            "library": ("/library.rs", []),
+        },
+    );
+
+    assert_snapshot_matches!(map, @r###"
+        ⋮crate
+        ⋮Bar: t v
+        ⋮async: t
+        ⋮
+        ⋮crate::async
+        ⋮type: t
+        ⋮
+        ⋮crate::async::type
+        ⋮Bar: t v
+    "###);
+}
+
+#[test]
+fn module_resolution_non_mod_rs_owns_directory_2015() {
+    let map = def_map_with_crate_graph(
+        "
+        //- /main.rs
+        mod foo;
+
+        //- /foo.rs
+        pub mod bar;
+
+        //- /foo/bar.rs
+        pub struct Baz;
+        ",
+        crate_graph! {
+            "main": ("/main.rs", "2015", []),
+        },
+    );
+
+    assert_snapshot_matches!(map, @r###"
+        ⋮crate
+        ⋮foo: t
+        ⋮
+        ⋮crate::foo
+        ⋮bar: t
+        ⋮
+        ⋮crate::foo::bar
+        ⋮Baz: t v
+    "###);
+}
+
+#[test]
+fn module_resolution_non_mod_rs_owns_directory_2018() {
+    let map = def_map_with_crate_graph(
+        "
+        //- /main.rs
+        mod foo;
+
+        //- /foo.rs
+        pub mod bar;
+
+        //- /foo/bar.rs
+        pub struct Baz;
+        ",
+        crate_graph! {
+            "main": ("/main.rs", "2018", []),
+        },
+    );
+
+    assert_snapshot_matches!(map, @r###"
+        ⋮crate
+        ⋮foo: t
+        ⋮
+        ⋮crate::foo
+        ⋮bar: t
+        ⋮
+        ⋮crate::foo::bar
+        ⋮Baz: t v
+    "###);
+}
+
 #[test]
 fn module_resolution_decl_path() {
     let map = def_map_with_crate_graph(
@@ -542,6 +637,89 @@ fn module_resolution_decl_inside_inline_module_relative_path() {
     "###);
 }
 
+#[test]
+fn module_resolution_relative_paths_with_win_separators() {
+    let map = def_map_with_crate_graph(
+        r###"
+        //- /main.rs
+        #[path = ".\models"]
+        mod foo {
+            mod bar;
+        }
+        #[path = ".\sub\baz.rs"]
+        mod baz;
+
+        //- /models/bar.rs
+        pub struct Bar;
+
+        //- /sub/baz.rs
+        pub struct Baz;
+        "###,
+        crate_graph! {
+            "main": ("/main.rs", []),
+        },
+    );
+
+    assert_snapshot_matches!(sort_modules(&map), @r###"
+        ⋮crate
+        ⋮baz: t
+        ⋮foo: t
+        ⋮
+        ⋮crate::baz
+        ⋮Baz: t v
+        ⋮
+        ⋮crate::foo
+        ⋮bar: t
+        ⋮
+        ⋮crate::foo::bar
+        ⋮Bar: t v
+    "###);
+}
+
+#[test]
+fn module_resolution_relative_paths_with_mixed_case() {
+    // Windows file systems ignore case, so these paths work there. They are
+    // resolved, but reported, as they don't work elsewhere.
+    let db = MockDatabase::with_files(
+        r#"
+        //- /lib.rs
+        #[path = ".\Sub\Baz.rs"] mod baz;
+        #[path = "Models"]
+        mod foo {
+            mod bar;
+        }
+
+        //- /sub/baz.rs
+        pub struct Baz;
+
+        //- /models/bar.rs
+        pub struct Bar;
+        "#,
+    );
+    let krate = db.crate_graph().iter().next().unwrap();
+    let map = db.crate_def_map(Crate { crate_id: krate });
+    assert_snapshot_matches!(sort_modules(&render_crate_def_map(&map)), @r###"
+        ⋮crate
+        ⋮baz: t
+        ⋮foo: t
+        ⋮
+        ⋮crate::baz
+        ⋮Baz: t v
+        ⋮
+        ⋮crate::foo
+        ⋮bar: t
+        ⋮
+        ⋮crate::foo::bar
+        ⋮Bar: t v
+    "###);
+
+    assert_snapshot_matches!(db.diagnostics(), @r###"
+"#[path = \".\\Sub\\Baz.rs\"] mod baz;": module file is named `sub/baz.rs` instead of `Sub/Baz.rs`
+"mod bar;": module file is named `models/bar.rs` instead of `Models/bar.rs`
+"###
+    );
+}
+
 #[test]
 fn module_resolution_decl_inside_inline_module_in_crate_root() {
     let map = def_map_with_crate_graph(
@@ -706,3 +884,938 @@ fn unresolved_module_diagnostics() {
 "###
     );
 }
+
+#[test]
+fn module_file_with_mismatched_case() {
+    let map = def_map(
+        "
+        //- /lib.rs
+        mod Foo;
+        use Foo::Bar;
+
+        //- /foo.rs
+        pub struct Bar;
+        ",
+    );
+    assert_snapshot_matches!(map, @r###"
+        ⋮crate
+        ⋮Bar: t v
+        ⋮Foo: t
+        ⋮
+        ⋮crate::Foo
+        ⋮Bar: t v
+    "###);
+
+    let diagnostics = MockDatabase::with_files(
+        r"
+        //- /lib.rs
+        mod Foo;
+        //- /foo.rs
+        ",
+    )
+    .diagnostics();
+
+    assert_snapshot_matches!(diagnostics, @r###"
+"mod Foo;": module file is named `foo.rs` instead of `Foo.rs`
+"###
+    );
+}
+
+#[test]
+fn module_directory_with_mismatched_case() {
+    let diagnostics = MockDatabase::with_files(
+        r"
+        //- /lib.rs
+        mod Foo;
+        //- /foo/mod.rs
+        ",
+    )
+    .diagnostics();
+
+    assert_snapshot_matches!(diagnostics, @r###"
+"mod Foo;": module file is named `foo/mod.rs` instead of `Foo/mod.rs`
+"###
+    );
+}
+
+#[test]
+fn module_for_file_finds_submodule() {
+    let db = MockDatabase::with_files(
+        "
+        //- /lib.rs
+        mod foo;
+
+        //- /foo/mod.rs
+        pub mod bar;
+
+        //- /foo/bar.rs
+        pub struct Baz;
+        ",
+    );
+    let krate = Crate { crate_id: db.crate_graph().iter().next().unwrap() };
+    let def_map = db.crate_def_map(krate);
+
+    let bar_file = db.file_id_of("/foo/bar.rs");
+    let bar = def_map.module_for_file(bar_file).unwrap();
+    assert_eq!(def_map[bar].definition, Some(bar_file));
+
+    let foo = def_map[bar].parent.unwrap();
+    assert_eq!(def_map.module_for_file(db.file_id_of("/foo/mod.rs")), Some(foo));
+    assert_eq!(def_map.module_for_file(db.file_id_of("/lib.rs")), Some(def_map.root()));
+}
+
+#[test]
+fn diagnostics_are_grouped_by_file() {
+    let db = MockDatabase::with_files(
+        "
+        //- /lib.rs
+        mod foo;
+        mod missing1;
+
+        //- /foo.rs
+        mod missing2;
+        mod missing3;
+        ",
+    );
+    let krate = Crate { crate_id: db.crate_graph().iter().next().unwrap() };
+
+    let by_file = krate.diagnostics_by_file(&db);
+    assert_eq!(by_file.len(), 2);
+    let expected =
+        [("/lib.rs", vec!["mod missing1;"]), ("/foo.rs", vec!["mod missing2;", "mod missing3;"])];
+    for (path, expected) in expected.iter() {
+        let file_id = db.file_id_of(path);
+        let text = db.file_text(file_id);
+        let mut actual: Vec<&str> = by_file[&file_id]
+            .iter()
+            .map(|(range, message)| {
+                assert_eq!(message, "unresolved module");
+                &text[range.start().to_usize()..range.end().to_usize()]
+            })
+            .collect();
+        actual.sort();
+        assert_eq!(&actual, expected);
+    }
+}
+
+#[test]
+fn files_outside_of_crates_have_no_module() {
+    let mut db = MockDatabase::with_files(
+        "
+        //- /main.rs
+        mod foo;
+
+        //- /foo.rs
+        pub struct Foo;
+
+        //- /unloaded/lib.rs
+        mod bar;
+
+        //- /unloaded/bar.rs
+        pub struct Bar;
+        ",
+    );
+    // the root of the second crate failed to load, so it's not in the graph
+    db.set_crate_graph_from_fixture(crate_graph! {
+        "main": ("/main.rs", []),
+    });
+
+    let module_for = |path| crate::source_binder::module_from_file_id(&db, db.file_id_of(path));
+    assert!(module_for("/foo.rs").is_some());
+    assert!(module_for("/unloaded/lib.rs").is_none());
+    assert!(module_for("/unloaded/bar.rs").is_none());
+}
+
+#[test]
+fn module_ancestors_lead_to_crate_root() {
+    let db = MockDatabase::with_files(
+        "
+        //- /lib.rs
+        mod foo;
+
+        //- /foo/mod.rs
+        mod bar;
+
+        //- /foo/bar.rs
+        mod baz {}
+        ",
+    );
+    let krate = Crate { crate_id: db.crate_graph().iter().next().unwrap() };
+    let def_map = db.crate_def_map(krate);
+
+    let root = def_map.root();
+    let foo = def_map.module_for_file(db.file_id_of("/foo/mod.rs")).unwrap();
+    let bar = def_map.module_for_file(db.file_id_of("/foo/bar.rs")).unwrap();
+    let baz = *def_map[bar].children.values().next().unwrap();
+
+    assert_eq!(def_map.ancestors(baz).collect::<Vec<_>>(), vec![baz, bar, foo, root]);
+    assert_eq!(def_map.ancestors(root).collect::<Vec<_>>(), vec![root]);
+    assert_eq!(def_map.depth(baz), 3);
+    assert_eq!(def_map.depth(root), 0);
+}
+
+#[test]
+fn misplaced_nested_module_reports_single_problem() {
+    // `a.rs` owns the `a/` directory, so `b.rs` is expected at `a/b.rs`. The
+    // modules declared by the misplaced `b.rs` are not looked at, so only the
+    // declaration which needs fixing is reported.
+    let db = MockDatabase::with_files(
+        r"
+        //- /lib.rs
+        mod a;
+        //- /a.rs
+        mod b;
+        //- /b.rs
+        mod c;
+        //- /b/c.rs
+        pub struct C;
+        ",
+    );
+    // `b.rs` and `b/c.rs` have no module, so only the others are checked.
+    let mut diagnostics = String::from("\n");
+    for path in &["/lib.rs", "/a.rs"] {
+        let module = crate::source_binder::module_from_file_id(&db, db.file_id_of(path)).unwrap();
+        module.diagnostics(
+            &db,
+            &mut DiagnosticSink::new(|d| {
+                diagnostics += &format!("{:?}: {}\n", d.syntax_node(&db).text(), d.message());
+            }),
+        );
+    }
+
+    assert_snapshot_matches!(diagnostics, @r###"
+"mod b;": unresolved module
+"###
+    );
+}
+
+#[test]
+fn unparsable_module_file_diagnostics() {
+    let diagnostics = MockDatabase::with_files(
+        r"
+        //- /lib.rs
+        mod foo;
+        mod bar;
+        mod baz;
+        //- /foo.rs
+        )))
+        //- /bar.rs
+        //- /baz.rs
+        pub struct Baz;
+        fn broken( {}
+        ",
+    )
+    .diagnostics();
+
+    assert_snapshot_matches!(diagnostics, @r###"
+"mod foo;": module file has syntax errors
+"###
+    );
+}
+
+#[test]
+fn crate_roots_are_listed() {
+    let mut db = MockDatabase::with_files(
+        "
+        //- /main.rs
+        extern crate lib;
+        mod foo;
+
+        //- /foo.rs
+        pub struct Foo;
+
+        //- /lib/lib.rs
+        mod bar;
+
+        //- /lib/bar.rs
+        pub struct Bar;
+        ",
+    );
+    db.set_crate_graph_from_fixture(crate_graph! {
+        "main": ("/main.rs", ["lib"]),
+        "lib": ("/lib/lib.rs", []),
+    });
+
+    let module_for = |path| crate::source_binder::module_from_file_id(&db, db.file_id_of(path));
+    let roots = Module::crate_roots(&db);
+    assert_eq!(roots.len(), 2);
+    assert_eq!(roots, vec![module_for("/main.rs").unwrap(), module_for("/lib/lib.rs").unwrap()]);
+}
+
+#[test]
+fn module_cycle_through_path_attributes() {
+    let map = def_map(
+        r#"
+        //- /lib.rs
+        mod foo;
+
+        //- /foo.rs
+        #[path = "bar.rs"]
+        mod bar;
+
+        //- /bar.rs
+        #[path = "foo.rs"]
+        mod foo;
+        "#,
+    );
+    assert_snapshot_matches!(map, @r###"
+        ⋮crate
+        ⋮foo: t
+        ⋮
+        ⋮crate::foo
+        ⋮bar: t
+        ⋮
+        ⋮crate::foo::bar
+    "###);
+
+    let diagnostics = MockDatabase::with_files(
+        r#"
+        //- /lib.rs
+        #[path = "lib.rs"]
+        mod this;
+        "#,
+    )
+    .diagnostics();
+
+    assert_snapshot_matches!(diagnostics, @r###"
+"#[path = \"lib.rs\"]\nmod this;": circular modules: the module file is already one of its ancestors
+"###
+    );
+}
+
+#[test]
+fn module_diagnostics_of_all_source_roots() {
+    let mut db = MockDatabase::with_files(
+        "
+        //- /lib.rs
+        mod foo;
+        mod missing_in_lib;
+
+        //- /foo.rs
+
+        //- root /main/
+
+        //- /main/main.rs
+        mod missing_in_main;
+        ",
+    );
+    db.set_crate_graph_from_fixture(crate_graph! {
+        "main": ("/main/main.rs", ["lib"]),
+        "lib": ("/lib.rs", []),
+    });
+
+    let diagnostics: Vec<_> = Crate::all_module_diagnostics(&db)
+        .into_iter()
+        .map(|(source_root, file_id, range, message)| {
+            (source_root.0, file_id, u32::from(range.start()), message)
+        })
+        .collect();
+    assert_eq!(
+        diagnostics,
+        vec![
+            (0, db.file_id_of("/lib.rs"), 9, "unresolved module".to_string()),
+            (1, db.file_id_of("/main/main.rs"), 0, "unresolved module".to_string()),
+        ]
+    );
+}
+
+#[test]
+fn module_declarations_with_visibility_and_attributes() {
+    let map = def_map(
+        "
+        //- /lib.rs
+        pub mod foo;
+        #[doc(hidden)] pub(crate) mod bar;
+        /// Docs
+        mod baz;
+        //! Inner docs are not attached to the next item
+        #[allow(unused)]
+        /// Docs
+        pub(in crate) mod qux;
+
+        //- /foo.rs
+        pub struct Foo;
+        //- /bar.rs
+        pub struct Bar;
+        //- /baz.rs
+        pub struct Baz;
+        //- /qux.rs
+        pub struct Qux;
+        ",
+    );
+    assert_snapshot_matches!(sort_modules(&map), @r###"
+        ⋮crate
+        ⋮bar: t
+        ⋮baz: t
+        ⋮foo: t
+        ⋮qux: t
+        ⋮
+        ⋮crate::bar
+        ⋮Bar: t v
+        ⋮
+        ⋮crate::baz
+        ⋮Baz: t v
+        ⋮
+        ⋮crate::foo
+        ⋮Foo: t v
+        ⋮
+        ⋮crate::qux
+        ⋮Qux: t v
+    "###);
+}
+
+#[test]
+fn out_of_line_module_resolves_to_child_module() {
+    let db = MockDatabase::with_files(
+        "
+        //- /lib.rs
+        mod foo;
+        mod bar {}
+
+        //- /foo.rs
+        pub struct Baz;
+        ",
+    );
+    let krate = Crate { crate_id: db.crate_graph().iter().next().unwrap() };
+    let def_map = db.crate_def_map(krate);
+    let root = def_map.root();
+    let foo = def_map.module_for_file(db.file_id_of("/foo.rs")).unwrap();
+
+    let resolve_module = |name: &str| {
+        let (_, resolution) =
+            def_map[root].scope.entries().find(|(it, _)| it.to_string() == name).unwrap();
+        match resolution.def.take_types() {
+            Some(ModuleDef::Module(module)) => module,
+            def => panic!("`{}` resolves to {:?}", name, def),
+        }
+    };
+    assert_eq!(resolve_module("foo"), Module { krate, module_id: foo });
+    assert_eq!(def_map[root].children.values().filter(|&&it| it == foo).count(), 1);
+    let bar = resolve_module("bar");
+    assert_eq!(def_map[bar.module_id].parent, Some(root));
+}
+
+fn cfg_attr_path_modules(cfg_options: CfgOptions) -> String {
+    let mut db = MockDatabase::with_files(
+        r#"
+        //- /lib.rs
+        #[cfg_attr(unix, path = "unix.rs")]
+        mod imp;
+        #[cfg_attr(all(unix, feature = "x"), path = "other.rs")]
+        mod foo;
+
+        //- /imp.rs
+        pub struct Default;
+
+        //- /unix.rs
+        pub struct Unix;
+
+        //- /foo.rs
+        pub struct Foo;
+
+        //- /other.rs
+        pub struct Other;
+        "#,
+    );
+    let mut crate_graph = (*db.crate_graph()).clone();
+    let crate_id = crate_graph.iter().next().unwrap();
+    crate_graph.set_cfg_options(crate_id, cfg_options);
+    db.set_crate_graph(Arc::new(crate_graph));
+    let map = db.crate_def_map(Crate { crate_id });
+    assert!(map.problems(&db).is_empty());
+    sort_modules(&render_crate_def_map(&map))
+}
+
+#[test]
+fn module_resolution_with_enabled_cfg_attr_path() {
+    let mut cfg_options = CfgOptions::default();
+    cfg_options.insert_atom("unix".into());
+    assert_snapshot_matches!(cfg_attr_path_modules(cfg_options.clone()), @r###"
+        ⋮crate
+        ⋮foo: t
+        ⋮imp: t
+        ⋮
+        ⋮crate::foo
+        ⋮Foo: t v
+        ⋮
+        ⋮crate::imp
+        ⋮Unix: t v
+    "###);
+
+    cfg_options.insert_key_value("feature".into(), "x".into());
+    assert_snapshot_matches!(cfg_attr_path_modules(cfg_options), @r###"
+        ⋮crate
+        ⋮foo: t
+        ⋮imp: t
+        ⋮
+        ⋮crate::foo
+        ⋮Other: t v
+        ⋮
+        ⋮crate::imp
+        ⋮Unix: t v
+    "###);
+}
+
+#[test]
+fn module_resolution_with_disabled_cfg_attr_path() {
+    assert_snapshot_matches!(cfg_attr_path_modules(CfgOptions::default()), @r###"
+        ⋮crate
+        ⋮foo: t
+        ⋮imp: t
+        ⋮
+        ⋮crate::foo
+        ⋮Foo: t v
+        ⋮
+        ⋮crate::imp
+        ⋮Default: t v
+    "###);
+}
+
+#[test]
+fn module_resolution_with_crate_root_in_subdirectory() {
+    // Like `[lib] path = "source/lib.rs"`: the crate root owns its directory,
+    // whatever its name and location.
+    let map = def_map_with_crate_graph(
+        "
+        //- /source/lib.rs
+        mod foo;
+        mod baz;
+
+        //- /source/foo.rs
+        mod bar;
+
+        //- /source/foo/bar.rs
+        pub struct Bar;
+
+        //- /source/baz/mod.rs
+        pub struct Baz;
+        ",
+        crate_graph! {
+            "lib": ("/source/lib.rs", []),
+        },
+    );
+
+    assert_snapshot_matches!(sort_modules(&map), @r###"
+        ⋮crate
+        ⋮baz: t
+        ⋮foo: t
+        ⋮
+        ⋮crate::baz
+        ⋮Baz: t v
+        ⋮
+        ⋮crate::foo
+        ⋮bar: t
+        ⋮
+        ⋮crate::foo::bar
+        ⋮Bar: t v
+    "###);
+}
+
+fn assert_round_trips(problem: ModuleProblem, json: &str) {
+    assert_eq!(serde_json::to_string(&problem).unwrap(), json);
+    assert_eq!(serde_json::from_str::<ModuleProblem>(json).unwrap(), problem);
+}
+
+#[test]
+fn module_problems_round_trip() {
+    assert_round_trips(
+        ModuleProblem::UnresolvedModule { file: 1, range: [0, 8], candidate: "foo.rs".into() },
+        r#"{"kind":"unresolved_module","file":1,"range":[0,8],"candidate":"foo.rs"}"#,
+    );
+    assert_round_trips(
+        ModuleProblem::ModuleCaseMismatch {
+            file: 1,
+            range: [0, 8],
+            declared: "foo.rs".into(),
+            on_disk: "Foo.rs".into(),
+        },
+        r#"{"kind":"module_case_mismatch","file":1,"range":[0,8],"declared":"foo.rs","on_disk":"Foo.rs"}"#,
+    );
+    assert_round_trips(
+        ModuleProblem::ModuleFileParseError { file: 2, range: [4, 12] },
+        r#"{"kind":"module_file_parse_error","file":2,"range":[4,12]}"#,
+    );
+    assert_round_trips(
+        ModuleProblem::ModuleCycle { file: 3, range: [0, 8] },
+        r#"{"kind":"module_cycle","file":3,"range":[0,8]}"#,
+    );
+    assert_round_trips(
+        ModuleProblem::UnresolvedGeneratedModule {
+            file: 1,
+            range: [0, 60],
+            path: "/generated.rs".into(),
+        },
+        r#"{"kind":"unresolved_generated_module","file":1,"range":[0,60],"path":"/generated.rs"}"#,
+    );
+    assert_round_trips(
+        ModuleProblem::ModuleFileNotIndexed { file: 1, range: [0, 40], path: "../foo.rs".into() },
+        r#"{"kind":"module_file_not_indexed","file":1,"range":[0,40],"path":"../foo.rs"}"#,
+    );
+}
+
+#[test]
+fn module_problems_point_at_declarations() {
+    let db = MockDatabase::with_files(
+        "
+        //- /lib.rs
+        mod foo;
+        mod missing;
+
+        //- /foo.rs
+        ",
+    );
+    let krate = Crate { crate_id: db.crate_graph().iter().next().unwrap() };
+    let lib = db.file_id_of("/lib.rs");
+
+    let problems = db.crate_def_map(krate).problems(&db);
+    assert_eq!(
+        problems,
+        vec![ModuleProblem::UnresolvedModule {
+            file: lib.0,
+            range: [9, 21],
+            candidate: "missing.rs".into(),
+        }]
+    );
+}
+
+#[test]
+fn macro_use_modules_record_their_position() {
+    let map = compute_crate_def_map(
+        "
+        //- /lib.rs
+        mod a;
+        #[macro_use]
+        mod m;
+        fn f() {}
+        #[macro_use]
+        mod inline {}
+
+        //- /a.rs
+        //- /m.rs
+        ",
+        None,
+    );
+    let macro_use = |name: &str| {
+        let (_, &child) =
+            map[map.root].children.iter().find(|(it, _)| it.to_string() == name).unwrap();
+        map[child].macro_use
+    };
+    assert_eq!(macro_use("a"), None);
+    assert_eq!(macro_use("m"), Some(1));
+    assert_eq!(macro_use("inline"), Some(3));
+}
+
+#[test]
+fn module_declarations_with_any_visibility_are_resolved() {
+    let map = def_map(
+        "
+        //- /lib.rs
+        pub mod a;
+        pub(crate) mod b;
+        mod c;
+
+        //- /a.rs
+        pub(super) mod d;
+        pub(in crate::a) mod e;
+        pub(self) mod f;
+
+        //- /b.rs
+        //- /c.rs
+        //- /a/d.rs
+        //- /a/e.rs
+        //- /a/f.rs
+        ",
+    );
+    assert_snapshot_matches!(sort_modules(&map), @r###"
+        ⋮crate
+        ⋮a: t
+        ⋮b: t
+        ⋮c: t
+        ⋮
+        ⋮crate::a
+        ⋮d: t
+        ⋮e: t
+        ⋮f: t
+        ⋮
+        ⋮crate::a::d
+        ⋮
+        ⋮crate::a::e
+        ⋮
+        ⋮crate::a::f
+        ⋮
+        ⋮crate::b
+        ⋮
+        ⋮crate::c
+    "###);
+}
+
+#[test]
+fn modules_generated_in_out_dir_are_reported() {
+    let diagnostics = MockDatabase::with_files(
+        r#"
+        //- /lib.rs
+        #[path = concat!(env!("OUT_DIR"), "/generated.rs")] mod generated;
+        #[path = concat!(env!("OTHER"), "/other.rs")] mod other;
+        "#,
+    )
+    .diagnostics();
+
+    assert_snapshot_matches!(diagnostics, @r###"
+"#[path = concat!(env!(\"OUT_DIR\"), \"/generated.rs\")] mod generated;": can't resolve module generated in OUT_DIR: /generated.rs
+"#[path = concat!(env!(\"OTHER\"), \"/other.rs\")] mod other;": unresolved module
+"###
+    );
+}
+
+#[test]
+fn modules_generated_in_out_dir_are_resolved() {
+    let mut db = MockDatabase::with_files(
+        r#"
+        //- /lib.rs
+        #[path = concat!(env!("OUT_DIR"), "/generated.rs")] mod generated;
+        #[path = concat!(env!("OUT_DIR"), "/missing.rs")] mod missing;
+
+        //- root /out/
+        //- /out/generated.rs
+        pub struct Generated;
+        "#,
+    );
+    let mut crate_graph = (*db.crate_graph()).clone();
+    let crate_id = crate_graph.iter().next().unwrap();
+    let mut env = Env::default();
+    env.set("OUT_DIR", "/out".to_string());
+    crate_graph.set_env(crate_id, env);
+    crate_graph.set_extern_path("/out".into(), SourceRootId(1));
+    db.set_crate_graph(Arc::new(crate_graph));
+
+    let map = render_crate_def_map(&db.crate_def_map(Crate { crate_id }));
+    assert_snapshot_matches!(map, @r###"
+        ⋮crate
+        ⋮generated: t
+        ⋮
+        ⋮crate::generated
+        ⋮Generated: t v
+    "###);
+    assert_snapshot_matches!(db.diagnostics(), @r###"
+"#[path = concat!(env!(\"OUT_DIR\"), \"/missing.rs\")] mod missing;": can't resolve module generated in OUT_DIR: /missing.rs
+"###
+    );
+}
+
+#[test]
+fn module_files_outside_of_source_root_are_reported() {
+    let mut db = MockDatabase::with_files(
+        r#"
+        //- root /ws/
+        //- /ws/lib.rs
+        #[path = "../vendor/outside.rs"] mod outside;
+        #[path = "../vendor/missing.rs"] mod missing_outside;
+        mod missing;
+
+        //- root /vendor/
+        //- /vendor/outside.rs
+        pub struct Outside;
+        "#,
+    );
+    let mut crate_graph = (*db.crate_graph()).clone();
+    crate_graph.set_extern_path("/ws".into(), SourceRootId(1));
+    crate_graph.set_extern_path("/vendor".into(), SourceRootId(2));
+    db.set_crate_graph(Arc::new(crate_graph));
+
+    // `outside.rs` has no module, so only `lib.rs` is checked.
+    let mut diagnostics = String::from("\n");
+    let module = crate::source_binder::module_from_file_id(&db, db.file_id_of("/ws/lib.rs"));
+    module.unwrap().diagnostics(
+        &db,
+        &mut DiagnosticSink::new(|d| {
+            diagnostics += &format!("{:?}: {}\n", d.syntax_node(&db).text(), d.message());
+        }),
+    );
+
+    assert_snapshot_matches!(diagnostics, @r###"
+"#[path = \"../vendor/outside.rs\"] mod outside;": module file exists in another source root: ../vendor/outside.rs
+"#[path = \"../vendor/missing.rs\"] mod missing_outside;": unresolved module
+"mod missing;": unresolved module
+"###
+    );
+}
+
+#[test]
+fn modules_are_resolved_in_files_with_bom_and_crlf() {
+    let mut db = MockDatabase::with_files(
+        "
+        //- /lib.rs
+        //- /foo.rs
+        pub struct Bar;
+        ",
+    );
+    let lib = db.file_id_of("/lib.rs");
+    db.set_file_text(lib, Arc::new("\u{feff}mod foo;\r\nmod missing;\r\nuse foo::Bar;\r\n".into()));
+    let krate = Crate { crate_id: db.crate_graph().iter().next().unwrap() };
+    let def_map = db.crate_def_map(krate);
+
+    let rendered = render_crate_def_map(&def_map);
+    assert_snapshot_matches!(rendered, @r###"
+        ⋮crate
+        ⋮Bar: t v
+        ⋮foo: t
+        ⋮
+        ⋮crate::foo
+        ⋮Bar: t v
+    "###);
+    assert_eq!(
+        def_map.problems(&db),
+        vec![ModuleProblem::UnresolvedModule {
+            file: lib.0,
+            range: [13, 25],
+            candidate: "missing.rs".into(),
+        }]
+    );
+}
+
+#[test]
+fn bin_with_custom_root_owns_its_directory() {
+    // Like `[[bin]] path = "src/app.rs"`.
+    let map = def_map_with_crate_graph(
+        "
+        //- /src/app.rs
+        mod util;
+
+        //- /src/util.rs
+        pub struct Helper;
+
+        //- /src/app/util.rs
+        pub struct Wrong;
+        ",
+        crate_graph! {
+            "app": ("/src/app.rs", []),
+        },
+    );
+
+    assert_snapshot_matches!(map, @r###"
+        ⋮crate
+        ⋮util: t
+        ⋮
+        ⋮crate::util
+        ⋮Helper: t v
+    "###);
+}
+
+#[test]
+fn module_files_can_be_resolved_with_a_custom_resolver() {
+    #[derive(Debug)]
+    struct GeneratedFirst;
+
+    impl ModuleNameResolver for GeneratedFirst {
+        fn candidates(&self, name: &str) -> Vec<String> {
+            vec![format!("{}.gen.rs", name), format!("{}.rs", name)]
+        }
+    }
+
+    let mut db = MockDatabase::with_files(
+        "
+        //- /lib.rs
+        mod foo;
+        mod bar;
+
+        //- /foo.gen.rs
+        pub struct Generated;
+
+        //- /foo.rs
+        pub struct Handwritten;
+
+        //- /bar.rs
+        pub struct Bar;
+        ",
+    );
+    db.set_module_name_resolver(ModuleNameResolverRef(Arc::new(GeneratedFirst)));
+    let krate = Crate { crate_id: db.crate_graph().iter().next().unwrap() };
+    let map = render_crate_def_map(&db.crate_def_map(krate));
+
+    assert_snapshot_matches!(sort_modules(&map), @r###"
+        ⋮crate
+        ⋮bar: t
+        ⋮foo: t
+        ⋮
+        ⋮crate::bar
+        ⋮Bar: t v
+        ⋮
+        ⋮crate::foo
+        ⋮Generated: t v
+    "###);
+}
+
+fn cfg_test_modules(cfg_test: bool) -> String {
+    let mut db = MockDatabase::with_files(
+        r#"
+        //- /lib.rs
+        #[cfg(test)]
+        #[path = "imp_test.rs"]
+        mod imp;
+        #[cfg(not(test))]
+        mod imp;
+
+        //- /imp.rs
+        pub struct Real;
+
+        //- /imp_test.rs
+        pub struct Mock;
+        "#,
+    );
+    let mut crate_graph = (*db.crate_graph()).clone();
+    let crate_id = crate_graph.iter().next().unwrap();
+    crate_graph.set_cfg_test(crate_id, cfg_test);
+    db.set_crate_graph(Arc::new(crate_graph));
+    let map = db.crate_def_map(Crate { crate_id });
+    // The other file has no module, so it has no diagnostics either.
+    assert!(map.problems(&db).is_empty());
+    render_crate_def_map(&map)
+}
+
+#[test]
+fn cfg_test_module_is_selected_in_tests() {
+    assert_snapshot_matches!(cfg_test_modules(true), @r###"
+        ⋮crate
+        ⋮imp: t
+        ⋮
+        ⋮crate::imp
+        ⋮Mock: t v
+    "###);
+}
+
+#[test]
+fn cfg_not_test_module_is_selected_outside_of_tests() {
+    assert_snapshot_matches!(cfg_test_modules(false), @r###"
+        ⋮crate
+        ⋮imp: t
+        ⋮
+        ⋮crate::imp
+        ⋮Real: t v
+    "###);
+}
+
+#[test]
+fn inline_cfg_test_module_is_kept_outside_of_tests() {
+    let map = def_map(
+        "
+        //- /lib.rs
+        pub struct Foo;
+        #[cfg(test)]
+        mod tests {
+            use super::Foo;
+            #[cfg(all(test, unix))]
+            mod unix {}
+        }
+        #[cfg(all(test, not(test)))]
+        mod never {}
+        ",
+    );
+    assert_snapshot_matches!(map, @r###"
+        ⋮crate
+        ⋮Foo: t v
+        ⋮tests: t
+        ⋮
+        ⋮crate::tests
+        ⋮Foo: t v
+    "###);
+}
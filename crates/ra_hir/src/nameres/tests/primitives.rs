@@ -22,3 +22,23 @@ fn primitive_reexport() {
     "###
     );
 }
+
+#[test]
+fn module_scope_items_shadow_primitives() {
+    let map = compute_crate_def_map(
+        "
+        //- /lib.rs
+        struct str;
+        ",
+        None,
+    );
+    let scope = &map[map.root()].scope;
+
+    match scope.get(&crate::name::STR).and_then(|it| it.def.take_types()) {
+        Some(crate::ModuleDef::Struct(_)) => (),
+        it => panic!("expected a struct, got {:?}", it),
+    }
+    assert_eq!(scope.entries().filter(|(name, _)| **name == crate::name::STR).count(), 1);
+    assert!(scope.get(&crate::name::BOOL).is_some());
+    assert!(scope.get(&crate::name::FUTURE_TYPE).is_none());
+}
@@ -1,5 +1,6 @@
 use std::{sync::Arc, time};
 
+use hir::db::DefDatabase;
 use ra_db::{
     salsa::{self, Database},
     Canceled, CheckCanceled, FileId, SourceDatabase,
@@ -60,6 +61,7 @@ impl RootDatabase {
         db.set_crate_graph(Default::default());
         db.set_local_roots(Default::default());
         db.set_library_roots(Default::default());
+        db.set_module_name_resolver(Default::default());
         let lru_capacity = lru_capacity.unwrap_or(ra_db::DEFAULT_LRU_CAP);
         db.query_mut(ra_db::ParseQuery).set_lru_capacity(lru_capacity);
         db.query_mut(hir::db::ParseMacroQuery).set_lru_capacity(lru_capacity);
@@ -75,7 +75,8 @@ pub use crate::{
 
 pub use hir::Documentation;
 pub use ra_db::{
-    Canceled, CrateGraph, CrateId, Edition, FileId, FilePosition, FileRange, SourceRootId,
+    Canceled, CfgOptions, CrateGraph, CrateId, Edition, FileId, FilePosition, FileRange,
+    SourceRootId,
 };
 
 pub type Cancelable<T> = Result<T, Canceled>;
@@ -476,6 +477,12 @@ impl Analysis {
         self.with_db(|db| parent_module::crate_for(db, file_id))
     }
 
+    /// Returns crates whose analysis can change when this file changes: the
+    /// crates it belongs to and everything that depends on them.
+    pub fn affected_crates(&self, file_id: FileId) -> Cancelable<Vec<CrateId>> {
+        self.with_db(|db| parent_module::affected_crates(db, file_id))
+    }
+
     /// Returns the root file of the given crate.
     pub fn crate_root(&self, crate_id: CrateId) -> Cancelable<FileId> {
         self.with_db(|db| db.crate_graph().crate_root(crate_id))
@@ -1,4 +1,4 @@
-use ra_db::{CrateId, FileId, FilePosition};
+use ra_db::{CrateId, FileId, FilePosition, SourceDatabase};
 
 use crate::{db::RootDatabase, NavigationTarget};
 
@@ -26,6 +26,17 @@ pub(crate) fn crate_for(db: &RootDatabase, file_id: FileId) -> Vec<CrateId> {
     vec![krate.crate_id()]
 }
 
+pub(crate) fn affected_crates(db: &RootDatabase, file_id: FileId) -> Vec<CrateId> {
+    let crate_graph = db.crate_graph();
+    let mut res = crate_for(db, file_id);
+    for krate in res.clone() {
+        res.extend(crate_graph.crates_depending_on(krate));
+    }
+    res.sort();
+    res.dedup();
+    res
+}
+
 #[cfg(test)]
 mod tests {
     use crate::{
@@ -87,4 +98,34 @@ mod tests {
 
         assert_eq!(host.analysis().crate_for(mod_file).unwrap(), vec![crate_id]);
     }
+
+    #[test]
+    fn test_affected_crates() {
+        let mock = MockAnalysis::with_files(
+            "
+        //- /a.rs
+        // empty
+        //- /b.rs
+        mod foo;
+        //- /foo.rs
+        // empty
+        //- /c.rs
+        // empty
+    ",
+        );
+        let mod_file = mock.id_of("/foo.rs");
+        let mut crate_graph = CrateGraph::default();
+        let a = crate_graph.add_crate_root(mock.id_of("/a.rs"), Edition2018);
+        let b = crate_graph.add_crate_root(mock.id_of("/b.rs"), Edition2018);
+        let c = crate_graph.add_crate_root(mock.id_of("/c.rs"), Edition2018);
+        let mut host = mock.analysis_host();
+        crate_graph.add_dep(a, "b".into(), b).unwrap();
+        crate_graph.add_dep(b, "c".into(), c).unwrap();
+        let mut change = AnalysisChange::new();
+        change.set_crate_graph(crate_graph);
+        host.apply_change(change);
+
+        let affected = host.analysis().affected_crates(mod_file).unwrap();
+        assert_eq!(affected, vec![a, b]);
+    }
 }
@@ -66,7 +66,8 @@ impl CargoTargetSpec {
         let file_id = world.analysis().crate_root(crate_id)?;
         let path = world.vfs.read().file2path(ra_vfs::VfsFile(file_id.0));
         let res = world.workspaces.iter().find_map(|ws| match ws {
-            project_model::ProjectWorkspace::Cargo { cargo, .. } => {
+            project_model::ProjectWorkspace::Cargo { cargo, .. }
+            | project_model::ProjectWorkspace::Merged { cargo, .. } => {
                 let tgt = cargo.target_by_root(&path)?;
                 Some(CargoTargetSpec {
                     package: tgt.package(&cargo).name(&cargo).to_string(),
@@ -74,7 +75,8 @@ impl CargoTargetSpec {
                     target_kind: tgt.kind(&cargo),
                 })
             }
-            project_model::ProjectWorkspace::Json { .. } => None,
+            project_model::ProjectWorkspace::Json { .. }
+            | project_model::ProjectWorkspace::DetachedFile { .. } => None,
         });
         Ok(res)
     }
@@ -99,7 +101,7 @@ impl CargoTargetSpec {
                 buf.push("--example".to_string());
                 buf.push(self.target);
             }
-            TargetKind::Lib => {
+            TargetKind::Lib | TargetKind::ProcMacro => {
                 buf.push("--lib".to_string());
             }
             TargetKind::Other => (),
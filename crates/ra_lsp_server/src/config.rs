@@ -1,3 +1,4 @@
+use ra_project_model::CargoConfig;
 use serde::{Deserialize, Deserializer};
 
 /// Client provided initialization options
@@ -21,6 +22,9 @@ pub struct ServerConfig {
     pub exclude_globs: Vec<String>,
 
     pub lru_capacity: Option<usize>,
+
+    /// Options for loading cargo workspaces
+    pub cargo: CargoConfig,
 }
 
 impl Default for ServerConfig {
@@ -30,6 +34,7 @@ impl Default for ServerConfig {
             show_workspace_loaded: true,
             exclude_globs: Vec::new(),
             lru_capacity: None,
+            cargo: CargoConfig::default(),
         }
     }
 }
@@ -59,7 +59,7 @@ pub fn main_loop(
     log::debug!("server_config: {:?}", config);
     // FIXME: support dynamic workspace loading.
     let workspaces = {
-        let ws_worker = workspace_loader();
+        let ws_worker = workspace_loader(config.cargo.clone());
         let mut loaded_workspaces = Vec::new();
         for ws_root in &ws_roots {
             ws_worker.sender().send(ws_root.clone()).unwrap();
@@ -99,6 +99,10 @@ pub fn main_loop(
         },
     );
 
+    for problem in state.crate_graph_problems.iter() {
+        show_message(req::MessageType::Warning, problem.to_string(), msg_sender);
+    }
+
     let pool = ThreadPool::new(THREADPOOL_SIZE);
     let (task_sender, task_receiver) = unbounded::<Task>();
     let mut pending_requests = PendingRequests::default();
@@ -5,17 +5,18 @@ use thread_worker::Worker;
 use crate::Result;
 
 pub use ra_project_model::{
-    CargoWorkspace, Package, ProjectWorkspace, Sysroot, Target, TargetKind,
+    CargoConfig, CargoWorkspace, CrateGraphProblem, Package, ProjectWorkspace, Sysroot, Target,
+    TargetKind,
 };
 
-pub fn workspace_loader() -> Worker<PathBuf, Result<ProjectWorkspace>> {
+pub fn workspace_loader(cargo_config: CargoConfig) -> Worker<PathBuf, Result<ProjectWorkspace>> {
     Worker::<PathBuf, Result<ProjectWorkspace>>::spawn(
         "workspace loader",
         1,
-        |input_receiver, output_sender| {
+        move |input_receiver, output_sender| {
             input_receiver
                 .into_iter()
-                .map(|path| ProjectWorkspace::discover(path.as_path()))
+                .map(|path| ProjectWorkspace::discover(path.as_path(), &cargo_config))
                 .try_for_each(|it| output_sender.send(it))
                 .unwrap()
         },
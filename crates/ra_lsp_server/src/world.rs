@@ -15,7 +15,7 @@ use relative_path::RelativePathBuf;
 
 use crate::{
     main_loop::pending_requests::{CompletedRequest, LatestRequests},
-    project_model::ProjectWorkspace,
+    project_model::{CrateGraphProblem, ProjectWorkspace},
     LspError, Result,
 };
 
@@ -37,6 +37,8 @@ pub struct WorldState {
     pub roots_to_scan: usize,
     pub roots: Vec<PathBuf>,
     pub workspaces: Arc<Vec<ProjectWorkspace>>,
+    /// Problems found while building the crate graph of the workspaces.
+    pub crate_graph_problems: Vec<CrateGraphProblem>,
     pub analysis_host: AnalysisHost,
     pub vfs: Arc<RwLock<Vfs>>,
     pub latest_requests: Arc<RwLock<LatestRequests>>,
@@ -76,16 +78,23 @@ impl WorldState {
                 for glob in exclude_globs.iter() {
                     filter = filter.exclude(glob.clone());
                 }
+                for dir in pkg_root.exclude() {
+                    if let Ok(dir) = dir.strip_prefix(pkg_root.path()) {
+                        filter = filter.exclude_dir(dir);
+                    }
+                }
                 RootEntry::new(pkg_root.path().clone(), filter.into_vfs_filter())
             }));
         }
 
         let (mut vfs, vfs_roots) = Vfs::new(roots);
         let roots_to_scan = vfs_roots.len();
+        let mut root_paths = Vec::with_capacity(roots_to_scan);
         for r in vfs_roots {
             let vfs_root_path = vfs.root2path(r);
             let is_local = folder_roots.iter().any(|it| vfs_root_path.starts_with(it));
             change.add_root(SourceRootId(r.0), is_local);
+            root_paths.push((vfs_root_path, SourceRootId(r.0)));
         }
 
         // Create crate graph from all the workspaces
@@ -94,8 +103,16 @@ impl WorldState {
             let vfs_file = vfs.load(path);
             vfs_file.map(|f| FileId(f.0))
         };
+        let mut crate_graph_problems = Vec::new();
         for ws in workspaces.iter() {
-            crate_graph.extend(ws.to_crate_graph(&mut load));
+            let (ws_crate_graph, problems) = ws.to_crate_graph_with_problems(&mut load);
+            crate_graph.extend(ws_crate_graph);
+            crate_graph_problems.extend(problems);
+        }
+        // Paths outside of a crate's sources, like its `OUT_DIR`, are looked
+        // up in the other roots.
+        for (path, root) in root_paths {
+            crate_graph.set_extern_path(path, root);
         }
         change.set_crate_graph(crate_graph);
 
@@ -106,6 +123,7 @@ impl WorldState {
             roots_to_scan,
             roots: folder_roots,
             workspaces: Arc::new(workspaces),
+            crate_graph_problems,
             analysis_host,
             vfs: Arc::new(RwLock::new(vfs)),
             latest_requests: Default::default(),
@@ -1,11 +1,19 @@
-use std::path::{Path, PathBuf};
+use std::{
+    collections::VecDeque,
+    env, fs, iter,
+    path::{Path, PathBuf},
+    process::Command,
+    time::Instant,
+};
 
-use cargo_metadata::{CargoOpt, MetadataCommand};
+use cargo_metadata::{DependencyKind, Metadata, PackageId};
 use ra_arena::{impl_arena_id, Arena, RawId};
 use ra_db::Edition;
-use rustc_hash::FxHashMap;
+use ra_prof::profile;
+use rustc_hash::{FxHashMap, FxHashSet};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
-use crate::Result;
+use crate::{file_stamps::FileStamps, fingerprint, ProjectModelError, Result};
 
 /// `CargoWorkspace` represents the logical structure of, well, a Cargo
 /// workspace. It pretty closely mirrors `cargo metadata` output.
@@ -14,56 +22,200 @@ use crate::Result;
 /// `CrateGraph`. `CrateGraph` is lower-level: it knows only about the crates,
 /// while this knows about `Packages` & `Targets`: purely cargo-related
 /// concepts.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CargoWorkspace {
     packages: Arena<Package, PackageData>,
     targets: Arena<Target, TargetData>,
     pub(crate) workspace_root: PathBuf,
+    manifest_path: PathBuf,
+    target_directory: PathBuf,
+    primary_package: Option<Package>,
+    pub(crate) stamps: FileStamps,
+    target: Option<String>,
+    fingerprint: u64,
+}
+
+/// Options which control how a `CargoWorkspace` is loaded.
+#[derive(Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "camelCase", default)]
+pub struct CargoConfig {
+    /// Load only the package of the given manifest (without dependencies and
+    /// other workspace members), trading completeness for fast startup.
+    pub single_package: bool,
+    /// Target triple to analyze the workspace for. Defaults to `build.target`
+    /// from `.cargo/config.toml`, if any, and to the host otherwise.
+    pub target: Option<String>,
+    /// Load the standard library crates. Disabling this speeds up loading of
+    /// `no_std` projects, but items from `std`, `core` and `alloc` won't
+    /// resolve anymore.
+    pub load_sysroot: bool,
+    /// Load only `std`, `core`, `alloc` and `proc_macro` from the sysroot.
+    /// This makes the crate graph smaller, but the internals of the standard
+    /// library, like `test` or `compiler_builtins`, won't resolve.
+    pub minimal_sysroot: bool,
+    /// `key=value` pairs passed to `cargo metadata` via `--config`, like
+    /// `net.offline=true`.
+    pub config_overrides: Vec<String>,
+    /// Unstable cargo features passed to `cargo metadata` via `-Z`, like
+    /// `bindeps`. These require a nightly toolchain.
+    pub unstable_flags: Vec<String>,
+    /// Name of the member the user works on. The whole workspace is still
+    /// loaded, but only this member and its path dependencies are indexed
+    /// eagerly.
+    pub focus_member: Option<String>,
+    /// Load only workspace members and the dependencies between them, to save
+    /// memory. Items of external crates, including the standard library,
+    /// won't resolve.
+    pub members_only: bool,
 }
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+impl Default for CargoConfig {
+    fn default() -> CargoConfig {
+        CargoConfig {
+            single_package: false,
+            target: None,
+            load_sysroot: true,
+            minimal_sysroot: false,
+            config_overrides: Vec::new(),
+            unstable_flags: Vec::new(),
+            focus_member: None,
+            members_only: false,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct Package(RawId);
 impl_arena_id!(Package);
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct Target(RawId);
 impl_arena_id!(Target);
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 struct PackageData {
     name: String,
+    version: String,
+    version_parts: VersionParts,
     manifest: PathBuf,
     targets: Vec<Target>,
     is_member: bool,
+    is_default_member: bool,
+    /// Whether the package lives on the local file system, rather than in a
+    /// registry or a git checkout.
+    is_local: bool,
+    is_eager: bool,
     dependencies: Vec<PackageDependency>,
+    #[serde(with = "edition")]
     edition: Edition,
+    features: FxHashMap<String, Vec<String>>,
+    enabled_features: Vec<String>,
+    rust_version: Option<String>,
 }
 
-#[derive(Debug, Clone)]
+/// Components of a semver version, as exposed to crates via the
+/// `CARGO_PKG_VERSION_*` environment variables.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VersionParts {
+    pub major: u64,
+    pub minor: u64,
+    pub patch: u64,
+    /// Pre-release part, like `alpha.1`. Empty for releases.
+    pub pre: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PackageDependency {
     pub pkg: Package,
     pub name: String,
+    pub kind: DepKind,
+    /// Optional dependencies are only present in the graph if they were
+    /// enabled, which also enables the feature of the same name.
+    pub optional: bool,
+    /// Whether the dependency is part of the public API of the package, as
+    /// declared with the unstable `public` key. `None` if it isn't declared.
+    pub public: Option<bool>,
 }
 
+/// What the manifest says about a dependency, which `resolve` doesn't know.
 #[derive(Debug, Clone)]
+struct ManifestDep {
+    kind: DepKind,
+    optional: bool,
+    /// Name of the implicit feature of an optional dependency.
+    feature: String,
+    public: Option<bool>,
+}
+
+/// The section of `Cargo.toml` a dependency comes from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DepKind {
+    /// `[dependencies]`
+    Normal,
+    /// `[dev-dependencies]`
+    Dev,
+    /// `[build-dependencies]`
+    Build,
+}
+
+impl DepKind {
+    fn new(kind: &DependencyKind) -> DepKind {
+        match kind {
+            DependencyKind::Development => DepKind::Dev,
+            DependencyKind::Build => DepKind::Build,
+            _ => DepKind::Normal,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 struct TargetData {
     pkg: Package,
     name: String,
     root: PathBuf,
     kind: TargetKind,
+    crate_types: Vec<String>,
+    required_features: Vec<String>,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Kind of a cargo target, as reported by the `kind` field of
+/// `cargo metadata`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum TargetKind {
+    /// `[[bin]]`, including the default `src/main.rs`.
     Bin,
+    /// Any library crate type (`lib`, `rlib`, `dylib`, `cdylib`, ...).
     Lib,
+    /// A library with `proc-macro = true`.
+    ProcMacro,
+    /// `[[example]]`, built with `cargo build --example`.
     Example,
+    /// `[[test]]`, an integration test, run with `cargo test --test`.
     Test,
+    /// `[[bench]]`, run with `cargo bench --bench`.
     Bench,
+    /// Build scripts and anything we don't recognize.
     Other,
 }
 
 impl TargetKind {
+    /// Whether other packages can depend on targets of this kind.
+    pub fn is_lib(self) -> bool {
+        match self {
+            TargetKind::Lib | TargetKind::ProcMacro => true,
+            _ => false,
+        }
+    }
+
+    /// Whether targets of this kind are built with the dev-dependencies of
+    /// their package.
+    pub fn has_dev_deps(self) -> bool {
+        match self {
+            TargetKind::Example | TargetKind::Test | TargetKind::Bench => true,
+            _ => false,
+        }
+    }
+
     fn new(kinds: &[String]) -> TargetKind {
         for kind in kinds {
             return match kind.as_str() {
@@ -71,6 +223,7 @@ impl TargetKind {
                 "test" => TargetKind::Test,
                 "bench" => TargetKind::Bench,
                 "example" => TargetKind::Example,
+                "proc-macro" => TargetKind::ProcMacro,
                 _ if kind.contains("lib") => TargetKind::Lib,
                 _ => continue,
             };
@@ -83,25 +236,115 @@ impl Package {
     pub fn name(self, ws: &CargoWorkspace) -> &str {
         ws.packages[self].name.as_str()
     }
+    pub fn version(self, ws: &CargoWorkspace) -> &str {
+        ws.packages[self].version.as_str()
+    }
+    pub fn version_parts(self, ws: &CargoWorkspace) -> &VersionParts {
+        &ws.packages[self].version_parts
+    }
+    /// The minimal supported Rust version, from the `rust-version` key of the
+    /// manifest. Omitted components are zero, so `1.56` is `1.56.0`.
+    pub fn rust_version(self, ws: &CargoWorkspace) -> Option<semver::Version> {
+        let rust_version = ws.packages[self].rust_version.as_ref()?;
+        let missing = 2usize.saturating_sub(rust_version.matches('.').count());
+        semver::Version::parse(&format!("{}{}", rust_version, ".0".repeat(missing))).ok()
+    }
     pub fn root(self, ws: &CargoWorkspace) -> &Path {
         ws.packages[self].manifest.parent().unwrap()
     }
+    pub fn manifest(self, ws: &CargoWorkspace) -> &Path {
+        ws.packages[self].manifest.as_path()
+    }
     pub fn edition(self, ws: &CargoWorkspace) -> Edition {
         ws.packages[self].edition
     }
     pub fn targets<'a>(self, ws: &'a CargoWorkspace) -> impl Iterator<Item = Target> + 'a {
         ws.packages[self].targets.iter().cloned()
     }
+    /// The target which other packages can depend on, if any.
+    pub fn lib_target(self, ws: &CargoWorkspace) -> Option<Target> {
+        self.targets(ws).find(|tgt| tgt.kind(ws).is_lib())
+    }
+    /// Whether the package is a workspace member. Local `[patch]`es inside
+    /// the workspace count as members.
     #[allow(unused)]
     pub fn is_member(self, ws: &CargoWorkspace) -> bool {
         ws.packages[self].is_member
     }
+    pub fn is_default_member(self, ws: &CargoWorkspace) -> bool {
+        ws.packages[self].is_default_member
+    }
+    pub fn is_local(self, ws: &CargoWorkspace) -> bool {
+        ws.packages[self].is_local
+    }
+    /// Whether the package should be indexed right away. Only false if
+    /// `CargoConfig::focus_member` is set and the package is unrelated to it.
+    pub fn is_eager(self, ws: &CargoWorkspace) -> bool {
+        ws.packages[self].is_eager
+    }
     pub fn dependencies<'a>(
         self,
         ws: &'a CargoWorkspace,
     ) -> impl Iterator<Item = &'a PackageDependency> + 'a {
         ws.packages[self].dependencies.iter()
     }
+    /// Features declared in the `[features]` section of the manifest, mapped
+    /// to the features and optional dependencies they enable.
+    pub fn features_map(self, ws: &CargoWorkspace) -> &FxHashMap<String, Vec<String>> {
+        &ws.packages[self].features
+    }
+    /// Features enabled by cargo's resolver, including the implicit features
+    /// of enabled optional dependencies. These are the features visible to
+    /// `#[cfg(feature = "...")]`.
+    pub fn enabled_features(self, ws: &CargoWorkspace) -> &[String] {
+        &ws.packages[self].enabled_features
+    }
+    /// Environment variables which cargo sets when compiling crates of this
+    /// package, as observed by `env!`.
+    pub fn env(self, ws: &CargoWorkspace) -> FxHashMap<String, String> {
+        let mut env = FxHashMap::default();
+        env.insert("CARGO_PKG_NAME".to_string(), self.name(ws).to_string());
+        env.insert("CARGO_PKG_VERSION".to_string(), self.version(ws).to_string());
+        let parts = self.version_parts(ws);
+        env.insert("CARGO_PKG_VERSION_MAJOR".to_string(), parts.major.to_string());
+        env.insert("CARGO_PKG_VERSION_MINOR".to_string(), parts.minor.to_string());
+        env.insert("CARGO_PKG_VERSION_PATCH".to_string(), parts.patch.to_string());
+        env.insert("CARGO_PKG_VERSION_PRE".to_string(), parts.pre.clone());
+        env.insert("CARGO_MANIFEST_DIR".to_string(), self.root(ws).display().to_string());
+        if let Some(out_dir) = self.out_dir(ws) {
+            env.insert("OUT_DIR".to_string(), out_dir.display().to_string());
+        }
+        env
+    }
+    /// The `OUT_DIR` of the package's build script, if the package was built
+    /// already. If there are several candidates (for example, because of
+    /// different feature sets), the most recently built one is picked.
+    pub fn out_dir(self, ws: &CargoWorkspace) -> Option<PathBuf> {
+        let mut build_dir = ws.target_directory.clone();
+        if let Some(target) = &ws.target {
+            build_dir.push(target);
+        }
+        build_dir.push("debug");
+        build_dir.push("build");
+        let prefix = format!("{}-", self.name(ws));
+        fs::read_dir(build_dir)
+            .ok()?
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| match entry.file_name().to_str() {
+                // Build directories are named `<package name>-<hash>`
+                Some(name) if name.starts_with(&prefix) => {
+                    name[prefix.len()..].chars().all(|c| c.is_ascii_hexdigit())
+                }
+                _ => false,
+            })
+            .filter_map(|entry| {
+                let out_dir = entry.path().join("out");
+                let modified = out_dir.metadata().and_then(|it| it.modified()).ok()?;
+                Some((modified, out_dir))
+            })
+            .max()
+            .map(|(_modified, out_dir)| out_dir)
+    }
 }
 
 impl Target {
@@ -114,64 +357,570 @@ impl Target {
     pub fn root(self, ws: &CargoWorkspace) -> &Path {
         ws.targets[self].root.as_path()
     }
+    /// The name under which the target is imported by other crates, which is
+    /// the target name with hyphens replaced by underscores.
+    pub fn crate_name(self, ws: &CargoWorkspace) -> String {
+        self.name(ws).replace('-', "_")
+    }
     pub fn kind(self, ws: &CargoWorkspace) -> TargetKind {
         ws.targets[self].kind
     }
+    /// The crate types cargo builds for the target, like `rlib` and
+    /// `cdylib`. Unlike `kind`, this lists all of them.
+    pub fn crate_types(self, ws: &CargoWorkspace) -> &[String] {
+        &ws.targets[self].crate_types
+    }
+    /// Features which must be enabled for cargo to build the target.
+    pub fn required_features(self, ws: &CargoWorkspace) -> &[String] {
+        &ws.targets[self].required_features
+    }
+    /// Whether all required features of the target are enabled.
+    pub fn is_enabled(self, ws: &CargoWorkspace) -> bool {
+        let enabled = self.package(ws).enabled_features(ws);
+        self.required_features(ws).iter().all(|it| enabled.contains(it))
+    }
 }
 
 impl CargoWorkspace {
-    pub fn from_cargo_metadata(cargo_toml: &Path) -> Result<CargoWorkspace> {
-        let mut meta = MetadataCommand::new();
-        meta.manifest_path(cargo_toml).features(CargoOpt::AllFeatures);
-        if let Some(parent) = cargo_toml.parent() {
-            meta.current_dir(parent);
+    pub fn from_cargo_metadata(cargo_toml: &Path, config: &CargoConfig) -> Result<CargoWorkspace> {
+        let _p = profile("CargoWorkspace::from_cargo_metadata");
+        let start = Instant::now();
+        let target = config
+            .target
+            .clone()
+            .or_else(|| cargo_toml.parent().and_then(cargo_config_build_target));
+        let json = run_cargo_metadata(cargo_toml, config, target.as_ref().map(String::as_str))?;
+        let lock_file =
+            json["workspace_root"].as_str().map(|root| Path::new(root).join("Cargo.lock"));
+        let mut ws = CargoWorkspace::from_json(json, cargo_toml, config)?;
+        // Capture stamps after running cargo, which might have updated the lock file.
+        let manifests = ws.packages().filter(|pkg| pkg.is_member(&ws)).map(|pkg| pkg.manifest(&ws));
+        let mut files: Vec<PathBuf> = manifests
+            .chain(Some(cargo_toml))
+            .chain(lock_file.as_ref().map(PathBuf::as_path))
+            .map(Path::to_path_buf)
+            .collect();
+        files.sort();
+        files.dedup();
+        ws.stamps = FileStamps::capture(files);
+        ws.target = target;
+        log::debug!(
+            "loaded cargo workspace {} in {:?}: {} packages, {} targets",
+            ws.workspace_root.display(),
+            start.elapsed(),
+            ws.packages.len(),
+            ws.targets.len(),
+        );
+        Ok(ws)
+    }
+
+    /// Builds the workspace from the JSON output of `cargo metadata`.
+    pub(crate) fn from_json(
+        json: serde_json::Value,
+        cargo_toml: &Path,
+        config: &CargoConfig,
+    ) -> Result<CargoWorkspace> {
+        // `cargo metadata` output is deterministic, so unchanged workspaces
+        // print the same.
+        let fingerprint = fingerprint(&(
+            json.to_string(),
+            cargo_toml,
+            config.single_package,
+            &config.focus_member,
+            config.members_only,
+        ));
+        let ext: MetadataExt = serde_json::from_value(json.clone())?;
+        let meta: Metadata = serde_json::from_value(json)?;
+        let mut ws = CargoWorkspace::from_metadata(meta, ext, cargo_toml, config);
+        if !ws.packages().any(|pkg| pkg.is_member(&ws)) {
+            let manifest = ws.workspace_manifest_path();
+            Err(ProjectModelError::EmptyWorkspace { manifest })?
         }
-        let meta = meta.exec().map_err(|e| format!("cargo metadata failed: {}", e))?;
+        ws.fingerprint = fingerprint;
+        Ok(ws)
+    }
+
+    fn from_metadata(
+        meta: Metadata,
+        ext: MetadataExt,
+        cargo_toml: &Path,
+        config: &CargoConfig,
+    ) -> CargoWorkspace {
         let mut pkg_by_id = FxHashMap::default();
+        let mut manifest_deps = FxHashMap::default();
         let mut packages = Arena::default();
         let mut targets = Arena::default();
 
         let ws_members = &meta.workspace_members;
+        let mut pkg_exts: FxHashMap<PackageId, PackageExt> =
+            ext.packages.into_iter().map(|pkg| (pkg.id.clone(), pkg)).collect();
+
+        // A virtual manifest has no package of its own, so all members are
+        // loaded instead.
+        let is_virtual = !meta.packages.iter().any(|pkg| pkg.manifest_path == cargo_toml);
+        if config.single_package && is_virtual {
+            log::info!("{} is a virtual manifest, loading all members", cargo_toml.display());
+        }
+        let single_package = config.single_package && !is_virtual;
+
+        // `[patch]` and `[replace]` redirect dependencies declared with a
+        // registry or git source to a local path, so the resolved package has
+        // no source while the dependency declaration still has one.
+        let redirected_deps: FxHashSet<String> = pkg_exts
+            .values()
+            .flat_map(|pkg| pkg.dependencies.iter())
+            .filter(|dep| dep.source.is_some())
+            .map(|dep| dep.name.clone())
+            .collect();
 
         for meta_pkg in meta.packages {
-            let is_member = ws_members.contains(&meta_pkg.id);
+            if single_package && meta_pkg.manifest_path != cargo_toml {
+                continue;
+            }
+            let manifest = match checked_path(&meta_pkg.manifest_path) {
+                Ok(it) if it.parent().is_some() => it,
+                _ => {
+                    log::warn!(
+                        "skipping package {}: invalid manifest path {}",
+                        meta_pkg.name,
+                        meta_pkg.manifest_path.display()
+                    );
+                    continue;
+                }
+            };
+            let (rust_version, dep_exts) = match pkg_exts.remove(&meta_pkg.id) {
+                Some(it) => (it.rust_version, it.dependencies),
+                None => (None, Vec::new()),
+            };
+            let is_listed_member = ws_members.contains(&meta_pkg.id);
+            // Dependencies redirected to a local path with `[patch]` or
+            // `[replace]` are not listed as members, but if they live in the
+            // workspace, the user edits them like members.
+            let is_local_patch = meta_pkg.source.is_none()
+                && redirected_deps.contains(&meta_pkg.name)
+                && manifest.starts_with(&meta.workspace_root)
+                && !manifest.starts_with(&meta.target_directory);
+            let is_member = is_listed_member || is_local_patch;
+            if config.members_only && !is_member {
+                continue;
+            }
+            let is_default_member = match &ext.workspace_default_members {
+                Some(default_members) => default_members.contains(&meta_pkg.id),
+                None => is_listed_member,
+            };
+            let version = &meta_pkg.version;
+            let pre: Vec<String> = version.pre.iter().map(|it| it.to_string()).collect();
+            let version_parts = VersionParts {
+                major: version.major,
+                minor: version.minor,
+                patch: version.patch,
+                pre: pre.join("."),
+            };
             let pkg = packages.alloc(PackageData {
                 name: meta_pkg.name,
-                manifest: meta_pkg.manifest_path.clone(),
+                version: version.to_string(),
+                version_parts,
+                manifest,
                 targets: Vec::new(),
                 is_member,
+                is_default_member,
+                is_local: meta_pkg.source.is_none(),
+                is_eager: true,
                 edition: Edition::from_string(&meta_pkg.edition),
                 dependencies: Vec::new(),
+                features: meta_pkg.features.into_iter().collect(),
+                enabled_features: Vec::new(),
+                rust_version,
             });
             let pkg_data = &mut packages[pkg];
             pkg_by_id.insert(meta_pkg.id.clone(), pkg);
+            // `resolve` only knows the crate names of dependencies, so
+            // remember what the manifest says about each of them. A dependency
+            // can be listed in several sections, in which case the normal one
+            // takes precedence. `dep_exts` lists the same dependencies, in the
+            // same order.
+            let publics = dep_exts.iter().map(|it| it.public).chain(iter::repeat(None));
+            for (dep, public) in meta_pkg.dependencies.iter().zip(publics) {
+                let feature = dep.rename.clone().unwrap_or_else(|| dep.name.clone());
+                let crate_name = feature.replace('-', "_");
+                let info = ManifestDep {
+                    kind: DepKind::new(&dep.kind),
+                    optional: dep.optional,
+                    feature,
+                    public,
+                };
+                let entry = manifest_deps.entry((pkg, crate_name)).or_insert_with(|| info.clone());
+                if info.kind == DepKind::Normal {
+                    *entry = info;
+                }
+            }
             for meta_tgt in meta_pkg.targets {
+                let root = match checked_path(&meta_tgt.src_path) {
+                    Ok(it) => it,
+                    Err(e) => {
+                        log::warn!("skipping target {} of {}: {}", meta_tgt.name, pkg_data.name, e);
+                        continue;
+                    }
+                };
                 let tgt = targets.alloc(TargetData {
                     pkg,
                     name: meta_tgt.name,
-                    root: meta_tgt.src_path.clone(),
+                    root,
                     kind: TargetKind::new(meta_tgt.kind.as_slice()),
+                    crate_types: meta_tgt.crate_types,
+                    required_features: meta_tgt.required_features,
                 });
                 pkg_data.targets.push(tgt);
             }
         }
-        let resolve = meta.resolve.expect("metadata executed with deps");
-        for node in resolve.nodes {
-            let source = pkg_by_id[&node.id];
+        let primary_package = match meta.resolve.as_ref() {
+            Some(resolve) => resolve.root.as_ref().and_then(|id| pkg_by_id.get(id)).copied(),
+            // Only the package of `cargo_toml` is loaded in this case.
+            None if single_package => pkg_by_id.values().next().copied(),
+            None => None,
+        };
+        // `resolve` is missing if metadata was executed with `--no-deps`
+        if meta.resolve.is_none() {
+            // Without a resolve graph, dependencies can't be matched to
+            // packages, so the crate graph only consists of the members.
+            log::info!("cargo metadata has no resolve graph, dependencies are skipped");
+            // We always pass `--all-features`.
+            for pkg in pkg_by_id.values() {
+                let pkg_data = &mut packages[*pkg];
+                let mut enabled_features: Vec<String> = pkg_data.features.keys().cloned().collect();
+                enabled_features.sort();
+                pkg_data.enabled_features = enabled_features;
+            }
+        }
+        for node in meta.resolve.into_iter().flat_map(|resolve| resolve.nodes) {
+            let source = match pkg_by_id.get(&node.id) {
+                Some(&it) => it,
+                None => continue,
+            };
+            let mut enabled_features = node.features;
             for dep_node in node.deps {
-                let dep = PackageDependency { name: dep_node.name, pkg: pkg_by_id[&dep_node.pkg] };
-                packages[source].dependencies.push(dep);
+                if let Some(&pkg) = pkg_by_id.get(&dep_node.pkg) {
+                    let info = manifest_deps.get(&(source, dep_node.name.clone()));
+                    let kind = info.map_or(DepKind::Normal, |it| it.kind);
+                    let optional = info.map_or(false, |it| it.optional);
+                    let public = info.and_then(|it| it.public);
+                    if let Some(info) = info.filter(|it| it.optional) {
+                        enabled_features.push(info.feature.clone());
+                    }
+                    let dep =
+                        PackageDependency { name: dep_node.name, pkg, kind, optional, public };
+                    packages[source].dependencies.push(dep);
+                }
             }
+            enabled_features.sort();
+            enabled_features.dedup();
+            packages[source].enabled_features = enabled_features;
         }
 
-        Ok(CargoWorkspace { packages, targets, workspace_root: meta.workspace_root })
+        let workspace_root = match cargo_toml.parent() {
+            Some(package_root) if single_package => package_root.to_path_buf(),
+            _ => meta.workspace_root,
+        };
+        let mut ws = CargoWorkspace {
+            packages,
+            targets,
+            workspace_root,
+            manifest_path: cargo_toml.to_path_buf(),
+            target_directory: meta.target_directory,
+            primary_package,
+            stamps: FileStamps::default(),
+            target: None,
+            fingerprint: 0,
+        };
+        if let Some(focus_member) = &config.focus_member {
+            ws.focus(focus_member);
+        }
+        ws
+    }
+
+    /// Marks everything except the member `name` and the local packages it
+    /// depends on as not eager.
+    fn focus(&mut self, name: &str) {
+        let focused =
+            match self.packages().find(|pkg| pkg.is_member(self) && pkg.name(self) == name) {
+                Some(it) => it,
+                None => {
+                    log::warn!("can't focus on {}: no such workspace member", name);
+                    return;
+                }
+            };
+        let mut eager: FxHashSet<Package> =
+            self.transitive_deps(focused).into_iter().filter(|pkg| pkg.is_local(self)).collect();
+        eager.insert(focused);
+        for (pkg, data) in self.packages.iter_mut() {
+            data.is_eager = eager.contains(&pkg);
+        }
     }
 
     pub fn packages<'a>(&'a self) -> impl Iterator<Item = Package> + ExactSizeIterator + 'a {
         self.packages.iter().map(|(id, _pkg)| id)
     }
 
+    /// Members which cargo builds when no package is specified, which are
+    /// likely what the user works on. Defaults to all members for versions of
+    /// cargo which don't report `default-members`.
+    pub fn default_members<'a>(&'a self) -> impl Iterator<Item = Package> + 'a {
+        self.packages().filter(move |pkg| pkg.is_default_member(self))
+    }
+
+    /// Returns all packages called `name`. There can be several of them, if
+    /// the dependency graph contains different versions of a crate.
+    pub fn packages_by_name(&self, name: &str) -> Vec<Package> {
+        self.packages().filter(|pkg| pkg.name(self) == name).collect()
+    }
+
+    pub fn package_by_name_version(&self, name: &str, version: &str) -> Option<Package> {
+        self.packages().find(|pkg| pkg.name(self) == name && pkg.version(self) == version)
+    }
+
+    /// The `Cargo.toml` the workspace was loaded from, which can be the
+    /// manifest of a member.
+    pub fn manifest_path(&self) -> &Path {
+        &self.manifest_path
+    }
+
+    /// The `Cargo.toml` in the workspace root.
+    pub fn workspace_manifest_path(&self) -> PathBuf {
+        self.workspace_root.join("Cargo.toml")
+    }
+
+    /// Whether the root manifest of the workspace only has a `[workspace]`
+    /// section, without a `[package]`.
+    pub fn is_virtual(&self) -> bool {
+        let root_manifest = self.workspace_manifest_path();
+        self.packages().all(|pkg| pkg.manifest(self) != root_manifest)
+    }
+
+    /// The package of the manifest `cargo metadata` was invoked for, which is
+    /// `None` for virtual workspaces.
+    pub fn primary_package(&self) -> Option<Package> {
+        self.primary_package
+    }
+
+    /// Returns the packages `pkg` directly depends on, together with the
+    /// names under which they are imported.
+    pub fn deps_of(&self, pkg: Package) -> Vec<(String, DepKind, Package)> {
+        pkg.dependencies(self).map(|dep| (dep.name.clone(), dep.kind, dep.pkg)).collect()
+    }
+
+    /// Returns all packages `pkg` depends on, directly or indirectly, in
+    /// breadth-first order. `pkg` itself is not included.
+    pub fn transitive_deps(&self, pkg: Package) -> Vec<Package> {
+        let mut res = Vec::new();
+        let mut visited = FxHashSet::default();
+        visited.insert(pkg);
+        let mut queue: VecDeque<Package> = iter::once(pkg).collect();
+        while let Some(pkg) = queue.pop_front() {
+            for dep in pkg.dependencies(self) {
+                if visited.insert(dep.pkg) {
+                    res.push(dep.pkg);
+                    queue.push_back(dep.pkg);
+                }
+            }
+        }
+        res
+    }
+
+    /// Returns the package which `crate_name`, as written in a path in a crate
+    /// of `from`, refers to. Dependencies are imported under their renamed
+    /// and normalized names, and the lib target of `from` itself is visible
+    /// to its other targets.
+    pub fn package_for_crate_name(&self, from: Package, crate_name: &str) -> Option<Package> {
+        let crate_name = crate_name.replace('-', "_");
+        if from.lib_target(self).map_or(false, |lib| lib.crate_name(self) == crate_name) {
+            return Some(from);
+        }
+        from.dependencies(self).find(|dep| dep.name == crate_name).map(|dep| dep.pkg)
+    }
+
+    /// Returns the innermost package whose root directory contains `path`.
+    pub fn package_for_path(&self, path: &Path) -> Option<Package> {
+        self.packages()
+            .filter(|pkg| path.starts_with(pkg.root(self)))
+            .max_by_key(|pkg| pkg.root(self).components().count())
+    }
+
     pub fn target_by_root(&self, root: &Path) -> Option<Target> {
         self.packages().filter_map(|pkg| pkg.targets(self).find(|it| it.root(self) == root)).next()
     }
+
+    /// The directory where cargo puts build artifacts. It is `target/` in the
+    /// workspace root, unless overridden with `CARGO_TARGET_DIR` or
+    /// `build.target-dir`.
+    pub fn target_directory(&self) -> &Path {
+        &self.target_directory
+    }
+
+    /// A hash of the `cargo metadata` output the workspace was built from.
+    /// Equal fingerprints mean equal workspaces.
+    pub fn fingerprint(&self) -> u64 {
+        self.fingerprint
+    }
+
+    /// The target triple the workspace was loaded for, or `None` for the host.
+    pub fn target_triple(&self) -> Option<&str> {
+        self.target.as_ref().map(String::as_str)
+    }
+}
+
+/// `Edition` doesn't implement serde traits, so it's stored as a string.
+mod edition {
+    use super::*;
+
+    pub(super) fn serialize<S: Serializer>(
+        edition: &Edition,
+        serializer: S,
+    ) -> std::result::Result<S::Ok, S::Error> {
+        let edition = match edition {
+            Edition::Edition2015 => "2015",
+            Edition::Edition2018 => "2018",
+        };
+        edition.serialize(serializer)
+    }
+
+    pub(super) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> std::result::Result<Edition, D::Error> {
+        String::deserialize(deserializer).map(|it| Edition::from_string(&it))
+    }
+}
+
+/// Fields of `cargo metadata` output which are too new for `cargo_metadata`.
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct MetadataExt {
+    workspace_default_members: Option<Vec<PackageId>>,
+    packages: Vec<PackageExt>,
+}
+
+#[derive(Deserialize)]
+struct PackageExt {
+    id: PackageId,
+    #[serde(default)]
+    rust_version: Option<String>,
+    #[serde(default)]
+    dependencies: Vec<DependencyExt>,
+}
+
+#[derive(Deserialize)]
+struct DependencyExt {
+    #[serde(default)]
+    name: String,
+    #[serde(default)]
+    source: Option<String>,
+    #[serde(default)]
+    public: Option<bool>,
+}
+
+fn run_cargo_metadata(
+    cargo_toml: &Path,
+    config: &CargoConfig,
+    target: Option<&str>,
+) -> Result<serde_json::Value> {
+    let cargo = env::var_os("CARGO").unwrap_or_else(|| "cargo".into());
+    let mut cmd = Command::new(cargo);
+    cmd.args(cargo_metadata_args(config, target)).arg("--manifest-path").arg(cargo_toml);
+    if let Some(parent) = cargo_toml.parent() {
+        cmd.current_dir(parent);
+    }
+    let output = cmd.output().map_err(|e| format!("failed to run cargo metadata: {}", e))?;
+    if !output.status.success() {
+        Err(format!("cargo metadata failed: {}", String::from_utf8_lossy(&output.stderr)))?
+    }
+    Ok(serde_json::from_slice(&output.stdout)?)
+}
+
+/// Arguments of the `cargo metadata` invocation, except for the manifest path.
+pub(crate) fn cargo_metadata_args(config: &CargoConfig, target: Option<&str>) -> Vec<String> {
+    let mut args: Vec<String> = vec!["metadata", "--format-version", "1", "--all-features"]
+        .into_iter()
+        .map(String::from)
+        .collect();
+    if config.single_package {
+        args.push("--no-deps".to_string());
+    }
+    if let Some(target) = target {
+        args.push("--filter-platform".to_string());
+        args.push(target.to_string());
+    }
+    for config_override in config.config_overrides.iter() {
+        // Overrides must not smuggle in flags, which might change the format
+        // of the output.
+        if config_override.starts_with('-') || !config_override.contains('=') {
+            log::warn!("ignoring invalid cargo config override {:?}", config_override);
+            continue;
+        }
+        args.push("--config".to_string());
+        args.push(config_override.clone());
+    }
+    for flag in config.unstable_flags.iter() {
+        if flag.starts_with('-') {
+            log::warn!("ignoring invalid unstable cargo flag {:?}", flag);
+            continue;
+        }
+        args.push("-Z".to_string());
+        args.push(flag.clone());
+    }
+    args
+}
+
+/// Finds `build.target` in the nearest cargo config file, looking in `dir` and
+/// its ancestors, like cargo itself does.
+pub(crate) fn cargo_config_build_target(dir: &Path) -> Option<String> {
+    for dir in dir.ancestors() {
+        // `config` is the legacy name of `config.toml`
+        for file_name in &["config.toml", "config"] {
+            let text = match fs::read_to_string(dir.join(".cargo").join(file_name)) {
+                Ok(it) => it,
+                Err(_) => continue,
+            };
+            if let Some(target) = parse_build_target(&text) {
+                return Some(target);
+            }
+        }
+    }
+    None
+}
+
+/// A poor man's TOML parser, which understands both `[build] target = ".."`
+/// and `build.target = ".."`.
+fn parse_build_target(text: &str) -> Option<String> {
+    let mut table = "";
+    for line in text.lines() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if line.starts_with('[') {
+            table = line.trim_matches(|c| c == '[' || c == ']').trim();
+            continue;
+        }
+        let mut parts = line.splitn(2, '=');
+        let key = parts.next()?.trim();
+        let value = match parts.next() {
+            Some(it) => it.trim(),
+            None => continue,
+        };
+        let is_target = match table {
+            "build" => key == "target",
+            "" => key == "build.target",
+            _ => false,
+        };
+        if is_target && value.len() >= 2 && value.starts_with('"') && value.ends_with('"') {
+            return Some(value[1..value.len() - 1].to_string());
+        }
+    }
+    None
+}
+
+/// `cargo metadata` output is JSON, so paths which are not valid UTF-8 can't
+/// be represented faithfully and come out mangled. Such paths would never
+/// match the files we load, so we'd better notice them early.
+pub(crate) fn checked_path(path: &Path) -> Result<PathBuf> {
+    match path.to_str() {
+        Some(s) if !s.contains(std::char::REPLACEMENT_CHARACTER) => Ok(path.to_path_buf()),
+        _ => Err(format!("path is not valid UTF-8: {}", path.display()))?,
+    }
 }
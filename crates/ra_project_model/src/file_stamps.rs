@@ -0,0 +1,40 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use serde::{Deserialize, Serialize};
+
+/// Remembers the modification time and size of the files a workspace was
+/// loaded from, so that we can cheaply tell whether it needs reloading.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub(crate) struct FileStamps {
+    files: Vec<(PathBuf, Option<Stamp>)>,
+}
+
+type Stamp = (SystemTime, u64);
+
+impl FileStamps {
+    pub(crate) fn capture(paths: impl IntoIterator<Item = PathBuf>) -> FileStamps {
+        let files = paths
+            .into_iter()
+            .map(|path| {
+                let stamp = stamp(&path);
+                (path, stamp)
+            })
+            .collect();
+        FileStamps { files }
+    }
+
+    /// Returns true if any of the files was modified, created or deleted
+    /// since the stamps were captured.
+    pub(crate) fn is_stale(&self) -> bool {
+        self.files.iter().any(|(path, old)| stamp(path) != *old)
+    }
+}
+
+fn stamp(path: &Path) -> Option<Stamp> {
+    let meta = fs::metadata(path).ok()?;
+    Some((meta.modified().ok()?, meta.len()))
+}
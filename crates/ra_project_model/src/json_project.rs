@@ -1,25 +1,82 @@
-use std::path::PathBuf;
+use std::{
+    error::Error,
+    fmt,
+    path::{Path, PathBuf},
+};
 
-use serde::Deserialize;
+use rustc_hash::FxHashMap;
+use serde::{Deserialize, Serialize};
+
+use crate::file_stamps::FileStamps;
 
 /// A root points to the directory which contains Rust crates. rust-analyzer watches all files in
 /// all roots. Roots might be nested.
-#[derive(Clone, Debug, Deserialize)]
-#[serde(transparent)]
+///
+/// A root is either a path or `{ "path": ..., "is_workspace_member": false }`. Roots of external
+/// crates are not members, and their `examples`, `tests` and `benches` are not indexed.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(from = "RootRepr", into = "RootRepr")]
 pub struct Root {
     pub(crate) path: PathBuf,
+    pub(crate) is_workspace_member: bool,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(untagged)]
+enum RootRepr {
+    Path(PathBuf),
+    Object {
+        path: PathBuf,
+        #[serde(default = "default_is_workspace_member")]
+        is_workspace_member: bool,
+    },
+}
+
+fn default_is_workspace_member() -> bool {
+    true
+}
+
+impl From<RootRepr> for Root {
+    fn from(repr: RootRepr) -> Root {
+        match repr {
+            RootRepr::Path(path) => Root { path, is_workspace_member: true },
+            RootRepr::Object { path, is_workspace_member } => Root { path, is_workspace_member },
+        }
+    }
+}
+
+impl From<Root> for RootRepr {
+    fn from(root: Root) -> RootRepr {
+        match root {
+            Root { path, is_workspace_member: true } => RootRepr::Path(path),
+            Root { path, is_workspace_member } => RootRepr::Object { path, is_workspace_member },
+        }
+    }
 }
 
 /// A crate points to the root module of a crate and lists the dependencies of the crate. This is
 /// useful in creating the crate graph.
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Crate {
     pub(crate) root_module: PathBuf,
     pub(crate) edition: Edition,
     pub(crate) deps: Vec<Dep>,
+    /// Environment variables visible to `env!` when compiling this crate.
+    #[serde(default)]
+    pub(crate) env: FxHashMap<String, String>,
+    /// A label for the UI. Defaults to the file stem of the root module.
+    #[serde(default)]
+    pub(crate) display_name: Option<String>,
+    /// Enabled cfg options, like `test` or `feature="foo"`.
+    #[serde(default)]
+    pub(crate) cfg: Vec<String>,
+    /// Names of entries of `JsonProject::cfg_groups` whose options are
+    /// enabled in addition to `cfg`.
+    #[serde(default)]
+    pub(crate) cfg_groups: Vec<String>,
 }
 
-#[derive(Clone, Copy, Debug, Deserialize)]
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
 #[serde(rename = "edition")]
 pub enum Edition {
     #[serde(rename = "2015")]
@@ -28,13 +85,22 @@ pub enum Edition {
     Edition2018,
 }
 
+impl From<Edition> for ra_db::Edition {
+    fn from(edition: Edition) -> ra_db::Edition {
+        match edition {
+            Edition::Edition2015 => ra_db::Edition::Edition2015,
+            Edition::Edition2018 => ra_db::Edition::Edition2018,
+        }
+    }
+}
+
 /// Identifies a crate by position in the crates array.
-#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, Ord, PartialEq, PartialOrd)]
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, Eq, Hash, Ord, PartialEq, PartialOrd)]
 #[serde(transparent)]
 pub struct CrateId(pub usize);
 
 /// A dependency of a crate, identified by its id in the crates array and name.
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Dep {
     #[serde(rename = "crate")]
     pub(crate) krate: CrateId,
@@ -42,8 +108,129 @@ pub struct Dep {
 }
 
 /// Roots and crates that compose this Rust project.
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct JsonProject {
+    /// If empty, roots are inferred from the directories of crate root modules.
+    #[serde(default)]
     pub(crate) roots: Vec<Root>,
     pub(crate) crates: Vec<Crate>,
+    /// Named sets of cfg options shared by several crates.
+    #[serde(default)]
+    pub(crate) cfg_groups: FxHashMap<String, Vec<String>>,
+    #[serde(skip)]
+    pub(crate) stamps: FileStamps,
+    /// The `rust-project.json` the project was loaded from.
+    #[serde(skip)]
+    pub(crate) path: Option<PathBuf>,
+}
+
+/// An inconsistency in a `rust-project.json` or between it and the file
+/// system, which usually means that the file is stale and should be
+/// regenerated.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum JsonProjectError {
+    /// The root module of a crate doesn't exist, so the crate can't be loaded.
+    MissingRootModule { crate_id: CrateId, path: PathBuf },
+    /// A crate refers to a cfg group which isn't declared.
+    UnknownCfgGroup { crate_id: CrateId, group: String },
+}
+
+impl fmt::Display for JsonProjectError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            JsonProjectError::MissingRootModule { crate_id, path } => {
+                write!(f, "root module of crate {} does not exist: {}", crate_id.0, path.display())
+            }
+            JsonProjectError::UnknownCfgGroup { crate_id, group } => {
+                write!(f, "crate {} refers to unknown cfg group `{}`", crate_id.0, group)
+            }
+        }
+    }
+}
+
+impl Error for JsonProjectError {}
+
+impl JsonProject {
+    /// Checks the project for consistency and against the file system.
+    pub fn validate(&self) -> Vec<JsonProjectError> {
+        let mut res = Vec::new();
+        for (idx, krate) in self.crates.iter().enumerate() {
+            let crate_id = CrateId(idx);
+            if !krate.root_module.is_file() {
+                let path = krate.root_module.clone();
+                res.push(JsonProjectError::MissingRootModule { crate_id, path });
+            }
+            for group in &krate.cfg_groups {
+                if !self.cfg_groups.contains_key(group) {
+                    let group = group.clone();
+                    res.push(JsonProjectError::UnknownCfgGroup { crate_id, group });
+                }
+            }
+        }
+        res
+    }
+
+    /// Returns the cfg options of `krate`, with its cfg groups expanded.
+    /// Returns `None` if the crate doesn't specify any, in which case all
+    /// options are assumed to be enabled.
+    pub(crate) fn cfg(&self, krate: CrateId) -> Option<Vec<&str>> {
+        let krate = &self.crates[krate.0];
+        if krate.cfg.is_empty() && krate.cfg_groups.is_empty() {
+            return None;
+        }
+        let groups = krate.cfg_groups.iter().filter_map(|group| self.cfg_groups.get(group));
+        let mut res: Vec<&str> =
+            krate.cfg.iter().chain(groups.flatten()).map(String::as_str).collect();
+        res.sort();
+        res.dedup();
+        Some(res)
+    }
+
+    pub fn to_json_string(&self) -> serde_json::Result<String> {
+        serde_json::to_string_pretty(self)
+    }
+
+    /// Returns explicitly specified roots or, if there are none, the
+    /// deduplicated directories containing crate root modules.
+    pub(crate) fn root_paths(&self) -> Vec<&Path> {
+        self.root_entries().into_iter().map(|(path, _is_member)| path).collect()
+    }
+
+    /// Like `root_paths`, but also tells whether each root belongs to the
+    /// workspace. Inferred roots always do.
+    pub(crate) fn root_entries(&self) -> Vec<(&Path, bool)> {
+        if !self.roots.is_empty() {
+            return self
+                .roots
+                .iter()
+                .map(|root| (root.path.as_path(), root.is_workspace_member))
+                .collect();
+        }
+        let mut res: Vec<&Path> =
+            self.crates.iter().filter_map(|krate| krate.root_module.parent()).collect();
+        res.sort();
+        res.dedup();
+        res.into_iter().map(|path| (path, true)).collect()
+    }
+
+    /// Returns the crate whose root module directory most closely contains
+    /// `path`.
+    pub(crate) fn crate_for_path(&self, path: &Path) -> Option<(CrateId, &Crate)> {
+        self.crates
+            .iter()
+            .enumerate()
+            .filter_map(|(id, krate)| Some((id, krate, krate.root_module.parent()?)))
+            .filter(|(_id, _krate, dir)| path.starts_with(dir))
+            .max_by_key(|(_id, _krate, dir)| dir.components().count())
+            .map(|(id, krate, _dir)| (CrateId(id), krate))
+    }
+
+    /// Returns the name under which other crates depend on `krate`, if any.
+    pub(crate) fn crate_name(&self, krate: CrateId) -> Option<&str> {
+        self.crates
+            .iter()
+            .flat_map(|it| it.deps.iter())
+            .find(|dep| dep.krate == krate)
+            .map(|dep| dep.name.as_str())
+    }
 }
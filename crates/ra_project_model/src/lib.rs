@@ -1,246 +1,1008 @@
 mod cargo_workspace;
+mod file_stamps;
 mod json_project;
 mod sysroot;
+#[cfg(test)]
+mod tests;
 
 use std::{
+    collections::hash_map::DefaultHasher,
     error::Error,
-    fs::File,
-    io::BufReader,
+    fmt,
+    fs::{self, File},
+    hash::{Hash, Hasher},
+    io::{BufReader, BufWriter},
     path::{Path, PathBuf},
+    time::Instant,
 };
 
-use ra_db::{CrateGraph, Edition, FileId};
-use rustc_hash::FxHashMap;
+use ra_db::{CfgOptions, CrateGraph, CrateId, Edition, Env, FileId};
+use ra_prof::profile;
+use rustc_hash::{FxHashMap, FxHashSet};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use serde_json::from_reader;
 
+use crate::file_stamps::FileStamps;
+
 pub use crate::{
-    cargo_workspace::{CargoWorkspace, Package, Target, TargetKind},
-    json_project::JsonProject,
-    sysroot::Sysroot,
+    cargo_workspace::{
+        CargoConfig, CargoWorkspace, DepKind, Package, Target, TargetKind, VersionParts,
+    },
+    json_project::{JsonProject, JsonProjectError},
+    sysroot::{Sysroot, SysrootLayout},
 };
 
 // FIXME use proper error enum
 pub type Result<T> = ::std::result::Result<T, Box<dyn Error + Send + Sync>>;
 
-#[derive(Debug, Clone)]
+/// Errors which callers might want to recover from, found by downcasting the
+/// boxed error. Other errors are only meant to be reported.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProjectModelError {
+    /// The standard library is not installed for the target triple.
+    TargetStdMissing { triple: String },
+    /// The workspace has no members, usually because the globs in `members`
+    /// of a virtual manifest don't match any directory yet.
+    EmptyWorkspace { manifest: PathBuf },
+}
+
+impl fmt::Display for ProjectModelError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ProjectModelError::TargetStdMissing { triple } => write!(
+                f,
+                "the standard library is not installed for target `{}`\n\
+                 try running `rustup target add {}`",
+                triple, triple
+            ),
+            ProjectModelError::EmptyWorkspace { manifest } => {
+                write!(f, "cargo workspace {} has no members", manifest.display())
+            }
+        }
+    }
+}
+
+impl Error for ProjectModelError {}
+
+/// A problem found while building the crate graph. The graph is still usable,
+/// but some items won't resolve, so the problem should be shown to the user.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CrateGraphProblem {
+    /// The root file of a crate failed to load, so the crate is missing.
+    CrateRootNotLoaded { root: PathBuf },
+    /// `dependency` of `package` has no library target, so it can't be
+    /// imported. This is usually a binary-only package.
+    DependencyWithoutLib { package: String, dependency: String },
+    /// The library target of `dependency` of `package` failed to load.
+    DependencyNotLoaded { package: String, dependency: String },
+}
+
+impl fmt::Display for CrateGraphProblem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CrateGraphProblem::CrateRootNotLoaded { root } => {
+                write!(f, "crate root {} failed to load", root.display())
+            }
+            CrateGraphProblem::DependencyWithoutLib { package, dependency } => write!(
+                f,
+                "dependency `{}` of {} can't be imported: it has no library target",
+                dependency, package
+            ),
+            CrateGraphProblem::DependencyNotLoaded { package, dependency } => write!(
+                f,
+                "dependency `{}` of {} can't be imported: its library target failed to load",
+                dependency, package
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum ProjectWorkspace {
     /// Project workspace was discovered by running `cargo metadata` and `rustc --print sysroot`.
     Cargo { cargo: CargoWorkspace, sysroot: Sysroot },
     /// Project workspace was manually specified using a `rust-project.json` file.
-    Json { project: JsonProject },
+    Json {
+        #[serde(with = "json_project_with_stamps")]
+        project: JsonProject,
+    },
+    /// A cargo workspace with a `rust-project.json` overlay, which describes
+    /// crates cargo doesn't know about (for example, generated ones).
+    ///
+    /// Overlay crates can depend on `std` and the library crates of workspace
+    /// members by their crate names. Explicit dependencies of overlay crates
+    /// take precedence over such implicit ones.
+    Merged {
+        cargo: CargoWorkspace,
+        sysroot: Sysroot,
+        #[serde(with = "json_project_with_stamps")]
+        overlay: JsonProject,
+    },
+    /// A single `.rs` file outside of any project, like a scratch file. It is
+    /// the root of a 2018 edition crate which only depends on `std`.
+    DetachedFile { file: PathBuf, sysroot: Sysroot },
 }
 
 /// `PackageRoot` describes a package root folder.
 /// Which may be an external dependency, or a member of
 /// the current workspace.
-#[derive(Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct PackageRoot {
     /// Path to the root folder
     path: PathBuf,
-    /// Is a member of the current workspace
-    is_member: bool,
+    /// Where the root comes from
+    origin: RootOrigin,
+    /// Subdirectories which should not be indexed
+    exclude: Vec<PathBuf>,
+    /// Should be indexed right away, rather than in the background
+    is_eager: bool,
+}
+
+/// Where the sources of a `PackageRoot` come from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RootOrigin {
+    /// A member of the current workspace
+    Member,
+    /// A dependency of the workspace, like a crates.io or git package
+    External,
+    /// A crate of the standard library
+    Sysroot,
 }
 
 impl PackageRoot {
     pub fn new(path: PathBuf, is_member: bool) -> PackageRoot {
-        PackageRoot { path, is_member }
+        let origin = if is_member { RootOrigin::Member } else { RootOrigin::External };
+        PackageRoot::with_origin(path, origin)
+    }
+
+    pub fn with_origin(path: PathBuf, origin: RootOrigin) -> PackageRoot {
+        PackageRoot { path, origin, exclude: Vec::new(), is_eager: true }
     }
 
     pub fn path(&self) -> &PathBuf {
         &self.path
     }
 
+    pub fn origin(&self) -> RootOrigin {
+        self.origin
+    }
+
     pub fn is_member(&self) -> bool {
-        self.is_member
+        self.origin == RootOrigin::Member
+    }
+
+    /// Whether the root belongs to the part of the workspace the user focuses
+    /// on. Other roots can be indexed lazily.
+    pub fn is_eager(&self) -> bool {
+        self.is_eager
+    }
+
+    /// Directories inside `path` which contain build artifacts rather than
+    /// sources, like a relocated cargo target directory.
+    pub fn exclude(&self) -> &[PathBuf] {
+        &self.exclude
+    }
+    /// Returns the path and the membership flag, dropping exclusions.
+    pub fn into_parts(self) -> (PathBuf, bool) {
+        let is_member = self.is_member();
+        (self.path, is_member)
+    }
+}
+
+/// The crate which owns a file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CrateRootInfo {
+    /// Name of the crate. Crates of a `rust-project.json` only have names if
+    /// other crates depend on them.
+    pub name: Option<String>,
+    pub edition: Edition,
+    /// Path to the root module of the crate.
+    pub root: PathBuf,
+}
+
+/// Location of a file which can be used to discover a `ProjectWorkspace`.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ProjectManifestPath {
+    /// A `rust-project.json` file.
+    ProjectJson(PathBuf),
+    /// A `Cargo.toml` with a `[workspace]` section.
+    CargoWorkspace(PathBuf),
+    /// Any other `Cargo.toml`.
+    CargoPackage(PathBuf),
+}
+
+impl ProjectManifestPath {
+    pub fn path(&self) -> &Path {
+        match self {
+            ProjectManifestPath::ProjectJson(it)
+            | ProjectManifestPath::CargoWorkspace(it)
+            | ProjectManifestPath::CargoPackage(it) => it,
+        }
     }
 }
 
 impl ProjectWorkspace {
-    pub fn discover(path: &Path) -> Result<ProjectWorkspace> {
+    /// Lists all manifests under `path`, without loading them, so that the
+    /// user can pick which projects to open. Hidden and `target` directories
+    /// are skipped.
+    pub fn find_all_manifests(path: &Path) -> Vec<ProjectManifestPath> {
+        let mut res = Vec::new();
+        collect_manifests(path, &mut res);
+        res.sort();
+        res
+    }
+
+    pub fn discover(path: &Path, cargo_config: &CargoConfig) -> Result<ProjectWorkspace> {
+        // Walk up from the real location of `path`: the parents of a symlink
+        // pointing into the workspace lie outside of it.
+        let path = &path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
         match find_rust_project_json(path) {
             Some(json_path) => {
-                let file = File::open(json_path)?;
+                let file = File::open(&json_path)?;
                 let reader = BufReader::new(file);
-                Ok(ProjectWorkspace::Json { project: from_reader(reader)? })
+                let mut project: JsonProject = from_reader(reader)?;
+                // Crates with a missing root module are skipped when building
+                // the crate graph, but unknown cfg groups would silently
+                // change which code is analyzed.
+                for error in project.validate() {
+                    match error {
+                        JsonProjectError::MissingRootModule { .. } => {
+                            log::warn!("{}: {}", json_path.display(), error)
+                        }
+                        JsonProjectError::UnknownCfgGroup { .. } => Err(error)?,
+                    }
+                }
+                project.stamps = FileStamps::capture(vec![json_path.clone()]);
+                project.path = Some(json_path);
+                Ok(ProjectWorkspace::Json { project })
             }
             None => {
-                let cargo_toml = find_cargo_toml(path)?;
-                Ok(ProjectWorkspace::Cargo {
-                    cargo: CargoWorkspace::from_cargo_metadata(&cargo_toml)?,
-                    sysroot: Sysroot::discover(&cargo_toml)?,
-                })
+                let cargo_toml = match find_cargo_toml(path) {
+                    Ok(it) => it,
+                    Err(_) if path.is_file() && path.extension() == Some("rs".as_ref()) => {
+                        let file = path.to_path_buf();
+                        let target = cargo_config.target.as_ref().map(String::as_str);
+                        let sysroot = load_sysroot(&file, target, cargo_config)?;
+                        return Ok(ProjectWorkspace::DetachedFile { file, sysroot });
+                    }
+                    Err(e) => return Err(e),
+                };
+                // `cargo metadata` reports resolved paths, so resolve symlinks
+                // in the manifest path as well to keep package roots consistent.
+                let cargo_toml = cargo_toml.canonicalize()?;
+                let cargo = CargoWorkspace::from_cargo_metadata(&cargo_toml, cargo_config)?;
+                let sysroot = if cargo_config.members_only {
+                    Sysroot::default()
+                } else {
+                    load_sysroot(&cargo_toml, cargo.target_triple(), cargo_config)?
+                };
+                Ok(ProjectWorkspace::Cargo { cargo, sysroot })
             }
         }
     }
 
+    /// The manifest the workspace was discovered from: the `Cargo.toml` for
+    /// cargo workspaces, including merged ones, the `rust-project.json` for
+    /// JSON projects and the file itself for detached files. `None` for JSON
+    /// projects which were not loaded from a file.
+    pub fn manifest_path(&self) -> Option<&Path> {
+        match self {
+            ProjectWorkspace::Cargo { cargo, .. } | ProjectWorkspace::Merged { cargo, .. } => {
+                Some(cargo.manifest_path())
+            }
+            ProjectWorkspace::Json { project } => project.path.as_ref().map(PathBuf::as_path),
+            ProjectWorkspace::DetachedFile { file, .. } => Some(file),
+        }
+    }
+
+    /// Saves the workspace to `path`, so that the next session can load it
+    /// without running `cargo metadata`. Use `is_stale` to check whether a
+    /// loaded workspace is still up to date.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let file = File::create(path)?;
+        serde_json::to_writer(BufWriter::new(file), self)?;
+        Ok(())
+    }
+
+    /// Loads a workspace previously stored with `save`.
+    pub fn load(path: &Path) -> Result<ProjectWorkspace> {
+        let file = File::open(path)?;
+        Ok(from_reader(BufReader::new(file))?)
+    }
+
+    /// Combines a cargo workspace with a `rust-project.json` overlay.
+    pub fn merge(
+        cargo_ws: ProjectWorkspace,
+        json_ws: ProjectWorkspace,
+    ) -> Result<ProjectWorkspace> {
+        match (cargo_ws, json_ws) {
+            (ProjectWorkspace::Cargo { cargo, sysroot }, ProjectWorkspace::Json { project }) => {
+                Ok(ProjectWorkspace::Merged { cargo, sysroot, overlay: project })
+            }
+            _ => Err("can only merge a cargo workspace with a rust-project.json")?,
+        }
+    }
+
     /// Returns the roots for the current `ProjectWorkspace`
     /// The return type contains the path and whether or not
     /// the root is a member of the current workspace
+    ///
+    /// The order is stable: members come before other roots, and cargo's
+    /// default members come first among members. Roots are sorted by path
+    /// otherwise.
     pub fn to_roots(&self) -> Vec<PackageRoot> {
-        match self {
-            ProjectWorkspace::Json { project } => {
-                let mut roots = Vec::with_capacity(project.roots.len());
-                for root in &project.roots {
-                    roots.push(PackageRoot::new(root.path.clone(), true));
-                }
+        let mut roots = match self {
+            ProjectWorkspace::Json { project } => json_roots(project),
+            ProjectWorkspace::Cargo { cargo, sysroot } => cargo_roots(cargo, sysroot),
+            ProjectWorkspace::Merged { cargo, sysroot, overlay } => {
+                let mut roots = cargo_roots(cargo, sysroot);
+                roots.extend(json_roots(overlay));
                 roots
             }
-            ProjectWorkspace::Cargo { cargo, sysroot } => {
-                let mut roots = Vec::with_capacity(cargo.packages().len() + sysroot.crates().len());
-                for pkg in cargo.packages() {
-                    let root = pkg.root(&cargo).to_path_buf();
-                    let member = pkg.is_member(&cargo);
-                    roots.push(PackageRoot::new(root, member));
-                }
-                for krate in sysroot.crates() {
-                    roots.push(PackageRoot::new(krate.root_dir(&sysroot).to_path_buf(), false))
-                }
+            ProjectWorkspace::DetachedFile { file, sysroot } => {
+                let mut roots: Vec<PackageRoot> = file
+                    .parent()
+                    .into_iter()
+                    .map(|dir| PackageRoot::new(dir.to_path_buf(), true))
+                    .collect();
+                roots.extend(sysroot_roots(sysroot));
                 roots
             }
-        }
+        };
+        // The sort is stable, so this keeps the order within both groups.
+        roots.sort_by_key(|root| !root.is_member());
+        roots
     }
 
+    /// The number of cargo packages, sysroot crates and `rust-project.json`
+    /// crates in the workspace. This doesn't iterate over them, so it's cheap
+    /// enough for progress reporting.
     pub fn n_packages(&self) -> usize {
         match self {
             ProjectWorkspace::Json { project } => project.crates.len(),
             ProjectWorkspace::Cargo { cargo, sysroot } => {
                 cargo.packages().len() + sysroot.crates().len()
             }
+            ProjectWorkspace::Merged { cargo, sysroot, overlay } => {
+                cargo.packages().len() + sysroot.crates().len() + overlay.crates.len()
+            }
+            ProjectWorkspace::DetachedFile { sysroot, .. } => 1 + sysroot.crates().len(),
         }
     }
 
+    /// Builds the crate graph, using `load` to map crate root paths to files.
+    ///
+    /// The result only depends on the workspace and on the `FileId`s returned
+    /// by `load`: crates are added in a fixed order, so calling this again
+    /// after a reload with a `load` which keeps the ids of unchanged files
+    /// produces an identical graph.
     pub fn to_crate_graph(&self, load: &mut dyn FnMut(&Path) -> Option<FileId>) -> CrateGraph {
+        self.to_crate_graph_with_problems(load).0
+    }
+
+    /// Like `to_crate_graph`, but also returns the problems which left crates
+    /// or dependencies out of the graph.
+    pub fn to_crate_graph_with_problems(
+        &self,
+        load: &mut dyn FnMut(&Path) -> Option<FileId>,
+    ) -> (CrateGraph, Vec<CrateGraphProblem>) {
+        self.build_crate_graph(load, &|| false).expect("building the crate graph was canceled")
+    }
+
+    /// Like `to_crate_graph`, but returns `None` as soon as `should_cancel`
+    /// returns `true`. It is checked before the crates of each package are
+    /// loaded, and nothing else is loaded once it fires.
+    pub fn to_crate_graph_cancelable(
+        &self,
+        load: &mut dyn FnMut(&Path) -> Option<FileId>,
+        should_cancel: &dyn Fn() -> bool,
+    ) -> Option<CrateGraph> {
+        self.build_crate_graph(load, should_cancel).map(|(crate_graph, _problems)| crate_graph)
+    }
+
+    fn build_crate_graph(
+        &self,
+        load: &mut dyn FnMut(&Path) -> Option<FileId>,
+        should_cancel: &dyn Fn() -> bool,
+    ) -> Option<(CrateGraph, Vec<CrateGraphProblem>)> {
+        let _p = profile("ProjectWorkspace::to_crate_graph");
+        let start = Instant::now();
         let mut crate_graph = CrateGraph::default();
+        let mut problems = Vec::new();
+        let mut unloaded_roots = Vec::new();
+        // Only crate roots are loaded, so every failure leaves out a crate.
+        let load: &mut dyn FnMut(&Path) -> Option<FileId> = &mut |path: &Path| {
+            let file_id = load(path);
+            if file_id.is_none() {
+                unloaded_roots.push(path.to_path_buf());
+            }
+            file_id
+        };
         match self {
             ProjectWorkspace::Json { project } => {
-                let mut crates = FxHashMap::default();
-                for (id, krate) in project.crates.iter().enumerate() {
-                    let crate_id = json_project::CrateId(id);
-                    if let Some(file_id) = load(&krate.root_module) {
-                        let edition = match krate.edition {
-                            json_project::Edition::Edition2015 => Edition::Edition2015,
-                            json_project::Edition::Edition2018 => Edition::Edition2018,
-                        };
-                        crates.insert(crate_id, crate_graph.add_crate_root(file_id, edition));
-                    }
+                add_json_crates(&mut crate_graph, project, &[], load, should_cancel)?;
+            }
+            ProjectWorkspace::Cargo { cargo, sysroot } => {
+                add_cargo_crates(
+                    &mut crate_graph,
+                    &mut problems,
+                    cargo,
+                    sysroot,
+                    load,
+                    should_cancel,
+                )?;
+            }
+            ProjectWorkspace::Merged { cargo, sysroot, overlay } => {
+                let externs = add_cargo_crates(
+                    &mut crate_graph,
+                    &mut problems,
+                    cargo,
+                    sysroot,
+                    load,
+                    should_cancel,
+                )?;
+                add_json_crates(&mut crate_graph, overlay, &externs, load, should_cancel)?;
+            }
+            ProjectWorkspace::DetachedFile { file, sysroot } => {
+                let sysroot_crates =
+                    sysroot.add_to_crate_graph(&mut crate_graph, load, should_cancel)?;
+                let libstd = sysroot.std().and_then(|it| sysroot_crates.get(&it).copied());
+                if should_cancel() {
+                    return None;
                 }
-
-                for (id, krate) in project.crates.iter().enumerate() {
-                    for dep in &krate.deps {
-                        let from_crate_id = json_project::CrateId(id);
-                        let to_crate_id = dep.krate;
-                        if let (Some(&from), Some(&to)) =
-                            (crates.get(&from_crate_id), crates.get(&to_crate_id))
-                        {
-                            if let Err(_) = crate_graph.add_dep(from, dep.name.clone().into(), to) {
-                                log::error!(
-                                    "cyclic dependency {:?} -> {:?}",
-                                    from_crate_id,
-                                    to_crate_id
-                                );
-                            }
+                if let Some(file_id) = load(file) {
+                    let crate_id = crate_graph.add_crate_root(file_id, Edition::Edition2018);
+                    if let Some(std) = libstd {
+                        if let Err(_) = crate_graph.add_dep(crate_id, "std".into(), std) {
+                            log::error!("cyclic dependency on std for {}", file.display())
                         }
                     }
                 }
             }
+        }
+        log::debug!(
+            "built crate graph with {} crates in {:?}",
+            crate_graph.iter().count(),
+            start.elapsed(),
+        );
+        let mut root_problems: Vec<CrateGraphProblem> = unloaded_roots
+            .into_iter()
+            .map(|root| CrateGraphProblem::CrateRootNotLoaded { root })
+            .collect();
+        root_problems.extend(problems);
+        Some((crate_graph, root_problems))
+    }
+
+    /// Renders the crate graph as text, for debugging. Crates are identified
+    /// by the paths of their roots and listed in alphabetical order, together
+    /// with their edition and dependencies:
+    ///
+    /// ```text
+    /// /ws/foo/src/lib.rs (2018)
+    ///     bar: /ws/bar/src/lib.rs
+    /// ```
+    pub fn dump_crate_graph(&self, load: &mut dyn FnMut(&Path) -> Option<FileId>) -> String {
+        let mut paths = FxHashMap::default();
+        let crate_graph = self.to_crate_graph(&mut |path: &Path| {
+            let file_id = load(path)?;
+            paths.insert(file_id, path.to_path_buf());
+            Some(file_id)
+        });
+        let root_path = |krate: CrateId| &paths[&crate_graph.crate_root(krate)];
+        let mut crates: Vec<CrateId> = crate_graph.iter().collect();
+        crates.sort_by_key(|&krate| (root_path(krate), krate));
+
+        let mut buf = String::new();
+        for krate in crates {
+            let edition = match crate_graph.edition(krate) {
+                Edition::Edition2015 => "2015",
+                Edition::Edition2018 => "2018",
+            };
+            buf += &format!("{} ({})\n", root_path(krate).display(), edition);
+            let mut deps: Vec<_> = crate_graph
+                .dependencies(krate)
+                .map(|dep| (dep.name.as_str(), root_path(dep.crate_id())))
+                .collect();
+            deps.sort();
+            for (name, path) in deps {
+                buf += &format!("    {}: {}\n", name, path.display());
+            }
+        }
+        buf
+    }
+
+    /// Returns the compile-time environment (as observed by `env!` and
+    /// `option_env!`) of the crate which owns the file at `path`.
+    ///
+    /// Note that `OUT_DIR` is only included for cargo packages whose build
+    /// script was already run by cargo: we don't run build scripts ourselves.
+    pub fn env_for_file(&self, path: &Path) -> FxHashMap<String, String> {
+        match self {
+            ProjectWorkspace::Cargo { cargo, .. } => {
+                cargo.package_for_path(path).map(|pkg| pkg.env(cargo)).unwrap_or_default()
+            }
+            ProjectWorkspace::Json { project } => {
+                project.crate_for_path(path).map(|(_, krate)| krate.env.clone()).unwrap_or_default()
+            }
+            ProjectWorkspace::Merged { cargo, overlay, .. } => overlay
+                .crate_for_path(path)
+                .map(|(_, krate)| krate.env.clone())
+                .or_else(|| cargo.package_for_path(path).map(|pkg| pkg.env(cargo)))
+                .unwrap_or_default(),
+            ProjectWorkspace::DetachedFile { .. } => FxHashMap::default(),
+        }
+    }
+
+    /// Returns true if any of the manifests (`Cargo.toml`, `Cargo.lock` or
+    /// `rust-project.json`) the workspace was loaded from changed on disk
+    /// since discovery.
+    pub fn is_stale(&self) -> bool {
+        match self {
+            ProjectWorkspace::Cargo { cargo, .. } => cargo.stamps.is_stale(),
+            ProjectWorkspace::Json { project } => project.stamps.is_stale(),
+            ProjectWorkspace::Merged { cargo, overlay, .. } => {
+                cargo.stamps.is_stale() || overlay.stamps.is_stale()
+            }
+            ProjectWorkspace::DetachedFile { .. } => false,
+        }
+    }
+
+    /// A hash of the contents of the workspace, which doesn't depend on file
+    /// modification times. Comparing fingerprints tells whether a reload
+    /// actually changed anything.
+    pub fn fingerprint(&self) -> u64 {
+        let sysroot_fingerprint = |sysroot: &Sysroot| {
+            let roots: Vec<&Path> = sysroot.crates().map(|it| it.root(sysroot)).collect();
+            fingerprint(&roots)
+        };
+        match self {
             ProjectWorkspace::Cargo { cargo, sysroot } => {
-                let mut sysroot_crates = FxHashMap::default();
-                for krate in sysroot.crates() {
-                    if let Some(file_id) = load(krate.root(&sysroot)) {
-                        sysroot_crates.insert(
-                            krate,
-                            crate_graph.add_crate_root(file_id, Edition::Edition2015),
-                        );
-                    }
+                fingerprint(&(cargo.fingerprint(), sysroot_fingerprint(sysroot)))
+            }
+            ProjectWorkspace::Json { project } => fingerprint(&project.to_json_string().ok()),
+            ProjectWorkspace::Merged { cargo, sysroot, overlay } => fingerprint(&(
+                cargo.fingerprint(),
+                sysroot_fingerprint(sysroot),
+                overlay.to_json_string().ok(),
+            )),
+            ProjectWorkspace::DetachedFile { file, sysroot } => {
+                fingerprint(&(file, sysroot_fingerprint(sysroot)))
+            }
+        }
+    }
+
+    /// Discovers the workspace again from the manifest it was discovered
+    /// from. Returns `None` if nothing changed, so that the crate graph
+    /// doesn't need to be rebuilt.
+    pub fn reload(&self, cargo_config: &CargoConfig) -> Result<Option<ProjectWorkspace>> {
+        let manifest_path = match (self, self.manifest_path()) {
+            (ProjectWorkspace::Merged { .. }, _) | (_, None) => {
+                Err("only discovered workspaces can be reloaded")?
+            }
+            (_, Some(it)) => it,
+        };
+        let new = ProjectWorkspace::discover(manifest_path, cargo_config)?;
+        if new.fingerprint() == self.fingerprint() {
+            return Ok(None);
+        }
+        Ok(Some(new))
+    }
+
+    /// Returns the crate which most likely owns the file at `path`, judging
+    /// by the directories of crate roots.
+    pub fn crate_root_for_file(&self, path: &Path) -> Option<CrateRootInfo> {
+        match self {
+            ProjectWorkspace::Cargo { cargo, .. } => cargo_crate_root_for_file(cargo, path),
+            ProjectWorkspace::Json { project } => json_crate_root_for_file(project, path),
+            ProjectWorkspace::Merged { cargo, overlay, .. } => {
+                json_crate_root_for_file(overlay, path)
+                    .or_else(|| cargo_crate_root_for_file(cargo, path))
+            }
+            ProjectWorkspace::DetachedFile { file, .. } => {
+                if !path.starts_with(file.parent()?) {
+                    return None;
                 }
-                for from in sysroot.crates() {
-                    for to in from.deps(&sysroot) {
-                        let name = to.name(&sysroot);
-                        if let (Some(&from), Some(&to)) =
-                            (sysroot_crates.get(&from), sysroot_crates.get(&to))
-                        {
-                            if let Err(_) = crate_graph.add_dep(from, name.into(), to) {
-                                log::error!("cyclic dependency between sysroot crates")
-                            }
-                        }
+                Some(CrateRootInfo {
+                    name: None,
+                    edition: Edition::Edition2018,
+                    root: file.clone(),
+                })
+            }
+        }
+    }
+
+    pub fn workspace_root_for(&self, path: &Path) -> Option<&Path> {
+        match self {
+            ProjectWorkspace::Cargo { cargo, .. } => {
+                Some(cargo.workspace_root.as_ref()).filter(|root| path.starts_with(root))
+            }
+            ProjectWorkspace::Json { project } => {
+                project.root_paths().into_iter().find(|root| path.starts_with(root))
+            }
+            ProjectWorkspace::Merged { cargo, overlay, .. } => Some(cargo.workspace_root.as_ref())
+                .into_iter()
+                .chain(overlay.root_paths())
+                .find(|root| path.starts_with(root)),
+            ProjectWorkspace::DetachedFile { file, .. } => {
+                file.parent().filter(|dir| path.starts_with(dir))
+            }
+        }
+    }
+}
+
+/// Stamps and the path of the file are not part of `rust-project.json`, but
+/// saved workspaces need them to tell whether they are stale.
+mod json_project_with_stamps {
+    use super::*;
+
+    pub(super) fn serialize<S: Serializer>(
+        project: &JsonProject,
+        serializer: S,
+    ) -> std::result::Result<S::Ok, S::Error> {
+        (project, &project.stamps, &project.path).serialize(serializer)
+    }
+
+    pub(super) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> std::result::Result<JsonProject, D::Error> {
+        let (mut project, stamps, path): (JsonProject, FileStamps, Option<PathBuf>) =
+            Deserialize::deserialize(deserializer)?;
+        project.stamps = stamps;
+        project.path = path;
+        Ok(project)
+    }
+}
+
+pub(crate) fn fingerprint(value: &impl Hash) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+fn cargo_crate_root_for_file(cargo: &CargoWorkspace, path: &Path) -> Option<CrateRootInfo> {
+    let pkg = cargo.package_for_path(path)?;
+    let target = match pkg.targets(cargo).find(|tgt| tgt.root(cargo) == path) {
+        Some(it) => it,
+        // Prefer the innermost target, and the library among targets sharing
+        // a directory, like `src/lib.rs` and `src/main.rs`.
+        None => pkg
+            .targets(cargo)
+            .filter(|tgt| tgt.root(cargo).parent().map_or(false, |dir| path.starts_with(dir)))
+            .max_by_key(|tgt| (tgt.root(cargo).components().count(), tgt.kind(cargo).is_lib()))?,
+    };
+    Some(CrateRootInfo {
+        name: Some(target.name(cargo).to_string()),
+        edition: pkg.edition(cargo),
+        root: target.root(cargo).to_path_buf(),
+    })
+}
+
+fn json_crate_root_for_file(project: &JsonProject, path: &Path) -> Option<CrateRootInfo> {
+    let (crate_id, krate) = project.crate_for_path(path)?;
+    Some(CrateRootInfo {
+        name: project.crate_name(crate_id).map(String::from),
+        edition: krate.edition.into(),
+        root: krate.root_module.clone(),
+    })
+}
+
+fn json_roots(project: &JsonProject) -> Vec<PackageRoot> {
+    let mut entries = project.root_entries();
+    entries.sort();
+    entries
+        .into_iter()
+        .map(|(path, is_member)| PackageRoot::new(path.to_path_buf(), is_member))
+        .collect()
+}
+
+fn cargo_roots(cargo: &CargoWorkspace, sysroot: &Sysroot) -> Vec<PackageRoot> {
+    let mut roots = Vec::with_capacity(cargo.packages().len() + sysroot.crates().len());
+    // List eager packages and default members first, so that they are
+    // indexed first.
+    let mut packages: Vec<Package> = cargo.packages().collect();
+    packages.sort_by(|&a, &b| {
+        let key = |pkg: Package| {
+            (!pkg.is_eager(cargo), !pkg.is_default_member(cargo), !pkg.is_member(cargo))
+        };
+        key(a).cmp(&key(b)).then_with(|| a.root(cargo).cmp(b.root(cargo)))
+    });
+    for pkg in packages {
+        let root = pkg.root(cargo).to_path_buf();
+        let member = pkg.is_member(cargo);
+        let mut pkg_root = PackageRoot::new(root, member);
+        pkg_root.is_eager = pkg.is_eager(cargo);
+        if cargo.target_directory().starts_with(pkg_root.path()) {
+            pkg_root.exclude.push(cargo.target_directory().to_path_buf());
+        }
+        roots.push(pkg_root);
+        // Modules generated by build scripts are included from `OUT_DIR`.
+        if let Some(out_dir) = pkg.out_dir(cargo) {
+            roots.push(PackageRoot::new(out_dir, member));
+        }
+    }
+    roots.extend(sysroot_roots(sysroot));
+    roots
+}
+
+fn sysroot_roots(sysroot: &Sysroot) -> Vec<PackageRoot> {
+    let mut sysroot_dirs: Vec<&Path> = sysroot.crates().map(|it| it.root_dir(sysroot)).collect();
+    sysroot_dirs.sort();
+    sysroot_dirs
+        .into_iter()
+        .map(|dir| PackageRoot::with_origin(dir.to_path_buf(), RootOrigin::Sysroot))
+        .collect()
+}
+
+/// Finds the sysroot for the project of `manifest` as configured, or an empty
+/// one if it shouldn't be loaded.
+fn load_sysroot(manifest: &Path, target: Option<&str>, config: &CargoConfig) -> Result<Sysroot> {
+    if !config.load_sysroot {
+        return Ok(Sysroot::default());
+    }
+    let sysroot = match Sysroot::discover(manifest, target) {
+        Ok(it) => it,
+        // The sources of the standard library are the same for all targets,
+        // so analysis still works.
+        Err(e) => match e.downcast_ref::<ProjectModelError>() {
+            Some(ProjectModelError::TargetStdMissing { .. }) => {
+                log::warn!("{}", e);
+                Sysroot::discover(manifest, None)?
+            }
+            _ => return Err(e),
+        },
+    };
+    if config.minimal_sysroot {
+        return Ok(sysroot.minimal());
+    }
+    Ok(sysroot)
+}
+
+/// Adds the crates of a `rust-project.json` to the graph. Each crate also gets
+/// a dependency on every crate of `externs`, unless it has an explicit
+/// dependency with the same name.
+fn add_json_crates(
+    crate_graph: &mut CrateGraph,
+    project: &JsonProject,
+    externs: &[(String, CrateId)],
+    load: &mut dyn FnMut(&Path) -> Option<FileId>,
+    should_cancel: &dyn Fn() -> bool,
+) -> Option<()> {
+    let mut crates = FxHashMap::default();
+    for (id, krate) in project.crates.iter().enumerate() {
+        if should_cancel() {
+            return None;
+        }
+        let crate_id = json_project::CrateId(id);
+        if let Some(file_id) = load(&krate.root_module) {
+            let edition = krate.edition.into();
+            let graph_crate_id = crate_graph.add_crate_root(file_id, edition);
+            let display_name = krate
+                .display_name
+                .as_ref()
+                .map(String::as_str)
+                .or_else(|| krate.root_module.file_stem().and_then(|it| it.to_str()));
+            if let Some(display_name) = display_name {
+                crate_graph.set_display_name(graph_crate_id, display_name.into());
+            }
+            if let Some(cfg) = project.cfg(crate_id) {
+                let mut cfg_options = CfgOptions::default();
+                for option in cfg {
+                    // Options are either atoms, like `unix`, or key-value
+                    // pairs, like `feature="std"`.
+                    match option.find('=') {
+                        Some(idx) => cfg_options.insert_key_value(
+                            option[..idx].trim().into(),
+                            option[idx + 1..].trim().trim_matches('"').into(),
+                        ),
+                        None => cfg_options.insert_atom(option.into()),
                     }
                 }
+                crate_graph.set_cfg_options(graph_crate_id, cfg_options);
+            }
+            let mut env = Env::default();
+            for (key, value) in krate.env.iter() {
+                env.set(key, value.clone());
+            }
+            crate_graph.set_env(graph_crate_id, env);
+            crates.insert(crate_id, graph_crate_id);
+        }
+    }
 
-                let libstd = sysroot.std().and_then(|it| sysroot_crates.get(&it).copied());
+    for (id, krate) in project.crates.iter().enumerate() {
+        for dep in &krate.deps {
+            let from_crate_id = json_project::CrateId(id);
+            let to_crate_id = dep.krate;
+            if let (Some(&from), Some(&to)) = (crates.get(&from_crate_id), crates.get(&to_crate_id))
+            {
+                if let Err(_) = crate_graph.add_dep(from, dep.name.clone().into(), to) {
+                    log::error!("cyclic dependency {:?} -> {:?}", from_crate_id, to_crate_id);
+                }
+            }
+        }
+    }
 
-                let mut pkg_to_lib_crate = FxHashMap::default();
-                let mut pkg_crates = FxHashMap::default();
-                // Next, create crates for each package, target pair
-                for pkg in cargo.packages() {
-                    let mut lib_tgt = None;
-                    for tgt in pkg.targets(&cargo) {
-                        let root = tgt.root(&cargo);
-                        if let Some(file_id) = load(root) {
-                            let edition = pkg.edition(&cargo);
-                            let crate_id = crate_graph.add_crate_root(file_id, edition);
-                            if tgt.kind(&cargo) == TargetKind::Lib {
-                                lib_tgt = Some(crate_id);
-                                pkg_to_lib_crate.insert(pkg, crate_id);
-                            }
-                            pkg_crates.entry(pkg).or_insert_with(Vec::new).push(crate_id);
-                        }
+    for (id, krate) in project.crates.iter().enumerate() {
+        let from = match crates.get(&json_project::CrateId(id)) {
+            Some(&it) => it,
+            None => continue,
+        };
+        for (name, to) in externs {
+            if krate.deps.iter().any(|dep| dep.name == *name) {
+                continue;
+            }
+            if let Err(_) = crate_graph.add_dep(from, name.as_str().into(), *to) {
+                log::error!("cyclic dependency {:?} -> {}", json_project::CrateId(id), name);
+            }
+        }
+    }
+    Some(())
+}
+
+/// Adds the crates of a cargo workspace and of the sysroot to the graph.
+/// Returns `std` and the library crates of workspace members, which is what
+/// crates outside of the workspace can depend on, or `None` if canceled.
+fn add_cargo_crates(
+    crate_graph: &mut CrateGraph,
+    problems: &mut Vec<CrateGraphProblem>,
+    cargo: &CargoWorkspace,
+    sysroot: &Sysroot,
+    load: &mut dyn FnMut(&Path) -> Option<FileId>,
+    should_cancel: &dyn Fn() -> bool,
+) -> Option<Vec<(String, CrateId)>> {
+    let sysroot_crates = sysroot.add_to_crate_graph(crate_graph, load, should_cancel)?;
+
+    let libstd = sysroot.std().and_then(|it| sysroot_crates.get(&it).copied());
+    let libproc_macro = sysroot.proc_macro().and_then(|it| sysroot_crates.get(&it).copied());
+
+    let mut pkg_to_lib_crate = FxHashMap::default();
+    let mut pkg_to_lib_name = FxHashMap::default();
+    let mut pkg_crates = FxHashMap::default();
+    // Next, create crates for each package, target pair
+    for pkg in cargo.packages() {
+        if should_cancel() {
+            return None;
+        }
+        let mut lib_tgt = None;
+        let mut env = Env::default();
+        for (key, value) in pkg.env(cargo) {
+            env.set(&key, value);
+        }
+        for tgt in pkg.targets(cargo) {
+            if !tgt.is_enabled(cargo) {
+                log::debug!(
+                    "skipping target {} of {}: features missing",
+                    tgt.name(cargo),
+                    pkg.name(cargo)
+                );
+                continue;
+            }
+            let root = tgt.root(cargo);
+            if let Some(file_id) = load(root) {
+                let edition = pkg.edition(cargo);
+                let crate_id = crate_graph.add_crate_root(file_id, edition);
+                let kind = tgt.kind(cargo);
+                let mut cfg_options = CfgOptions::default();
+                for feature in pkg.enabled_features(cargo) {
+                    cfg_options.insert_key_value("feature".into(), feature.as_str().into());
+                }
+                // cargo compiles integration tests and benchmarks with `--test`.
+                if let TargetKind::Test | TargetKind::Bench = kind {
+                    cfg_options.insert_atom("test".into());
+                }
+                crate_graph.set_cfg_options(crate_id, cfg_options);
+                crate_graph.set_env(crate_id, env.clone());
+                if kind.is_lib() {
+                    lib_tgt = Some(crate_id);
+                    pkg_to_lib_crate.insert(pkg, crate_id);
+                    pkg_to_lib_name.insert(pkg, tgt.crate_name(cargo));
+                }
+                // Proc macros implicitly depend on the `proc_macro` crate
+                if let (TargetKind::ProcMacro, Some(proc_macro)) = (kind, libproc_macro) {
+                    if let Err(_) = crate_graph.add_dep(crate_id, "proc_macro".into(), proc_macro) {
+                        log::error!("cyclic dependency on proc_macro for {}", pkg.name(cargo))
                     }
+                }
+                pkg_crates.entry(pkg).or_insert_with(Vec::new).push((crate_id, kind));
+            }
+        }
 
-                    // Set deps to the std and to the lib target of the current package
-                    for &from in pkg_crates.get(&pkg).into_iter().flatten() {
-                        if let Some(to) = lib_tgt {
-                            if to != from {
-                                if let Err(_) =
-                                    crate_graph.add_dep(from, pkg.name(&cargo).into(), to)
-                                {
-                                    log::error!(
-                                        "cyclic dependency between targets of {}",
-                                        pkg.name(&cargo)
-                                    )
-                                }
-                            }
-                        }
-                        if let Some(std) = libstd {
-                            if let Err(_) = crate_graph.add_dep(from, "std".into(), std) {
-                                log::error!("cyclic dependency on std for {}", pkg.name(&cargo))
-                            }
-                        }
+        // Set deps to the std and to the lib target of the current package
+        for &(from, _kind) in pkg_crates.get(&pkg).into_iter().flatten() {
+            if let Some(to) = lib_tgt {
+                if to != from {
+                    let name = pkg_to_lib_name[&pkg].as_str();
+                    if let Err(_) = crate_graph.add_dep(from, name.into(), to) {
+                        log::error!("cyclic dependency between targets of {}", pkg.name(cargo))
                     }
                 }
+            }
+            if let Some(std) = libstd {
+                if let Err(_) = crate_graph.add_dep(from, "std".into(), std) {
+                    log::error!("cyclic dependency on std for {}", pkg.name(cargo))
+                }
+            }
+        }
+    }
 
-                // Now add a dep ednge from all targets of upstream to the lib
-                // target of downstream.
-                for pkg in cargo.packages() {
-                    for dep in pkg.dependencies(&cargo) {
-                        if let Some(&to) = pkg_to_lib_crate.get(&dep.pkg) {
-                            for &from in pkg_crates.get(&pkg).into_iter().flatten() {
-                                if let Err(_) =
-                                    crate_graph.add_dep(from, dep.name.clone().into(), to)
-                                {
-                                    log::error!(
-                                        "cyclic dependency {} -> {}",
-                                        pkg.name(&cargo),
-                                        dep.pkg.name(&cargo)
-                                    )
-                                }
-                            }
-                        }
+    // Now add a dep ednge from all targets of upstream to the lib
+    // target of downstream.
+    for pkg in cargo.packages() {
+        // A dependency which is listed under several kinds, e.g. both as a
+        // normal and a dev-dependency, must only produce a single edge.
+        let mut edges = FxHashSet::default();
+        for dep in pkg.dependencies(cargo) {
+            let to = match pkg_to_lib_crate.get(&dep.pkg) {
+                Some(&it) => it,
+                None => {
+                    let package = pkg.name(cargo).to_string();
+                    let dependency = dep.name.clone();
+                    let problem = match dep.pkg.lib_target(cargo) {
+                        Some(_) => CrateGraphProblem::DependencyNotLoaded { package, dependency },
+                        None => CrateGraphProblem::DependencyWithoutLib { package, dependency },
+                    };
+                    if !problems.contains(&problem) {
+                        log::warn!("{}", problem);
+                        problems.push(problem);
                     }
+                    continue;
+                }
+            };
+            for &(from, kind) in pkg_crates.get(&pkg).into_iter().flatten() {
+                if dep.kind == DepKind::Dev && !kind.has_dev_deps() {
+                    continue;
+                }
+                if !edges.insert((from, dep.name.as_str(), to)) {
+                    continue;
+                }
+                if let Err(_) = crate_graph.add_dep(from, dep.name.clone().into(), to) {
+                    log::error!("cyclic dependency {} -> {}", pkg.name(cargo), dep.pkg.name(cargo))
                 }
             }
         }
-        crate_graph
     }
 
-    pub fn workspace_root_for(&self, path: &Path) -> Option<&Path> {
-        match self {
-            ProjectWorkspace::Cargo { cargo, .. } => {
-                Some(cargo.workspace_root.as_ref()).filter(|root| path.starts_with(root))
+    let mut externs: Vec<(String, CrateId)> =
+        libstd.map(|it| ("std".to_string(), it)).into_iter().collect();
+    for pkg in cargo.packages().filter(|pkg| pkg.is_member(cargo)) {
+        if let Some(&krate) = pkg_to_lib_crate.get(&pkg) {
+            externs.push((pkg_to_lib_name[&pkg].clone(), krate));
+        }
+    }
+    Some(externs)
+}
+
+fn collect_manifests(dir: &Path, acc: &mut Vec<ProjectManifestPath>) {
+    let entries = match fs::read_dir(dir) {
+        Ok(it) => it,
+        Err(_) => return,
+    };
+    for entry in entries.filter_map(|it| it.ok()) {
+        let path = entry.path();
+        let file_name = entry.file_name();
+        let file_name = file_name.to_string_lossy();
+        let file_type = match entry.file_type() {
+            Ok(it) => it,
+            Err(_) => continue,
+        };
+        if file_type.is_dir() {
+            if !file_name.starts_with('.') && file_name != "target" {
+                collect_manifests(&path, acc);
+            }
+            continue;
+        }
+        match file_name.as_ref() {
+            "rust-project.json" => acc.push(ProjectManifestPath::ProjectJson(path)),
+            "Cargo.toml" => {
+                let is_workspace = fs::read_to_string(&path)
+                    .map(|text| text.lines().any(|line| line.trim() == "[workspace]"))
+                    .unwrap_or(false);
+                acc.push(if is_workspace {
+                    ProjectManifestPath::CargoWorkspace(path)
+                } else {
+                    ProjectManifestPath::CargoPackage(path)
+                })
             }
-            ProjectWorkspace::Json { project: JsonProject { roots, .. } } => roots
-                .iter()
-                .find(|root| path.starts_with(&root.path))
-                .map(|root| root.path.as_ref()),
+            _ => (),
         }
     }
 }
@@ -1,22 +1,54 @@
 use std::{
+    fs,
     path::{Path, PathBuf},
     process::Command,
+    time::Instant,
 };
 
 use ra_arena::{impl_arena_id, Arena, RawId};
+use ra_db::{CrateGraph, CrateId, Edition, FileId};
+use ra_prof::profile;
+use rustc_hash::FxHashMap;
+use serde::{Deserialize, Serialize};
 
-use crate::Result;
+use crate::{ProjectModelError, Result};
 
-#[derive(Debug, Clone)]
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
 pub struct Sysroot {
     crates: Arena<SysrootCrate, SysrootCrateData>,
+    layout: Option<SysrootLayout>,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+/// Directory layout of the `rust-src` component, which changed over time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SysrootLayout {
+    /// `src/lib<name>/lib.rs`, used by older toolchains.
+    Src,
+    /// `library/<name>/src/lib.rs`, used since Rust 1.47.
+    Library,
+}
+
+impl SysrootLayout {
+    fn dir_name(self) -> &'static str {
+        match self {
+            SysrootLayout::Src => "src",
+            SysrootLayout::Library => "library",
+        }
+    }
+
+    fn crate_root(self, dir: &Path, name: &str) -> PathBuf {
+        match self {
+            SysrootLayout::Src => dir.join(format!("lib{}", name)).join("lib.rs"),
+            SysrootLayout::Library => dir.join(name).join("src").join("lib.rs"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct SysrootCrate(RawId);
 impl_arena_id!(SysrootCrate);
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 struct SysrootCrateData {
     name: String,
     root: PathBuf,
@@ -28,33 +60,75 @@ impl Sysroot {
         self.by_name("std")
     }
 
+    pub fn proc_macro(&self) -> Option<SysrootCrate> {
+        self.by_name("proc_macro")
+    }
+
+    pub fn test(&self) -> Option<SysrootCrate> {
+        self.by_name("test")
+    }
+
     pub fn crates<'a>(&'a self) -> impl Iterator<Item = SysrootCrate> + ExactSizeIterator + 'a {
         self.crates.iter().map(|(id, _data)| id)
     }
 
-    pub fn discover(cargo_toml: &Path) -> Result<Sysroot> {
-        let rustc_output = Command::new("rustc")
-            .current_dir(cargo_toml.parent().unwrap())
-            .args(&["--print", "sysroot"])
-            .output()?;
+    /// The layout of the standard library sources, `None` if the sysroot
+    /// wasn't loaded.
+    pub fn layout(&self) -> Option<SysrootLayout> {
+        self.layout
+    }
+
+    /// Finds the sysroot of the toolchain used for `cargo_toml`. If a target
+    /// triple is given, the standard library must be installed for it.
+    ///
+    /// A toolchain pinned with `rust-toolchain` or `rust-toolchain.toml` is
+    /// passed to rustup explicitly, as rust-analyzer itself might run with a
+    /// different one.
+    pub fn discover(cargo_toml: &Path, target: Option<&str>) -> Result<Sysroot> {
+        let _p = profile("Sysroot::discover");
+        let dir = cargo_toml.parent().unwrap();
+        let mut cmd = Command::new("rustc");
+        cmd.current_dir(dir).args(&["--print", "sysroot"]);
+        if let Some(toolchain) = pinned_toolchain(dir) {
+            log::debug!("using toolchain {} pinned for {}", toolchain, dir.display());
+            cmd.env("RUSTUP_TOOLCHAIN", toolchain);
+        }
+        let rustc_output = cmd.output()?;
         if !rustc_output.status.success() {
             Err("failed to locate sysroot")?
         }
         let stdout = String::from_utf8(rustc_output.stdout)?;
         let sysroot_path = Path::new(stdout.trim());
-        let src = sysroot_path.join("lib/rustlib/src/rust/src");
-        if !src.exists() {
-            Err(format!(
-                "can't load standard library from sysroot\n\
-                 {:?}\n\
-                 try running `rustup component add rust-src`",
-                src,
-            ))?;
+        if let Some(target) = target {
+            check_target_std(sysroot_path, target)?;
+        }
+        Sysroot::from_rust_src(&sysroot_path.join("lib/rustlib/src/rust"))
+    }
+
+    /// Loads the standard library from the root of the `rust-src` component,
+    /// in whichever layout it has.
+    pub(crate) fn from_rust_src(rust_src: &Path) -> Result<Sysroot> {
+        for &layout in &[SysrootLayout::Library, SysrootLayout::Src] {
+            let dir = rust_src.join(layout.dir_name());
+            if dir.is_dir() {
+                return Ok(Sysroot::load(&dir, layout));
+            }
         }
+        Err(format!(
+            "can't load standard library from sysroot\n\
+             {:?}\n\
+             try running `rustup component add rust-src`",
+            rust_src,
+        ))?
+    }
 
-        let mut sysroot = Sysroot { crates: Arena::default() };
+    /// Loads the standard library crates from the `src` or `library`
+    /// directory of the `rust-src` component.
+    pub(crate) fn load(dir: &Path, layout: SysrootLayout) -> Sysroot {
+        let start = Instant::now();
+        let mut sysroot = Sysroot { crates: Arena::default(), layout: Some(layout) };
         for name in SYSROOT_CRATES.trim().lines() {
-            let root = src.join(format!("lib{}", name)).join("lib.rs");
+            let root = layout.crate_root(dir, name);
             if root.exists() {
                 sysroot.crates.alloc(SysrootCrateData {
                     name: name.into(),
@@ -75,7 +149,85 @@ impl Sysroot {
                 sysroot.crates[alloc].deps.push(core);
             }
         }
-        Ok(sysroot)
+        // Custom test harnesses and procedural macros use these through the
+        // crates which the compiler injects for them.
+        for name in &["test", "proc_macro"] {
+            if let Some(krate) = sysroot.by_name(name) {
+                for dep in &["std", "core"] {
+                    if let Some(dep) = sysroot.by_name(dep) {
+                        sysroot.crates[krate].deps.push(dep)
+                    }
+                }
+            }
+        }
+        log::debug!(
+            "loaded sysroot {} in {:?}: {} crates",
+            dir.display(),
+            start.elapsed(),
+            sysroot.crates.len(),
+        );
+        sysroot
+    }
+
+    /// Returns a copy of the sysroot with only the crates user code usually
+    /// imports, `std`, `core`, `alloc` and `proc_macro`, and the dependencies
+    /// between them.
+    pub fn minimal(&self) -> Sysroot {
+        let mut res = Sysroot { crates: Arena::default(), layout: self.layout };
+        let mut mapping = FxHashMap::default();
+        for krate in self.crates() {
+            let data = &self.crates[krate];
+            if MINIMAL_SYSROOT_CRATES.contains(&data.name.as_str()) {
+                let data = SysrootCrateData { deps: Vec::new(), ..data.clone() };
+                mapping.insert(krate, res.crates.alloc(data));
+            }
+        }
+        for (&krate, &new_krate) in mapping.iter() {
+            let deps = krate.deps(self).filter_map(|it| mapping.get(&it).copied()).collect();
+            res.crates[new_krate].deps = deps;
+        }
+        res
+    }
+
+    /// Builds a crate graph of just the standard library, using `load` to map
+    /// crate root paths to files.
+    pub fn to_crate_graph(&self, load: &mut dyn FnMut(&Path) -> Option<FileId>) -> CrateGraph {
+        let mut crate_graph = CrateGraph::default();
+        self.add_to_crate_graph(&mut crate_graph, load, &|| false);
+        crate_graph
+    }
+
+    /// Adds the crates which could be loaded to `crate_graph`, returning their
+    /// ids, or `None` if `should_cancel` fired.
+    pub(crate) fn add_to_crate_graph(
+        &self,
+        crate_graph: &mut CrateGraph,
+        load: &mut dyn FnMut(&Path) -> Option<FileId>,
+        should_cancel: &dyn Fn() -> bool,
+    ) -> Option<FxHashMap<SysrootCrate, CrateId>> {
+        let mut sysroot_crates = FxHashMap::default();
+        for krate in self.crates() {
+            if should_cancel() {
+                return None;
+            }
+            if let Some(file_id) = load(krate.root(self)) {
+                sysroot_crates
+                    .insert(krate, crate_graph.add_crate_root(file_id, Edition::Edition2015));
+            }
+        }
+        for from in self.crates() {
+            for to in from.deps(self) {
+                let name = to.name(self);
+                if let (Some(&from), Some(&to)) =
+                    (sysroot_crates.get(&from), sysroot_crates.get(&to))
+                {
+                    if let Err(_) = crate_graph.add_dep(from, name.into(), to) {
+                        log::error!("cyclic dependency between sysroot crates")
+                    }
+                }
+            }
+        }
+        Some(sysroot_crates)
     }
 
     fn by_name(&self, name: &str) -> Option<SysrootCrate> {
@@ -83,6 +235,41 @@ impl Sysroot {
     }
 }
 
+/// Checks that the compiled standard library for `triple` is installed in
+/// `sysroot`. Its sources are the same for all targets, but code which is
+/// analyzed for a target without it can't be built.
+pub(crate) fn check_target_std(sysroot: &Path, triple: &str) -> Result<()> {
+    if sysroot.join("lib/rustlib").join(triple).join("lib").is_dir() {
+        return Ok(());
+    }
+    Err(ProjectModelError::TargetStdMissing { triple: triple.to_string() })?
+}
+
+/// Returns the toolchain pinned by the closest `rust-toolchain.toml` or
+/// `rust-toolchain` file in `dir` or its ancestors. The latter contains either
+/// just the name of the toolchain or the same TOML as the former.
+pub(crate) fn pinned_toolchain(dir: &Path) -> Option<String> {
+    for dir in dir.ancestors() {
+        for file_name in &["rust-toolchain.toml", "rust-toolchain"] {
+            let text = match fs::read_to_string(dir.join(file_name)) {
+                Ok(it) => it,
+                Err(_) => continue,
+            };
+            if !text.contains('[') {
+                return Some(text.trim().to_string()).filter(|it| !it.is_empty());
+            }
+            return text
+                .lines()
+                .map(|line| line.trim())
+                .filter(|line| line.starts_with("channel"))
+                .filter_map(|line| line.splitn(2, '=').nth(1))
+                .map(|value| value.trim().trim_matches('"').to_string())
+                .next();
+        }
+    }
+    None
+}
+
 impl SysrootCrate {
     pub fn name(self, sysroot: &Sysroot) -> &str {
         &sysroot.crates[self].name
@@ -98,6 +285,8 @@ impl SysrootCrate {
     }
 }
 
+const MINIMAL_SYSROOT_CRATES: &[&str] = &["std", "core", "alloc", "proc_macro"];
+
 const SYSROOT_CRATES: &str = "
 std
 core
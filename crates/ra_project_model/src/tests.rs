@@ -0,0 +1,1946 @@
+use std::{
+    cell::{Cell, RefCell},
+    fs, mem,
+    path::{Path, PathBuf},
+    sync::Once,
+};
+
+use ra_db::{CrateGraph, CrateId, Edition, FileId};
+use rustc_hash::FxHashMap;
+use serde_json::{json, Value};
+
+use crate::{
+    cargo_workspace::{cargo_config_build_target, cargo_metadata_args, checked_path},
+    sysroot::{check_target_std, pinned_toolchain},
+    CargoConfig, CargoWorkspace, CrateGraphProblem, CrateRootInfo, DepKind, JsonProject,
+    JsonProjectError, PackageRoot, ProjectManifestPath, ProjectModelError, ProjectWorkspace,
+    RootOrigin, Sysroot, SysrootLayout, TargetKind, VersionParts,
+};
+
+fn pkg_id(name: &str, version: &str) -> String {
+    format!("{} {} (path+file:///ws/{})", name, version, name)
+}
+
+/// A package with a single lib target, rooted at `/ws/<name>`.
+fn package(name: &str, version: &str) -> Value {
+    json!({
+        "name": name,
+        "version": version,
+        "id": pkg_id(name, version),
+        "authors": [],
+        "source": null,
+        "description": null,
+        "dependencies": [],
+        "license": null,
+        "license_file": null,
+        "targets": [target(name, &["lib"], &format!("/ws/{}/src/lib.rs", name))],
+        "features": {},
+        "manifest_path": format!("/ws/{}/Cargo.toml", name),
+        "categories": [],
+        "keywords": [],
+        "readme": null,
+        "repository": null,
+        "edition": "2018",
+        "metadata": null,
+    })
+}
+
+/// Like `package`, but downloaded from crates.io.
+fn registry_package(name: &str, version: &str) -> Value {
+    let mut pkg = package(name, version);
+    pkg["source"] = json!("registry+https://github.com/rust-lang/crates.io-index");
+    pkg
+}
+
+fn target(name: &str, kind: &[&str], src_path: &str) -> Value {
+    json!({
+        "name": name,
+        "kind": kind,
+        "crate_types": kind,
+        "required-features": [],
+        "src_path": src_path,
+        "edition": "2018",
+    })
+}
+
+/// `cargo metadata` output where every package is a workspace member and
+/// there are no dependencies.
+fn metadata(packages: Vec<Value>) -> Value {
+    let ids: Vec<Value> = packages.iter().map(|pkg| pkg["id"].clone()).collect();
+    let nodes: Vec<Value> = ids
+        .iter()
+        .map(|id| json!({ "id": id, "dependencies": [], "deps": [], "features": [] }))
+        .collect();
+    json!({
+        "packages": packages,
+        "workspace_members": ids,
+        "resolve": { "nodes": nodes, "root": null },
+        "workspace_root": "/ws",
+        "target_directory": "/ws/target",
+        "version": 1,
+    })
+}
+
+/// Records that `packages[from]` depends on `packages[to]` under `name`.
+fn add_dep(meta: &mut Value, from: usize, to: usize, name: &str) {
+    let to_id = meta["packages"][to]["id"].clone();
+    let node = &mut meta["resolve"]["nodes"][from];
+    node["dependencies"].as_array_mut().unwrap().push(to_id.clone());
+    node["deps"].as_array_mut().unwrap().push(json!({ "name": name, "pkg": to_id }));
+}
+
+/// A `[dependencies]`-like manifest entry, `kind` being `null`, `"dev"` or
+/// `"build"`.
+fn dependency(name: &str, kind: Value) -> Value {
+    json!({
+        "name": name,
+        "source": null,
+        "req": "*",
+        "kind": kind,
+        "rename": null,
+        "optional": false,
+        "uses_default_features": true,
+        "features": [],
+        "target": null,
+        "registry": null,
+    })
+}
+
+fn cargo_workspace(meta: Value) -> CargoWorkspace {
+    cargo_workspace_with_config(meta, "/ws/Cargo.toml", &CargoConfig::default())
+}
+
+fn cargo_workspace_with_config(
+    meta: Value,
+    cargo_toml: &str,
+    config: &CargoConfig,
+) -> CargoWorkspace {
+    CargoWorkspace::from_json(meta, Path::new(cargo_toml), config).unwrap()
+}
+
+fn json_project(project: Value) -> JsonProject {
+    serde_json::from_value(project).unwrap()
+}
+
+/// A crate graph where every file except `missing` loads successfully.
+struct TestCrateGraph {
+    graph: CrateGraph,
+    problems: Vec<CrateGraphProblem>,
+    files: FxHashMap<PathBuf, FileId>,
+}
+
+impl TestCrateGraph {
+    fn new(ws: &ProjectWorkspace, missing: &[&str]) -> TestCrateGraph {
+        let mut files = FxHashMap::default();
+        let (graph, problems) = ws.to_crate_graph_with_problems(&mut |path: &Path| {
+            if missing.iter().any(|it| Path::new(it) == path) {
+                return None;
+            }
+            let next_id = FileId(files.len() as u32);
+            Some(*files.entry(path.to_path_buf()).or_insert(next_id))
+        });
+        TestCrateGraph { graph, problems, files }
+    }
+
+    fn crate_for_root(&self, root: &str) -> CrateId {
+        let file_id = self.files[Path::new(root)];
+        self.graph.crate_id_for_crate_root(file_id).unwrap()
+    }
+
+    fn deps(&self, root: &str) -> Vec<String> {
+        let mut res: Vec<String> = self
+            .graph
+            .dependencies(self.crate_for_root(root))
+            .map(|dep| dep.name.to_string())
+            .collect();
+        res.sort();
+        res
+    }
+}
+
+#[test]
+fn env_for_file_in_cargo_workspace() {
+    let cargo = cargo_workspace(metadata(vec![package("foo", "1.2.3")]));
+    let ws = ProjectWorkspace::Cargo { cargo, sysroot: Sysroot::default() };
+
+    let env = ws.env_for_file(Path::new("/ws/foo/src/lib.rs"));
+    assert_eq!(env["CARGO_PKG_NAME"], "foo");
+    assert_eq!(env["CARGO_PKG_VERSION"], "1.2.3");
+    assert_eq!(env["CARGO_MANIFEST_DIR"], "/ws/foo");
+
+    assert!(ws.env_for_file(Path::new("/elsewhere/lib.rs")).is_empty());
+}
+
+#[test]
+fn env_for_file_in_json_project() {
+    let project = json_project(json!({
+        "roots": ["/ws"],
+        "crates": [
+            {
+                "root_module": "/ws/foo/lib.rs",
+                "edition": "2018",
+                "deps": [],
+                "env": { "FOO": "bar" },
+            },
+            { "root_module": "/ws/foo/baz/lib.rs", "edition": "2018", "deps": [] },
+        ],
+    }));
+    let ws = ProjectWorkspace::Json { project };
+
+    assert_eq!(ws.env_for_file(Path::new("/ws/foo/qux.rs"))["FOO"], "bar");
+    assert!(ws.env_for_file(Path::new("/ws/foo/baz/lib.rs")).is_empty());
+}
+
+#[test]
+fn single_package_skips_other_members() {
+    let mut meta = metadata(vec![package("foo", "0.1.0"), package("bar", "0.1.0")]);
+    // `--no-deps` output
+    meta["resolve"] = Value::Null;
+    let config = CargoConfig { single_package: true, ..CargoConfig::default() };
+    let cargo = cargo_workspace_with_config(meta, "/ws/bar/Cargo.toml", &config);
+
+    let packages: Vec<_> = cargo.packages().map(|pkg| pkg.name(&cargo).to_string()).collect();
+    assert_eq!(packages, vec!["bar"]);
+    assert_eq!(cargo.workspace_root, Path::new("/ws/bar"));
+}
+
+#[test]
+fn dependency_without_lib_target_is_skipped() {
+    let mut bin_only = package("tool", "0.1.0");
+    bin_only["targets"] = json!([target("tool", &["bin"], "/ws/tool/src/main.rs")]);
+    let mut meta = metadata(vec![package("foo", "0.1.0"), bin_only]);
+    add_dep(&mut meta, 0, 1, "tool");
+    let cargo = cargo_workspace(meta);
+    let tool = cargo.packages().find(|pkg| pkg.name(&cargo) == "tool").unwrap();
+    assert!(tool.lib_target(&cargo).is_none());
+
+    let ws = ProjectWorkspace::Cargo { cargo, sysroot: Sysroot::default() };
+    let graph = TestCrateGraph::new(&ws, &[]);
+    assert!(graph.deps("/ws/foo/src/lib.rs").is_empty());
+    assert_eq!(
+        graph.problems,
+        vec![CrateGraphProblem::DependencyWithoutLib {
+            package: "foo".to_string(),
+            dependency: "tool".to_string(),
+        }]
+    );
+}
+
+#[test]
+fn target_kinds_are_classified() {
+    let mut pkg = package("foo", "0.1.0");
+    pkg["targets"] = json!([
+        target("foo", &["lib"], "/ws/foo/src/lib.rs"),
+        target("foo", &["bin"], "/ws/foo/src/main.rs"),
+        target("ex", &["example"], "/ws/foo/examples/ex.rs"),
+        target("it", &["test"], "/ws/foo/tests/it.rs"),
+        target("perf", &["bench"], "/ws/foo/benches/perf.rs"),
+        target("derive", &["proc-macro"], "/ws/foo/derive/lib.rs"),
+        target("build-script-build", &["custom-build"], "/ws/foo/build.rs"),
+    ]);
+    let cargo = cargo_workspace(metadata(vec![pkg]));
+    let pkg = cargo.packages().next().unwrap();
+
+    let kinds: Vec<_> = pkg.targets(&cargo).map(|tgt| tgt.kind(&cargo)).collect();
+    assert_eq!(
+        kinds,
+        vec![
+            TargetKind::Lib,
+            TargetKind::Bin,
+            TargetKind::Example,
+            TargetKind::Test,
+            TargetKind::Bench,
+            TargetKind::ProcMacro,
+            TargetKind::Other,
+        ]
+    );
+}
+
+#[test]
+fn json_project_roots_are_inferred_from_crates() {
+    let project = json_project(json!({
+        "crates": [
+            { "root_module": "/ws/foo/lib.rs", "edition": "2018", "deps": [] },
+            { "root_module": "/ws/foo/main.rs", "edition": "2018", "deps": [] },
+            { "root_module": "/ws/bar/lib.rs", "edition": "2015", "deps": [] },
+        ],
+    }));
+    let ws = ProjectWorkspace::Json { project };
+
+    let roots: Vec<_> = ws.to_roots().into_iter().map(|root| root.path().clone()).collect();
+    assert_eq!(roots, vec![PathBuf::from("/ws/bar"), PathBuf::from("/ws/foo")]);
+    assert_eq!(ws.workspace_root_for(Path::new("/ws/foo/lib.rs")), Some(Path::new("/ws/foo")));
+}
+
+#[test]
+fn json_project_round_trips() {
+    let text = r#"{
+        "roots": ["/ws"],
+        "crates": [
+            { "root_module": "/ws/foo/lib.rs", "edition": "2018", "deps": [] },
+            {
+                "root_module": "/ws/bar/lib.rs",
+                "edition": "2015",
+                "deps": [{ "crate": 0, "name": "foo" }],
+                "env": { "KEY": "value" }
+            }
+        ]
+    }"#;
+    let project: JsonProject = serde_json::from_str(text).unwrap();
+    let serialized = project.to_json_string().unwrap();
+    let reparsed: JsonProject = serde_json::from_str(&serialized).unwrap();
+    assert_eq!(reparsed.to_json_string().unwrap(), serialized);
+}
+
+#[cfg(unix)]
+#[test]
+fn discover_follows_symlinks_into_the_workspace() {
+    let ws_dir = tempfile::tempdir().unwrap();
+    fs::create_dir_all(ws_dir.path().join("src")).unwrap();
+    fs::write(ws_dir.path().join("src/lib.rs"), "").unwrap();
+    fs::write(ws_dir.path().join("rust-project.json"), r#"{ "roots": [], "crates": [] }"#).unwrap();
+    let outside = tempfile::tempdir().unwrap();
+    let link = outside.path().join("lib.rs");
+    std::os::unix::fs::symlink(ws_dir.path().join("src/lib.rs"), &link).unwrap();
+
+    match ProjectWorkspace::discover(&link, &CargoConfig::default()).unwrap() {
+        ProjectWorkspace::Json { .. } => (),
+        ws => panic!("unexpected workspace: {:?}", ws),
+    }
+}
+
+#[test]
+fn workspace_is_stale_after_manifest_changes() {
+    let dir = tempfile::tempdir().unwrap();
+    let manifest = dir.path().join("rust-project.json");
+    fs::write(&manifest, r#"{ "crates": [] }"#).unwrap();
+
+    let ws = ProjectWorkspace::discover(dir.path(), &CargoConfig::default()).unwrap();
+    assert!(!ws.is_stale());
+
+    fs::write(&manifest, r#"{ "roots": [], "crates": [] }"#).unwrap();
+    assert!(ws.is_stale());
+}
+
+#[test]
+fn deps_of_returns_direct_dependencies() {
+    let mut meta =
+        metadata(vec![package("a", "0.1.0"), package("b", "0.1.0"), package("c", "0.1.0")]);
+    meta["packages"][0]["dependencies"] = json!([dependency("b", Value::Null)]);
+    meta["packages"][1]["dependencies"] = json!([dependency("c", json!("dev"))]);
+    add_dep(&mut meta, 0, 1, "b");
+    add_dep(&mut meta, 1, 2, "c");
+    let cargo = cargo_workspace(meta);
+    let pkgs: Vec<_> = cargo.packages().collect();
+
+    assert_eq!(cargo.deps_of(pkgs[0]), vec![("b".to_string(), DepKind::Normal, pkgs[1])]);
+    assert_eq!(cargo.deps_of(pkgs[1]), vec![("c".to_string(), DepKind::Dev, pkgs[2])]);
+    assert!(cargo.deps_of(pkgs[2]).is_empty());
+}
+
+#[test]
+fn packages_with_mangled_paths_are_skipped() {
+    let mut mangled = package("bad", "0.1.0");
+    mangled["manifest_path"] = json!("/ws/b\u{FFFD}d/Cargo.toml");
+    let mut pkg = package("foo", "0.1.0");
+    pkg["targets"] = json!([
+        target("foo", &["lib"], "/ws/foo/src/lib.rs"),
+        target("foo", &["bin"], "/ws/foo/src/m\u{FFFD}in.rs"),
+    ]);
+    let cargo = cargo_workspace(metadata(vec![pkg, mangled]));
+
+    let packages: Vec<_> = cargo.packages().map(|pkg| pkg.name(&cargo).to_string()).collect();
+    assert_eq!(packages, vec!["foo"]);
+    let foo = cargo.packages().next().unwrap();
+    let roots: Vec<_> = foo.targets(&cargo).map(|tgt| tgt.root(&cargo).to_path_buf()).collect();
+    assert_eq!(roots, vec![PathBuf::from("/ws/foo/src/lib.rs")]);
+
+    assert!(checked_path(Path::new("/ws/foo/src/lib.rs")).is_ok());
+    assert!(checked_path(Path::new("/ws/\u{FFFD}.rs")).is_err());
+}
+
+#[test]
+fn build_target_is_read_from_cargo_config() {
+    let dir = tempfile::tempdir().unwrap();
+    let project = dir.path().join("project");
+    fs::create_dir_all(project.join("src")).unwrap();
+    assert_eq!(cargo_config_build_target(&project.join("src")), None);
+
+    fs::create_dir_all(dir.path().join(".cargo")).unwrap();
+    fs::write(dir.path().join(".cargo/config"), "[build]\ntarget = \"wasm32-unknown-unknown\"\n")
+        .unwrap();
+    assert_eq!(
+        cargo_config_build_target(&project.join("src")).as_ref().map(String::as_str),
+        Some("wasm32-unknown-unknown")
+    );
+
+    // The nearest config wins.
+    fs::create_dir_all(project.join(".cargo")).unwrap();
+    fs::write(
+        project.join(".cargo/config.toml"),
+        "[term]\nverbose = true\n\n[build]\njobs = 1\ntarget = \"thumbv7em-none-eabi\"\n",
+    )
+    .unwrap();
+    assert_eq!(
+        cargo_config_build_target(&project.join("src")).as_ref().map(String::as_str),
+        Some("thumbv7em-none-eabi")
+    );
+}
+
+#[test]
+fn cargo_workspace_in_directory_with_spaces() {
+    let dir = tempfile::tempdir().unwrap();
+    let root = dir.path().join("my project");
+    fs::create_dir_all(root.join("src")).unwrap();
+    fs::write(
+        root.join("Cargo.toml"),
+        "[package]\nname = \"spaced\"\nversion = \"0.1.0\"\n\n[workspace]\n",
+    )
+    .unwrap();
+    fs::write(root.join("src/lib.rs"), "").unwrap();
+
+    let cargo_toml = root.join("Cargo.toml").canonicalize().unwrap();
+    let cargo = CargoWorkspace::from_cargo_metadata(&cargo_toml, &CargoConfig::default()).unwrap();
+    let pkg = cargo.packages().next().unwrap();
+    assert_eq!(pkg.name(&cargo), "spaced");
+    assert_eq!(pkg.root(&cargo), cargo_toml.parent().unwrap());
+}
+
+#[test]
+fn features_map_lists_declared_features() {
+    let mut pkg = package("foo", "0.1.0");
+    pkg["features"] = json!({
+        "default": ["std"],
+        "std": ["serde/std"],
+        "serde": [],
+    });
+    let cargo = cargo_workspace(metadata(vec![pkg]));
+    let pkg = cargo.packages().next().unwrap();
+
+    let features = pkg.features_map(&cargo);
+    assert_eq!(features.len(), 3);
+    assert_eq!(features["default"], vec!["std"]);
+    assert_eq!(features["std"], vec!["serde/std"]);
+    assert!(features["serde"].is_empty());
+}
+
+#[test]
+fn default_members_come_first_in_roots() {
+    let mut meta =
+        metadata(vec![package("a", "0.1.0"), package("b", "0.1.0"), package("c", "0.1.0")]);
+    let cargo = cargo_workspace(meta.clone());
+    assert_eq!(cargo.default_members().count(), 3);
+
+    meta["workspace_default_members"] = json!([pkg_id("c", "0.1.0")]);
+    let cargo = cargo_workspace(meta);
+    let default_members: Vec<_> = cargo.default_members().map(|pkg| pkg.name(&cargo)).collect();
+    assert_eq!(default_members, vec!["c"]);
+
+    let ws = ProjectWorkspace::Cargo { cargo, sysroot: Sysroot::default() };
+    let roots: Vec<_> = ws.to_roots().into_iter().map(|root| root.path().clone()).collect();
+    assert_eq!(roots, vec![PathBuf::from("/ws/c"), PathBuf::from("/ws/a"), PathBuf::from("/ws/b")]);
+}
+
+#[test]
+fn merged_workspace_links_overlay_to_members() {
+    let cargo = cargo_workspace(metadata(vec![package("foo", "0.1.0")]));
+    let cargo_ws = ProjectWorkspace::Cargo { cargo, sysroot: Sysroot::default() };
+    let project = json_project(json!({
+        "roots": ["/gen"],
+        "crates": [
+            { "root_module": "/gen/a.rs", "edition": "2018", "deps": [] },
+            {
+                "root_module": "/gen/b.rs",
+                "edition": "2018",
+                "deps": [{ "crate": 0, "name": "foo" }],
+            },
+        ],
+    }));
+    let ws = ProjectWorkspace::merge(cargo_ws, ProjectWorkspace::Json { project }).unwrap();
+
+    let roots: Vec<_> = ws.to_roots().into_iter().map(|root| root.path().clone()).collect();
+    assert_eq!(roots, vec![PathBuf::from("/ws/foo"), PathBuf::from("/gen")]);
+
+    let graph = TestCrateGraph::new(&ws, &[]);
+    let foo = graph.crate_for_root("/ws/foo/src/lib.rs");
+    let a = graph.crate_for_root("/gen/a.rs");
+    let b = graph.crate_for_root("/gen/b.rs");
+    let deps = |krate| -> Vec<_> {
+        graph.graph.dependencies(krate).map(|dep| (dep.name.to_string(), dep.crate_id())).collect()
+    };
+    assert_eq!(deps(a), vec![("foo".to_string(), foo)]);
+    // explicit dependencies take precedence
+    assert_eq!(deps(b), vec![("foo".to_string(), a)]);
+
+    assert!(ProjectWorkspace::merge(
+        ProjectWorkspace::Json { project: json_project(json!({ "crates": [] })) },
+        ProjectWorkspace::Json { project: json_project(json!({ "crates": [] })) },
+    )
+    .is_err());
+}
+
+#[test]
+fn unloadable_crate_roots_are_left_out() {
+    let mut meta = metadata(vec![package("foo", "0.1.0"), package("bar", "0.1.0")]);
+    add_dep(&mut meta, 0, 1, "bar");
+    let cargo = cargo_workspace(meta);
+    let ws = ProjectWorkspace::Cargo { cargo, sysroot: Sysroot::default() };
+
+    let graph = TestCrateGraph::new(&ws, &["/ws/bar/src/lib.rs"]);
+    assert_eq!(graph.graph.iter().count(), 1);
+    assert!(graph.deps("/ws/foo/src/lib.rs").is_empty());
+    assert_eq!(
+        graph.problems,
+        vec![
+            CrateGraphProblem::CrateRootNotLoaded { root: PathBuf::from("/ws/bar/src/lib.rs") },
+            CrateGraphProblem::DependencyNotLoaded {
+                package: "foo".to_string(),
+                dependency: "bar".to_string(),
+            },
+        ]
+    );
+}
+
+#[test]
+fn version_components_are_exposed() {
+    let cargo = cargo_workspace(metadata(vec![package("foo", "1.2.3-alpha.1")]));
+    let pkg = cargo.packages().next().unwrap();
+
+    assert_eq!(pkg.version(&cargo), "1.2.3-alpha.1");
+    assert_eq!(
+        *pkg.version_parts(&cargo),
+        VersionParts { major: 1, minor: 2, patch: 3, pre: "alpha.1".to_string() }
+    );
+    let env = pkg.env(&cargo);
+    assert_eq!(env["CARGO_PKG_VERSION_MAJOR"], "1");
+    assert_eq!(env["CARGO_PKG_VERSION_MINOR"], "2");
+    assert_eq!(env["CARGO_PKG_VERSION_PATCH"], "3");
+    assert_eq!(env["CARGO_PKG_VERSION_PRE"], "alpha.1");
+}
+
+#[test]
+fn proc_macro_crates_depend_on_proc_macro() {
+    let dir = tempfile::tempdir().unwrap();
+    for krate in &["std", "core", "proc_macro"] {
+        let krate_dir = dir.path().join(format!("lib{}", krate));
+        fs::create_dir_all(&krate_dir).unwrap();
+        fs::write(krate_dir.join("lib.rs"), "").unwrap();
+    }
+    let sysroot = Sysroot::load(dir.path(), SysrootLayout::Src);
+    let proc_macro_root = dir.path().join("libproc_macro/lib.rs");
+
+    let mut derive = package("derive", "0.1.0");
+    derive["targets"] = json!([target("derive", &["proc-macro"], "/ws/derive/src/lib.rs")]);
+    let mut meta = metadata(vec![package("foo", "0.1.0"), derive]);
+    add_dep(&mut meta, 0, 1, "derive");
+    let cargo = cargo_workspace(meta);
+    let ws = ProjectWorkspace::Cargo { cargo, sysroot };
+
+    let graph = TestCrateGraph::new(&ws, &[]);
+    assert_eq!(graph.deps("/ws/derive/src/lib.rs"), vec!["proc_macro", "std"]);
+    assert_eq!(graph.deps("/ws/foo/src/lib.rs"), vec!["derive", "std"]);
+    let proc_macro = graph.crate_for_root(proc_macro_root.to_str().unwrap());
+    let derive = graph.crate_for_root("/ws/derive/src/lib.rs");
+    assert!(graph.graph.dependencies(derive).any(|dep| dep.crate_id() == proc_macro));
+}
+
+#[test]
+fn all_manifests_are_found() {
+    let dir = tempfile::tempdir().unwrap();
+    let files = &[
+        ("Cargo.toml", "[workspace]\nmembers = [\"crates/*\"]\n"),
+        ("crates/foo/Cargo.toml", "[package]\nname = \"foo\"\n"),
+        ("tools/rust-project.json", "{ \"crates\": [] }"),
+        ("target/debug/Cargo.toml", "[package]\n"),
+        (".git/Cargo.toml", "[package]\n"),
+    ];
+    for (path, text) in files {
+        let path = dir.path().join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, text).unwrap();
+    }
+
+    let manifests = ProjectWorkspace::find_all_manifests(dir.path());
+    assert_eq!(
+        manifests,
+        vec![
+            ProjectManifestPath::ProjectJson(dir.path().join("tools/rust-project.json")),
+            ProjectManifestPath::CargoWorkspace(dir.path().join("Cargo.toml")),
+            ProjectManifestPath::CargoPackage(dir.path().join("crates/foo/Cargo.toml")),
+        ]
+    );
+}
+
+#[test]
+fn relocated_target_directory_is_honored() {
+    let dir = tempfile::tempdir().unwrap();
+    let target_dir = dir.path().join("shared-target");
+    let out_dir = target_dir.join("debug/build/foo-0123abcd/out");
+    fs::create_dir_all(&out_dir).unwrap();
+    // A build directory of another package with a common prefix.
+    fs::create_dir_all(target_dir.join("debug/build/foo-bar-4567/out")).unwrap();
+
+    let mut meta = metadata(vec![package("foo", "0.1.0"), package("foo-bar", "0.1.0")]);
+    meta["target_directory"] = json!(target_dir);
+    let cargo = cargo_workspace(meta);
+    let foo = cargo.packages().find(|pkg| pkg.name(&cargo) == "foo").unwrap();
+
+    assert_eq!(cargo.target_directory(), target_dir.as_path());
+    assert_eq!(foo.out_dir(&cargo), Some(out_dir.clone()));
+    assert_eq!(foo.env(&cargo)["OUT_DIR"], out_dir.display().to_string());
+
+    let ws = ProjectWorkspace::Cargo { cargo, sysroot: Sysroot::default() };
+    assert!(ws.to_roots().iter().any(|root| root.path() == &out_dir));
+    let graph = TestCrateGraph::new(&ws, &[]);
+    let env = graph.graph.env(graph.crate_for_root("/ws/foo/src/lib.rs"));
+    assert_eq!(env.get("OUT_DIR"), Some(out_dir.display().to_string().as_str()));
+
+    let mut meta = metadata(vec![package("foo", "0.1.0")]);
+    meta["target_directory"] = json!("/ws/foo/build");
+    let ws = ProjectWorkspace::Cargo { cargo: cargo_workspace(meta), sysroot: Sysroot::default() };
+    assert_eq!(ws.to_roots()[0].exclude(), &[PathBuf::from("/ws/foo/build")]);
+}
+
+#[test]
+fn sysroot_is_not_loaded_when_disabled() {
+    let dir = tempfile::tempdir().unwrap();
+    fs::create_dir_all(dir.path().join("src")).unwrap();
+    fs::write(
+        dir.path().join("Cargo.toml"),
+        "[package]\nname = \"no_std_crate\"\nversion = \"0.1.0\"\n\n[workspace]\n",
+    )
+    .unwrap();
+    fs::write(dir.path().join("src/lib.rs"), "#![no_std]").unwrap();
+
+    let config = CargoConfig { load_sysroot: false, ..CargoConfig::default() };
+    let ws = ProjectWorkspace::discover(dir.path(), &config).unwrap();
+    match &ws {
+        ProjectWorkspace::Cargo { sysroot, .. } => assert_eq!(sysroot.crates().len(), 0),
+        _ => panic!("expected a cargo workspace"),
+    }
+    assert_eq!(ws.n_packages(), 1);
+    let graph = TestCrateGraph::new(&ws, &[]).graph;
+    let crates: Vec<CrateId> = graph.iter().collect();
+    assert_eq!(crates.len(), 1);
+    assert_eq!(graph.dependencies(crates[0]).count(), 0);
+}
+
+#[test]
+fn saved_workspace_round_trips() {
+    let dir = tempfile::tempdir().unwrap();
+    for krate in &["std", "core", "alloc"] {
+        let krate_dir = dir.path().join(format!("lib{}", krate));
+        fs::create_dir_all(&krate_dir).unwrap();
+        fs::write(krate_dir.join("lib.rs"), "").unwrap();
+    }
+    let sysroot = Sysroot::load(dir.path(), SysrootLayout::Src);
+    let mut foo = package("foo", "0.1.0");
+    foo["edition"] = json!("2015");
+    let mut meta = metadata(vec![foo, package("bar", "0.2.0")]);
+    add_dep(&mut meta, 0, 1, "bar");
+    let ws = ProjectWorkspace::Cargo { cargo: cargo_workspace(meta), sysroot };
+
+    let snapshot = dir.path().join("workspace.json");
+    ws.save(&snapshot).unwrap();
+    let loaded = ProjectWorkspace::load(&snapshot).unwrap();
+
+    let graph = TestCrateGraph::new(&ws, &[]);
+    let loaded_graph = TestCrateGraph::new(&loaded, &[]);
+    assert_eq!(loaded_graph.graph, graph.graph);
+    assert_eq!(loaded_graph.files, graph.files);
+    assert_eq!(loaded.n_packages(), ws.n_packages());
+
+    let manifest = dir.path().join("rust-project.json");
+    fs::write(&manifest, r#"{ "roots": [], "crates": [] }"#).unwrap();
+    let ws = ProjectWorkspace::discover(dir.path(), &CargoConfig::default()).unwrap();
+    ws.save(&snapshot).unwrap();
+    let loaded = ProjectWorkspace::load(&snapshot).unwrap();
+    assert!(!loaded.is_stale());
+    fs::write(&manifest, r#"{ "roots": ["/src"], "crates": [] }"#).unwrap();
+    assert!(loaded.is_stale());
+}
+
+#[test]
+fn packages_are_found_by_name() {
+    let meta =
+        metadata(vec![package("foo", "0.1.0"), package("rand", "0.7.3"), package("rand", "0.8.0")]);
+    let cargo = cargo_workspace(meta);
+
+    let versions: Vec<&str> =
+        cargo.packages_by_name("rand").into_iter().map(|pkg| pkg.version(&cargo)).collect();
+    assert_eq!(versions, vec!["0.7.3", "0.8.0"]);
+    assert!(cargo.packages_by_name("bar").is_empty());
+
+    let rand = cargo.package_by_name_version("rand", "0.8.0").unwrap();
+    assert_eq!(rand.version(&cargo), "0.8.0");
+    assert_eq!(cargo.package_by_name_version("rand", "0.6.0"), None);
+}
+
+#[test]
+fn config_overrides_are_passed_to_cargo_metadata() {
+    let config = CargoConfig {
+        single_package: true,
+        config_overrides: vec![
+            "net.offline=true".to_string(),
+            "--format-version=2".to_string(),
+            "source.crates-io.replace-with=\"mirror\"".to_string(),
+        ],
+        ..CargoConfig::default()
+    };
+    let args = cargo_metadata_args(&config, Some("wasm32-unknown-unknown"));
+    assert_eq!(
+        args,
+        vec![
+            "metadata",
+            "--format-version",
+            "1",
+            "--all-features",
+            "--no-deps",
+            "--filter-platform",
+            "wasm32-unknown-unknown",
+            "--config",
+            "net.offline=true",
+            "--config",
+            "source.crates-io.replace-with=\"mirror\"",
+        ]
+    );
+}
+
+#[test]
+fn enabled_optional_dependencies_enable_features() {
+    let mut meta = metadata(vec![
+        package("foo", "0.1.0"),
+        package("bar-impl", "0.1.0"),
+        package("baz", "0.1.0"),
+    ]);
+    let mut bar = dependency("bar-impl", Value::Null);
+    bar["rename"] = json!("bar");
+    bar["optional"] = json!(true);
+    let mut baz = dependency("baz", Value::Null);
+    baz["optional"] = json!(true);
+    meta["packages"][0]["dependencies"] = json!([bar, baz]);
+    // Only `bar` is enabled.
+    add_dep(&mut meta, 0, 1, "bar");
+    meta["resolve"]["nodes"][0]["features"] = json!(["default"]);
+    let cargo = cargo_workspace(meta);
+    let foo = cargo.packages().next().unwrap();
+
+    let deps: Vec<_> =
+        foo.dependencies(&cargo).map(|dep| (dep.name.as_str(), dep.optional)).collect();
+    assert_eq!(deps, vec![("bar", true)]);
+    assert_eq!(foo.enabled_features(&cargo), &["bar".to_string(), "default".to_string()]);
+}
+
+#[test]
+fn crate_root_for_file_in_cargo_workspace() {
+    let mut foo = package("foo", "0.1.0");
+    foo["edition"] = json!("2015");
+    foo["targets"] = json!([
+        target("foo", &["lib"], "/ws/foo/src/lib.rs"),
+        target("foo-cli", &["bin"], "/ws/foo/src/main.rs"),
+        target("it", &["test"], "/ws/foo/tests/it.rs"),
+    ]);
+    let cargo = cargo_workspace(metadata(vec![foo]));
+    let ws = ProjectWorkspace::Cargo { cargo, sysroot: Sysroot::default() };
+
+    let crate_root = |path: &str| {
+        ws.crate_root_for_file(Path::new(path)).map(|info| {
+            assert_eq!(info.edition, Edition::Edition2015);
+            (info.name.unwrap(), info.root.display().to_string())
+        })
+    };
+    let lib = Some(("foo".to_string(), "/ws/foo/src/lib.rs".to_string()));
+    assert_eq!(crate_root("/ws/foo/src/lib.rs"), lib);
+    assert_eq!(crate_root("/ws/foo/src/bar/baz.rs"), lib);
+    assert_eq!(
+        crate_root("/ws/foo/src/main.rs"),
+        Some(("foo-cli".to_string(), "/ws/foo/src/main.rs".to_string()))
+    );
+    assert_eq!(
+        crate_root("/ws/foo/tests/common/mod.rs"),
+        Some(("it".to_string(), "/ws/foo/tests/it.rs".to_string()))
+    );
+    assert_eq!(crate_root("/ws/foo/build.rs"), None);
+    assert_eq!(crate_root("/elsewhere/lib.rs"), None);
+}
+
+#[test]
+fn crate_root_for_file_in_json_project() {
+    let project = json_project(json!({
+        "crates": [
+            { "root_module": "/gen/a/lib.rs", "edition": "2015", "deps": [] },
+            {
+                "root_module": "/gen/b/lib.rs",
+                "edition": "2018",
+                "deps": [{ "crate": 0, "name": "alpha" }],
+            },
+        ],
+    }));
+    let ws = ProjectWorkspace::Json { project };
+
+    assert_eq!(
+        ws.crate_root_for_file(Path::new("/gen/a/foo/bar.rs")),
+        Some(CrateRootInfo {
+            name: Some("alpha".to_string()),
+            edition: Edition::Edition2015,
+            root: PathBuf::from("/gen/a/lib.rs"),
+        })
+    );
+    assert_eq!(
+        ws.crate_root_for_file(Path::new("/gen/b/lib.rs")),
+        Some(CrateRootInfo {
+            name: None,
+            edition: Edition::Edition2018,
+            root: PathBuf::from("/gen/b/lib.rs"),
+        })
+    );
+    assert_eq!(ws.crate_root_for_file(Path::new("/gen/c.rs")), None);
+}
+
+#[test]
+fn sysroot_is_loaded_in_both_layouts() {
+    let old = tempfile::tempdir().unwrap();
+    let new = tempfile::tempdir().unwrap();
+    for krate in &["std", "core", "alloc"] {
+        let old_dir = old.path().join("src").join(format!("lib{}", krate));
+        fs::create_dir_all(&old_dir).unwrap();
+        fs::write(old_dir.join("lib.rs"), "").unwrap();
+        let new_dir = new.path().join("library").join(krate).join("src");
+        fs::create_dir_all(&new_dir).unwrap();
+        fs::write(new_dir.join("lib.rs"), "").unwrap();
+    }
+
+    for (rust_src, layout) in
+        &[(old.path(), SysrootLayout::Src), (new.path(), SysrootLayout::Library)]
+    {
+        let sysroot = Sysroot::from_rust_src(rust_src).unwrap();
+        assert_eq!(sysroot.layout(), Some(*layout));
+        let mut names: Vec<&str> = sysroot.crates().map(|it| it.name(&sysroot)).collect();
+        names.sort();
+        assert_eq!(names, vec!["alloc", "core", "std"]);
+        let std = sysroot.std().unwrap();
+        assert!(std.root(&sysroot).starts_with(rust_src));
+        assert!(std.root(&sysroot).exists());
+    }
+
+    let empty = tempfile::tempdir().unwrap();
+    assert!(Sysroot::from_rust_src(empty.path()).is_err());
+}
+
+#[test]
+fn primary_package_comes_from_resolve_root() {
+    let mut meta = metadata(vec![package("dep", "0.1.0"), package("foo", "0.1.0")]);
+    meta["resolve"]["root"] = json!(pkg_id("foo", "0.1.0"));
+    let cargo = cargo_workspace(meta);
+    let primary = cargo.primary_package().unwrap();
+    assert_eq!(primary.name(&cargo), "foo");
+
+    // Virtual workspaces have no root package.
+    let cargo = cargo_workspace(metadata(vec![package("a", "0.1.0"), package("b", "0.1.0")]));
+    assert_eq!(cargo.primary_package(), None);
+
+    let mut meta = metadata(vec![package("foo", "0.1.0")]);
+    meta["resolve"] = Value::Null;
+    let config = CargoConfig { single_package: true, ..CargoConfig::default() };
+    let cargo = cargo_workspace_with_config(meta, "/ws/foo/Cargo.toml", &config);
+    assert_eq!(cargo.primary_package().map(|pkg| pkg.name(&cargo)), Some("foo"));
+}
+
+#[test]
+fn roots_are_ordered_deterministically() {
+    let mut meta = metadata(vec![
+        package("b", "0.1.0"),
+        registry_package("aaa-dep", "0.1.0"),
+        package("a", "0.1.0"),
+        package("c", "0.1.0"),
+    ]);
+    meta["workspace_members"] =
+        json!([pkg_id("b", "0.1.0"), pkg_id("a", "0.1.0"), pkg_id("c", "0.1.0")]);
+    meta["workspace_default_members"] = json!([pkg_id("c", "0.1.0")]);
+    let cargo_ws =
+        ProjectWorkspace::Cargo { cargo: cargo_workspace(meta), sysroot: Sysroot::default() };
+    let overlay = json_project(json!({
+        "roots": ["/gen/z", "/gen/y"],
+        "crates": [],
+    }));
+    let ws =
+        ProjectWorkspace::merge(cargo_ws, ProjectWorkspace::Json { project: overlay }).unwrap();
+
+    let roots: Vec<_> = ws
+        .to_roots()
+        .into_iter()
+        .map(|root| (root.path().display().to_string(), root.is_member()))
+        .collect();
+    assert_eq!(
+        roots,
+        vec![
+            ("/ws/c".to_string(), true),
+            ("/ws/a".to_string(), true),
+            ("/ws/b".to_string(), true),
+            ("/gen/y".to_string(), true),
+            ("/gen/z".to_string(), true),
+            ("/ws/aaa-dep".to_string(), false),
+        ]
+    );
+    let again: Vec<_> = ws.to_roots().into_iter().map(|root| root.path().clone()).collect();
+    let first: Vec<_> = roots.into_iter().map(|(path, _)| PathBuf::from(path)).collect();
+    assert_eq!(again, first);
+}
+
+#[test]
+fn rust_version_is_parsed() {
+    let mut meta =
+        metadata(vec![package("foo", "0.1.0"), package("bar", "0.1.0"), package("baz", "0.1.0")]);
+    meta["packages"][0]["rust_version"] = json!("1.56");
+    meta["packages"][1]["rust_version"] = json!("1.38.1");
+    let cargo = cargo_workspace(meta);
+
+    let rust_version = |name: &str| {
+        let pkg = cargo.packages_by_name(name)[0];
+        pkg.rust_version(&cargo).map(|it| it.to_string())
+    };
+    assert_eq!(rust_version("foo"), Some("1.56.0".to_string()));
+    assert_eq!(rust_version("bar"), Some("1.38.1".to_string()));
+    assert_eq!(rust_version("baz"), None);
+}
+
+#[test]
+fn unstable_flags_are_passed_to_cargo_metadata() {
+    let config = CargoConfig {
+        unstable_flags: vec!["bindeps".to_string(), "--offline".to_string()],
+        ..CargoConfig::default()
+    };
+    let args = cargo_metadata_args(&config, None);
+    assert_eq!(args, vec!["metadata", "--format-version", "1", "--all-features", "-Z", "bindeps"]);
+}
+
+#[test]
+fn public_dependencies_are_parsed_when_declared() {
+    let mut meta = metadata(vec![
+        package("foo", "0.1.0"),
+        package("bar", "0.1.0"),
+        package("baz", "0.1.0"),
+        package("qux", "0.1.0"),
+    ]);
+    let mut bar = dependency("bar", Value::Null);
+    bar["public"] = json!(true);
+    let mut baz = dependency("baz", Value::Null);
+    baz["public"] = json!(false);
+    meta["packages"][0]["dependencies"] = json!([bar, baz, dependency("qux", Value::Null)]);
+    add_dep(&mut meta, 0, 1, "bar");
+    add_dep(&mut meta, 0, 2, "baz");
+    add_dep(&mut meta, 0, 3, "qux");
+    let cargo = cargo_workspace(meta);
+    let foo = cargo.packages().next().unwrap();
+
+    let deps: Vec<_> =
+        foo.dependencies(&cargo).map(|dep| (dep.name.as_str(), dep.public)).collect();
+    assert_eq!(deps, vec![("bar", Some(true)), ("baz", Some(false)), ("qux", None)]);
+}
+
+#[test]
+fn public_dependencies_are_unknown_when_not_declared() {
+    let mut meta = metadata(vec![package("foo", "0.1.0"), package("bar", "0.1.0")]);
+    meta["packages"][0]["dependencies"] = json!([dependency("bar", Value::Null)]);
+    add_dep(&mut meta, 0, 1, "bar");
+    let cargo = cargo_workspace(meta);
+    let foo = cargo.packages().next().unwrap();
+
+    let deps: Vec<_> =
+        foo.dependencies(&cargo).map(|dep| (dep.name.as_str(), dep.public)).collect();
+    assert_eq!(deps, vec![("bar", None)]);
+}
+
+#[test]
+fn crate_graph_is_dumped_deterministically() {
+    let mut foo = package("foo", "0.1.0");
+    foo["targets"].as_array_mut().unwrap().push(target("foo", &["bin"], "/ws/foo/src/main.rs"));
+    let mut bar = package("bar", "0.1.0");
+    bar["edition"] = json!("2015");
+    let mut meta = metadata(vec![foo, bar]);
+    add_dep(&mut meta, 0, 1, "bar");
+    let ws = ProjectWorkspace::Cargo { cargo: cargo_workspace(meta), sysroot: Sysroot::default() };
+
+    let mut files = FxHashMap::default();
+    let mut load = |path: &Path| {
+        let next_id = FileId(files.len() as u32);
+        Some(*files.entry(path.to_path_buf()).or_insert(next_id))
+    };
+    let dump = ws.dump_crate_graph(&mut load);
+    assert_eq!(
+        dump,
+        "\
+/ws/bar/src/lib.rs (2015)
+/ws/foo/src/lib.rs (2018)
+    bar: /ws/bar/src/lib.rs
+/ws/foo/src/main.rs (2018)
+    bar: /ws/bar/src/lib.rs
+    foo: /ws/foo/src/lib.rs
+"
+    );
+    assert_eq!(ws.dump_crate_graph(&mut load), dump);
+}
+
+#[test]
+fn lib_targets_are_imported_by_their_crate_names() {
+    let mut foo_bar = package("foo-bar", "0.1.0");
+    foo_bar["targets"] = json!([
+        target("foo-bar", &["lib"], "/ws/foo-bar/src/lib.rs"),
+        target("foo-bar", &["bin"], "/ws/foo-bar/src/main.rs"),
+    ]);
+    let mut baz = package("baz", "0.1.0");
+    baz["targets"] = json!([
+        target("custom", &["lib"], "/ws/baz/src/lib.rs"),
+        target("baz", &["bin"], "/ws/baz/src/main.rs"),
+    ]);
+    let cargo = cargo_workspace(metadata(vec![foo_bar, baz]));
+    let cargo_ws = ProjectWorkspace::Cargo { cargo, sysroot: Sysroot::default() };
+    let overlay = json_project(json!({
+        "roots": ["/gen"],
+        "crates": [{ "root_module": "/gen/lib.rs", "edition": "2018", "deps": [] }],
+    }));
+    let ws =
+        ProjectWorkspace::merge(cargo_ws, ProjectWorkspace::Json { project: overlay }).unwrap();
+
+    let graph = TestCrateGraph::new(&ws, &[]);
+    assert_eq!(graph.deps("/ws/foo-bar/src/main.rs"), vec!["foo_bar"]);
+    assert_eq!(graph.deps("/ws/baz/src/main.rs"), vec!["custom"]);
+    assert_eq!(graph.deps("/gen/lib.rs"), vec!["custom", "foo_bar"]);
+}
+
+#[test]
+fn json_roots_can_be_marked_external() {
+    let text = r#"{
+        "roots": ["/ws", { "path": "/vendor/dep", "is_workspace_member": false }],
+        "crates": []
+    }"#;
+    let project: JsonProject = serde_json::from_str(text).unwrap();
+    let serialized = project.to_json_string().unwrap();
+    let ws = ProjectWorkspace::Json { project };
+
+    let roots: Vec<_> = ws
+        .to_roots()
+        .into_iter()
+        .map(|root| (root.path().display().to_string(), root.is_member()))
+        .collect();
+    assert_eq!(roots, vec![("/ws".to_string(), true), ("/vendor/dep".to_string(), false)]);
+
+    let reparsed: JsonProject = serde_json::from_str(&serialized).unwrap();
+    assert_eq!(reparsed.to_json_string().unwrap(), serialized);
+}
+
+#[test]
+fn targets_with_disabled_required_features_are_skipped() {
+    let mut foo = package("foo", "0.1.0");
+    let mut demo = target("demo", &["example"], "/ws/foo/examples/demo.rs");
+    demo["required-features"] = json!(["cli"]);
+    let mut tool = target("tool", &["bin"], "/ws/foo/src/bin/tool.rs");
+    tool["required-features"] = json!(["fast"]);
+    foo["targets"].as_array_mut().unwrap().extend(vec![demo, tool]);
+    foo["features"] = json!({ "cli": [], "fast": [] });
+    let mut meta = metadata(vec![foo]);
+    meta["resolve"]["nodes"][0]["features"] = json!(["fast"]);
+    let cargo = cargo_workspace(meta);
+
+    let required_features: Vec<_> = cargo
+        .packages()
+        .flat_map(|pkg| pkg.targets(&cargo))
+        .map(|tgt| {
+            (tgt.name(&cargo), tgt.required_features(&cargo).to_vec(), tgt.is_enabled(&cargo))
+        })
+        .collect();
+    assert_eq!(
+        required_features,
+        vec![
+            ("foo", vec![], true),
+            ("demo", vec!["cli".to_string()], false),
+            ("tool", vec!["fast".to_string()], true),
+        ]
+    );
+
+    let ws = ProjectWorkspace::Cargo { cargo, sysroot: Sysroot::default() };
+    let graph = TestCrateGraph::new(&ws, &[]);
+    assert!(!graph.files.contains_key(Path::new("/ws/foo/examples/demo.rs")));
+    assert_eq!(graph.deps("/ws/foo/src/bin/tool.rs"), vec!["foo"]);
+}
+
+#[test]
+fn all_features_are_enabled_without_resolve() {
+    let mut foo = package("foo", "0.1.0");
+    let mut demo = target("demo", &["example"], "/ws/foo/examples/demo.rs");
+    demo["required-features"] = json!(["cli"]);
+    foo["targets"].as_array_mut().unwrap().push(demo);
+    foo["features"] = json!({ "cli": [], "default": ["cli"] });
+    let mut meta = metadata(vec![foo]);
+    meta["resolve"] = Value::Null;
+    let cargo = cargo_workspace(meta);
+
+    let foo = cargo.packages().next().unwrap();
+    assert_eq!(foo.enabled_features(&cargo), &["cli".to_string(), "default".to_string()]);
+    assert!(foo.targets(&cargo).all(|tgt| tgt.is_enabled(&cargo)));
+
+    let ws = ProjectWorkspace::Cargo { cargo, sysroot: Sysroot::default() };
+    let graph = TestCrateGraph::new(&ws, &[]);
+    let cfg_options = graph.graph.cfg_options(graph.crate_for_root("/ws/foo/src/lib.rs"));
+    assert!(cfg_options.is_key_value_enabled("feature", "cli"));
+    assert!(cfg_options.is_key_value_enabled("feature", "default"));
+    assert!(!cfg_options.is_atom_enabled("cli"));
+}
+
+#[test]
+fn missing_target_std_is_detected() {
+    let sysroot = tempfile::tempdir().unwrap();
+    fs::create_dir_all(sysroot.path().join("lib/rustlib/x86_64-unknown-linux-gnu/lib")).unwrap();
+    fs::create_dir_all(sysroot.path().join("lib/rustlib/src/rust/library")).unwrap();
+
+    assert!(check_target_std(sysroot.path(), "x86_64-unknown-linux-gnu").is_ok());
+    let err = check_target_std(sysroot.path(), "wasm32-unknown-unknown").unwrap_err();
+    assert_eq!(
+        err.downcast_ref::<ProjectModelError>(),
+        Some(&ProjectModelError::TargetStdMissing { triple: "wasm32-unknown-unknown".to_string() })
+    );
+    assert!(err.to_string().contains("rustup target add wasm32-unknown-unknown"));
+}
+
+#[test]
+fn virtual_workspaces_are_detected() {
+    let cargo = cargo_workspace(metadata(vec![package("a", "0.1.0"), package("b", "0.1.0")]));
+    assert!(cargo.is_virtual());
+
+    let mut meta = metadata(vec![package("foo", "0.1.0")]);
+    meta["workspace_root"] = json!("/ws/foo");
+    meta["resolve"]["root"] = json!(pkg_id("foo", "0.1.0"));
+    let cargo = cargo_workspace_with_config(meta, "/ws/foo/Cargo.toml", &CargoConfig::default());
+    assert!(!cargo.is_virtual());
+}
+
+#[test]
+fn roots_can_be_compared() {
+    let mut meta = metadata(vec![package("foo", "0.1.0"), registry_package("dep", "0.1.0")]);
+    meta["workspace_members"] = json!([pkg_id("foo", "0.1.0")]);
+    let ws = ProjectWorkspace::Cargo { cargo: cargo_workspace(meta), sysroot: Sysroot::default() };
+
+    let roots = ws.to_roots();
+    assert_eq!(
+        roots,
+        vec![
+            PackageRoot::new(PathBuf::from("/ws/foo"), true),
+            PackageRoot::new(PathBuf::from("/ws/dep"), false),
+        ]
+    );
+    let parts: Vec<_> = roots.into_iter().map(PackageRoot::into_parts).collect();
+    assert_eq!(parts, vec![(PathBuf::from("/ws/foo"), true), (PathBuf::from("/ws/dep"), false)]);
+}
+
+#[test]
+fn minimal_sysroot_keeps_only_public_crates() {
+    let rust_src = tempfile::tempdir().unwrap();
+    for krate in &["std", "core", "alloc", "proc_macro", "test", "panic_abort", "compiler_builtins"]
+    {
+        let dir = rust_src.path().join("library").join(krate).join("src");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("lib.rs"), "").unwrap();
+    }
+    let sysroot = Sysroot::from_rust_src(rust_src.path()).unwrap();
+    assert_eq!(sysroot.crates().len(), 7);
+
+    let minimal = sysroot.minimal();
+    assert_eq!(minimal.layout(), Some(SysrootLayout::Library));
+    let mut names: Vec<&str> = minimal.crates().map(|it| it.name(&minimal)).collect();
+    names.sort();
+    assert_eq!(names, vec!["alloc", "core", "proc_macro", "std"]);
+    let std = minimal.std().unwrap();
+    let std_deps: Vec<&str> = std.deps(&minimal).map(|it| it.name(&minimal)).collect();
+    assert_eq!(std_deps, vec!["alloc", "core"]);
+}
+
+#[test]
+fn packages_are_found_by_crate_name() {
+    let mut meta = metadata(vec![
+        package("app", "0.1.0"),
+        package("serde", "1.0.0"),
+        package("bar", "0.1.0"),
+        package("foo-bar", "0.1.0"),
+    ]);
+    add_dep(&mut meta, 0, 1, "serde");
+    add_dep(&mut meta, 0, 2, "baz");
+    add_dep(&mut meta, 0, 3, "foo_bar");
+    let cargo = cargo_workspace(meta);
+    let by_name = |name: &str| cargo.packages().find(|pkg| pkg.name(&cargo) == name);
+    let app = by_name("app").unwrap();
+    let found = |crate_name: &str| cargo.package_for_crate_name(app, crate_name);
+
+    assert_eq!(found("serde"), by_name("serde"));
+    assert_eq!(found("baz"), by_name("bar"));
+    assert_eq!(found("bar"), None);
+    assert_eq!(found("foo_bar"), by_name("foo-bar"));
+    assert_eq!(found("app"), by_name("app"));
+}
+
+#[test]
+fn missing_json_root_modules_are_reported() {
+    let dir = tempfile::tempdir().unwrap();
+    let present = dir.path().join("present/lib.rs");
+    let missing = dir.path().join("missing/lib.rs");
+    fs::create_dir_all(present.parent().unwrap()).unwrap();
+    fs::write(&present, "").unwrap();
+    let project = json_project(json!({
+        "crates": [
+            { "root_module": present, "edition": "2018", "deps": [] },
+            { "root_module": missing, "edition": "2018", "deps": [] },
+        ]
+    }));
+
+    assert_eq!(
+        project.validate(),
+        vec![JsonProjectError::MissingRootModule {
+            crate_id: crate::json_project::CrateId(1),
+            path: missing,
+        }]
+    );
+}
+
+#[test]
+fn crate_graph_is_stable_across_reloads() {
+    let workspace = || {
+        let mut foo = package("foo", "0.1.0");
+        foo["targets"].as_array_mut().unwrap().push(target("foo", &["bin"], "/ws/foo/src/main.rs"));
+        let mut meta = metadata(vec![foo, package("bar", "0.1.0"), package("baz", "0.1.0")]);
+        add_dep(&mut meta, 0, 1, "bar");
+        add_dep(&mut meta, 0, 2, "baz");
+        add_dep(&mut meta, 1, 2, "baz");
+        let cargo_ws =
+            ProjectWorkspace::Cargo { cargo: cargo_workspace(meta), sysroot: Sysroot::default() };
+        let overlay = json_project(json!({
+            "crates": [
+                { "root_module": "/gen/a/lib.rs", "edition": "2018", "deps": [] },
+                {
+                    "root_module": "/gen/b/lib.rs",
+                    "edition": "2015",
+                    "deps": [{ "crate": 0, "name": "a" }],
+                },
+            ]
+        }));
+        ProjectWorkspace::merge(cargo_ws, ProjectWorkspace::Json { project: overlay }).unwrap()
+    };
+
+    let mut files = FxHashMap::default();
+    let mut load = |path: &Path| {
+        let next_id = FileId(files.len() as u32);
+        Some(*files.entry(path.to_path_buf()).or_insert(next_id))
+    };
+    let first = workspace().to_crate_graph(&mut load);
+    let second = workspace().to_crate_graph(&mut load);
+    assert_eq!(first, second);
+
+    let roots = |graph: &CrateGraph| -> Vec<(CrateId, FileId)> {
+        let mut res: Vec<_> = graph.iter().map(|krate| (krate, graph.crate_root(krate))).collect();
+        res.sort();
+        res
+    };
+    assert_eq!(roots(&first), roots(&second));
+    assert_eq!(roots(&first).len(), 6);
+}
+
+#[test]
+fn sysroot_test_and_proc_macro_depend_on_std() {
+    let dir = tempfile::tempdir().unwrap();
+    for krate in &["std", "core", "proc_macro", "test"] {
+        let krate_dir = dir.path().join(format!("lib{}", krate));
+        fs::create_dir_all(&krate_dir).unwrap();
+        fs::write(krate_dir.join("lib.rs"), "").unwrap();
+    }
+    let sysroot = Sysroot::load(dir.path(), SysrootLayout::Src);
+
+    for krate in &[sysroot.test().unwrap(), sysroot.proc_macro().unwrap()] {
+        let deps: Vec<&str> = krate.deps(&sysroot).map(|it| it.name(&sysroot)).collect();
+        assert_eq!(deps, vec!["std", "core"]);
+    }
+}
+
+#[test]
+fn n_packages_matches_iterated_count() {
+    let dir = tempfile::tempdir().unwrap();
+    for krate in &["std", "core"] {
+        let krate_dir = dir.path().join(format!("lib{}", krate));
+        fs::create_dir_all(&krate_dir).unwrap();
+        fs::write(krate_dir.join("lib.rs"), "").unwrap();
+    }
+    let sysroot = Sysroot::load(dir.path(), SysrootLayout::Src);
+    let cargo = cargo_workspace(metadata(vec![package("foo", "0.1.0"), package("bar", "0.1.0")]));
+    let iterated = cargo.packages().count() + sysroot.crates().count();
+    let cargo_ws = ProjectWorkspace::Cargo { cargo, sysroot };
+    assert_eq!(cargo_ws.n_packages(), iterated);
+    assert_eq!(iterated, 4);
+
+    let overlay = json_project(json!({
+        "crates": [{ "root_module": "/gen/lib.rs", "edition": "2018", "deps": [] }]
+    }));
+    let ws =
+        ProjectWorkspace::merge(cargo_ws, ProjectWorkspace::Json { project: overlay }).unwrap();
+    assert_eq!(ws.n_packages(), iterated + 1);
+}
+
+#[test]
+fn workspaces_remember_their_manifest() {
+    let meta = metadata(vec![package("foo", "0.1.0")]);
+    let cargo = cargo_workspace_with_config(meta, "/ws/foo/Cargo.toml", &CargoConfig::default());
+    assert_eq!(cargo.manifest_path(), Path::new("/ws/foo/Cargo.toml"));
+    assert_eq!(cargo.workspace_manifest_path(), Path::new("/ws/Cargo.toml"));
+    let cargo_ws = ProjectWorkspace::Cargo { cargo, sysroot: Sysroot::default() };
+    assert_eq!(cargo_ws.manifest_path(), Some(Path::new("/ws/foo/Cargo.toml")));
+
+    let overlay = json_project(json!({ "crates": [] }));
+    assert_eq!(ProjectWorkspace::Json { project: overlay.clone() }.manifest_path(), None);
+    let merged =
+        ProjectWorkspace::merge(cargo_ws, ProjectWorkspace::Json { project: overlay }).unwrap();
+    assert_eq!(merged.manifest_path(), Some(Path::new("/ws/foo/Cargo.toml")));
+
+    let dir = tempfile::tempdir().unwrap();
+    let json_path = dir.path().join("rust-project.json");
+    fs::write(&json_path, r#"{ "crates": [] }"#).unwrap();
+    let json_ws = ProjectWorkspace::discover(dir.path(), &CargoConfig::default()).unwrap();
+    let json_path = json_path.canonicalize().unwrap();
+    assert_eq!(json_ws.manifest_path(), Some(json_path.as_path()));
+
+    let saved = dir.path().join("workspace.json");
+    json_ws.save(&saved).unwrap();
+    let loaded = ProjectWorkspace::load(&saved).unwrap();
+    assert_eq!(loaded.manifest_path(), Some(json_path.as_path()));
+}
+
+#[test]
+fn local_patches_inside_the_workspace_are_members() {
+    let mut patched = package("patched", "0.2.0");
+    patched["manifest_path"] = json!("/ws/vendor/patched/Cargo.toml");
+    patched["targets"] = json!([target("patched", &["lib"], "/ws/vendor/patched/src/lib.rs")]);
+    let mut meta =
+        metadata(vec![package("foo", "0.1.0"), patched, registry_package("serde", "1.0.0")]);
+    meta["workspace_members"] = json!([pkg_id("foo", "0.1.0")]);
+    let mut patched_dep = dependency("patched", Value::Null);
+    patched_dep["source"] = json!("registry+https://github.com/rust-lang/crates.io-index");
+    meta["packages"][0]["dependencies"] = json!([patched_dep]);
+    add_dep(&mut meta, 0, 1, "patched");
+    add_dep(&mut meta, 0, 2, "serde");
+    let cargo = cargo_workspace(meta);
+
+    let members: Vec<_> = cargo
+        .packages()
+        .map(|pkg| (pkg.name(&cargo), pkg.is_member(&cargo), pkg.is_default_member(&cargo)))
+        .collect();
+    assert_eq!(
+        members,
+        vec![("foo", true, true), ("patched", true, false), ("serde", false, false)]
+    );
+
+    let ws = ProjectWorkspace::Cargo { cargo, sysroot: Sysroot::default() };
+    let graph = TestCrateGraph::new(&ws, &[]);
+    assert_eq!(graph.deps("/ws/foo/src/lib.rs"), vec!["patched", "serde"]);
+    let patched = graph.crate_for_root("/ws/vendor/patched/src/lib.rs");
+    let foo = graph.crate_for_root("/ws/foo/src/lib.rs");
+    assert!(graph.graph.dependencies(foo).any(|dep| dep.crate_id() == patched));
+}
+
+#[test]
+fn path_dependencies_inside_the_workspace_are_not_members() {
+    let mut local = package("local", "0.1.0");
+    local["manifest_path"] = json!("/ws/libs/local/Cargo.toml");
+    local["targets"] = json!([target("local", &["lib"], "/ws/libs/local/src/lib.rs")]);
+    let mut meta = metadata(vec![package("foo", "0.1.0"), local]);
+    meta["workspace_members"] = json!([pkg_id("foo", "0.1.0")]);
+    meta["packages"][0]["dependencies"] = json!([dependency("local", Value::Null)]);
+    add_dep(&mut meta, 0, 1, "local");
+    let cargo = cargo_workspace(meta);
+
+    let members: Vec<_> =
+        cargo.packages().map(|pkg| (pkg.name(&cargo), pkg.is_member(&cargo))).collect();
+    assert_eq!(members, vec![("foo", true), ("local", false)]);
+}
+
+#[test]
+fn fingerprints_only_change_with_contents() {
+    let meta = || metadata(vec![package("foo", "0.1.0")]);
+    assert_eq!(cargo_workspace(meta()).fingerprint(), cargo_workspace(meta()).fingerprint());
+    let mut changed = meta();
+    changed["packages"][0]["edition"] = json!("2015");
+    assert_ne!(cargo_workspace(meta()).fingerprint(), cargo_workspace(changed).fingerprint());
+}
+
+#[test]
+fn reloading_unchanged_workspace_is_a_no_op() {
+    let dir = tempfile::tempdir().unwrap();
+    let manifest = dir.path().join("rust-project.json");
+    let text = r#"{ "crates": [{ "root_module": "/ws/lib.rs", "edition": "2018", "deps": [] }] }"#;
+    fs::write(&manifest, text).unwrap();
+    let ws = ProjectWorkspace::discover(&manifest, &CargoConfig::default()).unwrap();
+
+    // Rewritten with the same contents, for example by a formatter.
+    fs::write(&manifest, format!("{}\n", text)).unwrap();
+    assert!(ws.reload(&CargoConfig::default()).unwrap().is_none());
+
+    fs::write(&manifest, text.replace("2018", "2015")).unwrap();
+    let reloaded = ws.reload(&CargoConfig::default()).unwrap().unwrap();
+    assert_ne!(reloaded.fingerprint(), ws.fingerprint());
+}
+
+#[test]
+fn json_crates_have_display_names() {
+    let project = json_project(json!({
+        "roots": ["/ws"],
+        "crates": [
+            {
+                "root_module": "/ws/foo/lib.rs",
+                "edition": "2018",
+                "deps": [],
+                "display_name": "foo-rs",
+            },
+            { "root_module": "/ws/bar/bar.rs", "edition": "2018", "deps": [] },
+        ],
+    }));
+    let ws = ProjectWorkspace::Json { project };
+
+    let graph = TestCrateGraph::new(&ws, &[]);
+    let display_name = |root| graph.graph.display_name(graph.crate_for_root(root));
+    assert_eq!(display_name("/ws/foo/lib.rs"), Some("foo-rs"));
+    assert_eq!(display_name("/ws/bar/bar.rs"), Some("bar"));
+}
+
+#[test]
+fn building_crate_graph_can_be_canceled() {
+    let mut meta =
+        metadata(vec![package("a", "0.1.0"), package("b", "0.1.0"), package("c", "0.1.0")]);
+    add_dep(&mut meta, 0, 1, "b");
+    add_dep(&mut meta, 1, 2, "c");
+    let cargo = cargo_workspace(meta);
+    let ws = ProjectWorkspace::Cargo { cargo, sysroot: Sysroot::default() };
+
+    let loaded = Cell::new(0);
+    let mut load = |_: &Path| {
+        loaded.set(loaded.get() + 1);
+        Some(FileId(loaded.get()))
+    };
+    let checks = Cell::new(0);
+    let should_cancel = || {
+        checks.set(checks.get() + 1);
+        loaded.get() == 1
+    };
+    let res = ws.to_crate_graph_cancelable(&mut load, &should_cancel);
+    assert!(res.is_none());
+    assert_eq!(loaded.get(), 1);
+    // No package after the canceled one is visited.
+    assert_eq!(checks.get(), 2);
+
+    let graph = ws.to_crate_graph_cancelable(&mut load, &|| false).unwrap();
+    assert_eq!(graph.iter().count(), 3);
+}
+
+#[test]
+fn transitive_deps_of_diamond() {
+    let mut meta = metadata(vec![
+        package("a", "0.1.0"),
+        package("b", "0.1.0"),
+        package("c", "0.1.0"),
+        package("d", "0.1.0"),
+    ]);
+    add_dep(&mut meta, 0, 1, "b");
+    add_dep(&mut meta, 0, 2, "c");
+    add_dep(&mut meta, 1, 3, "d");
+    add_dep(&mut meta, 2, 3, "d");
+    // dev-dependencies can form cycles
+    add_dep(&mut meta, 3, 0, "a");
+    let cargo = cargo_workspace(meta);
+    let names = |pkg| -> Vec<_> {
+        cargo.transitive_deps(pkg).into_iter().map(|it| it.name(&cargo).to_string()).collect()
+    };
+    let pkgs: Vec<_> = cargo.packages().collect();
+
+    assert_eq!(names(pkgs[0]), vec!["b", "c", "d"]);
+    assert_eq!(names(pkgs[1]), vec!["d", "a", "c"]);
+    assert_eq!(names(pkgs[3]), vec!["a", "b", "c"]);
+}
+
+#[test]
+fn json_crates_share_cfg_groups() {
+    let project = json_project(json!({
+        "roots": ["/ws"],
+        "cfg_groups": { "testing": ["test", "debug_assertions"] },
+        "crates": [
+            {
+                "root_module": "/ws/foo/lib.rs",
+                "edition": "2018",
+                "deps": [],
+                "cfg": ["feature=\"std\""],
+                "cfg_groups": ["testing"],
+            },
+            {
+                "root_module": "/ws/bar/lib.rs",
+                "edition": "2018",
+                "deps": [],
+                "cfg": ["debug_assertions"],
+            },
+            { "root_module": "/ws/baz/lib.rs", "edition": "2018", "deps": [] },
+            { "root_module": "/ws/qux/lib.rs", "edition": "2018", "deps": [], "cfg_groups": ["nope"] },
+        ],
+    }));
+    let crate_id = crate::json_project::CrateId;
+
+    assert_eq!(project.cfg(crate_id(0)), Some(vec!["debug_assertions", "feature=\"std\"", "test"]));
+    assert_eq!(
+        project
+            .validate()
+            .into_iter()
+            .filter(|it| match it {
+                JsonProjectError::UnknownCfgGroup { .. } => true,
+                _ => false,
+            })
+            .collect::<Vec<_>>(),
+        vec![JsonProjectError::UnknownCfgGroup {
+            crate_id: crate_id(3),
+            group: "nope".to_string()
+        }]
+    );
+
+    let ws = ProjectWorkspace::Json { project };
+    let graph = TestCrateGraph::new(&ws, &[]);
+    let cfg_test = |root| graph.graph.cfg_test(graph.crate_for_root(root));
+    assert!(cfg_test("/ws/foo/lib.rs"));
+    assert!(!cfg_test("/ws/bar/lib.rs"));
+    assert!(!cfg_test("/ws/baz/lib.rs"));
+    let cfg_options = graph.graph.cfg_options(graph.crate_for_root("/ws/foo/lib.rs"));
+    assert!(cfg_options.is_atom_enabled("debug_assertions"));
+    assert!(cfg_options.is_key_value_enabled("feature", "std"));
+}
+
+#[test]
+fn json_projects_with_unknown_cfg_groups_are_rejected() {
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(dir.path().join("lib.rs"), "").unwrap();
+    let crates = json!([{
+        "root_module": dir.path().join("lib.rs"),
+        "edition": "2018",
+        "deps": [],
+        "cfg_groups": ["nope"],
+    }]);
+    let json = json!({ "crates": crates, "cfg_groups": {} });
+    fs::write(dir.path().join("rust-project.json"), json.to_string()).unwrap();
+
+    let err = ProjectWorkspace::discover(dir.path(), &CargoConfig::default()).unwrap_err();
+    assert_eq!(
+        err.downcast_ref::<JsonProjectError>(),
+        Some(&JsonProjectError::UnknownCfgGroup {
+            crate_id: crate::json_project::CrateId(0),
+            group: "nope".to_string()
+        })
+    );
+
+    let json = json!({ "crates": crates, "cfg_groups": { "nope": ["test"] } });
+    fs::write(dir.path().join("rust-project.json"), json.to_string()).unwrap();
+    assert!(ProjectWorkspace::discover(dir.path(), &CargoConfig::default()).is_ok());
+}
+
+thread_local! {
+    static LOGGED: RefCell<Vec<String>> = RefCell::new(Vec::new());
+}
+
+/// Records messages per thread, so that tests running in parallel only see
+/// their own.
+struct ThreadLogger;
+
+impl log::Log for ThreadLogger {
+    fn enabled(&self, _metadata: &log::Metadata) -> bool {
+        true
+    }
+    fn log(&self, record: &log::Record) {
+        LOGGED.with(|it| it.borrow_mut().push(record.args().to_string()));
+    }
+    fn flush(&self) {}
+}
+
+/// Runs `f` and returns the messages it logged.
+fn capture_logs(f: impl FnOnce()) -> Vec<String> {
+    static LOGGER: ThreadLogger = ThreadLogger;
+    static INIT: Once = Once::new();
+    INIT.call_once(|| {
+        log::set_logger(&LOGGER).unwrap();
+        log::set_max_level(log::LevelFilter::Debug);
+    });
+    LOGGED.with(|it| it.borrow_mut().clear());
+    f();
+    LOGGED.with(|it| mem::replace(&mut *it.borrow_mut(), Vec::new()))
+}
+
+#[test]
+fn loading_workspaces_logs_timings() {
+    let dir = tempfile::tempdir().unwrap();
+    let root = dir.path().join("foo");
+    fs::create_dir_all(root.join("src")).unwrap();
+    fs::write(
+        root.join("Cargo.toml"),
+        "[package]\nname = \"foo\"\nversion = \"0.1.0\"\n\n[workspace]\n",
+    )
+    .unwrap();
+    fs::write(root.join("src/lib.rs"), "").unwrap();
+    let sysroot_dir = dir.path().join("sysroot");
+    fs::create_dir_all(sysroot_dir.join("libstd")).unwrap();
+    fs::write(sysroot_dir.join("libstd/lib.rs"), "").unwrap();
+    let cargo_toml = root.join("Cargo.toml").canonicalize().unwrap();
+
+    let logs = capture_logs(|| {
+        let cargo =
+            CargoWorkspace::from_cargo_metadata(&cargo_toml, &CargoConfig::default()).unwrap();
+        let sysroot = Sysroot::load(&sysroot_dir, SysrootLayout::Src);
+        TestCrateGraph::new(&ProjectWorkspace::Cargo { cargo, sysroot }, &[]);
+    });
+    let is_logged = |prefix: &str, suffix: &str| {
+        logs.iter().any(|it| it.starts_with(prefix) && it.contains(" in ") && it.ends_with(suffix))
+    };
+    assert!(is_logged("loaded cargo workspace", ": 1 packages, 1 targets"), "{:?}", logs);
+    assert!(is_logged("loaded sysroot", ": 1 crates"), "{:?}", logs);
+    assert!(is_logged("built crate graph with 2 crates", ""), "{:?}", logs);
+}
+
+#[test]
+fn pinned_toolchain_is_found() {
+    let dir = tempfile::tempdir().unwrap();
+    let member = dir.path().join("crates/foo");
+    fs::create_dir_all(&member).unwrap();
+    assert_eq!(pinned_toolchain(&member), None);
+
+    fs::write(dir.path().join("rust-toolchain"), "nightly-2019-06-01\n").unwrap();
+    assert_eq!(pinned_toolchain(&member), Some("nightly-2019-06-01".to_string()));
+
+    fs::write(
+        dir.path().join("crates/rust-toolchain.toml"),
+        "[toolchain]\nchannel = \"1.35.0\"\ncomponents = [\"rust-src\"]\n",
+    )
+    .unwrap();
+    assert_eq!(pinned_toolchain(&member), Some("1.35.0".to_string()));
+}
+
+#[test]
+fn focused_member_and_its_path_deps_are_eager() {
+    let mut meta = metadata(vec![
+        package("app", "0.1.0"),
+        package("util", "0.1.0"),
+        package("other", "0.1.0"),
+        registry_package("serde", "1.0.0"),
+    ]);
+    meta["workspace_members"] = json!([pkg_id("app", "0.1.0"), pkg_id("other", "0.1.0")]);
+    add_dep(&mut meta, 0, 1, "util");
+    add_dep(&mut meta, 0, 3, "serde");
+    add_dep(&mut meta, 2, 1, "util");
+    let config = CargoConfig { focus_member: Some("app".to_string()), ..CargoConfig::default() };
+    let cargo = cargo_workspace_with_config(meta, "/ws/Cargo.toml", &config);
+
+    let eager: Vec<_> =
+        cargo.packages().filter(|pkg| pkg.is_eager(&cargo)).map(|pkg| pkg.name(&cargo)).collect();
+    assert_eq!(eager, vec!["app", "util"]);
+
+    // The crate graph still has all edges.
+    let ws = ProjectWorkspace::Cargo { cargo, sysroot: Sysroot::default() };
+    let graph = TestCrateGraph::new(&ws, &[]);
+    assert_eq!(graph.deps("/ws/app/src/lib.rs"), vec!["serde", "util"]);
+    assert_eq!(graph.deps("/ws/other/src/lib.rs"), vec!["util"]);
+
+    let roots: Vec<_> =
+        ws.to_roots().into_iter().map(|root| (root.path().clone(), root.is_eager())).collect();
+    assert_eq!(
+        roots,
+        vec![
+            (PathBuf::from("/ws/app"), true),
+            (PathBuf::from("/ws/util"), true),
+            (PathBuf::from("/ws/other"), false),
+            (PathBuf::from("/ws/serde"), false),
+        ]
+    );
+}
+
+#[test]
+fn sysroot_alone_has_a_crate_graph() {
+    let dir = tempfile::tempdir().unwrap();
+    for krate in &["std", "core", "alloc"] {
+        let krate_dir = dir.path().join(format!("lib{}", krate));
+        fs::create_dir_all(&krate_dir).unwrap();
+        fs::write(krate_dir.join("lib.rs"), "").unwrap();
+    }
+    let sysroot = Sysroot::load(dir.path(), SysrootLayout::Src);
+
+    let mut files = FxHashMap::default();
+    let graph = sysroot.to_crate_graph(&mut |path: &Path| {
+        let next_id = FileId(files.len() as u32);
+        Some(*files.entry(path.to_path_buf()).or_insert(next_id))
+    });
+    let krate = |name: &str| {
+        let file_id = files[&dir.path().join(format!("lib{}/lib.rs", name))];
+        graph.crate_id_for_crate_root(file_id).unwrap()
+    };
+    assert_eq!(graph.iter().count(), 3);
+    let std_deps: Vec<_> = graph
+        .dependencies(krate("std"))
+        .map(|dep| (dep.name.to_string(), dep.crate_id()))
+        .collect();
+    assert!(std_deps.contains(&("core".to_string(), krate("core"))));
+}
+
+#[test]
+fn virtual_workspace_without_members_is_an_error() {
+    // `members = ["crates/*"]` with an empty `crates` directory.
+    let meta = metadata(Vec::new());
+    let err = CargoWorkspace::from_json(meta, Path::new("/ws/Cargo.toml"), &CargoConfig::default())
+        .unwrap_err();
+    assert_eq!(
+        err.downcast_ref::<ProjectModelError>(),
+        Some(&ProjectModelError::EmptyWorkspace { manifest: PathBuf::from("/ws/Cargo.toml") })
+    );
+}
+
+#[test]
+fn members_only_skips_external_crates() {
+    let mut meta = metadata(vec![
+        package("app", "0.1.0"),
+        package("util", "0.1.0"),
+        registry_package("serde", "1.0.0"),
+    ]);
+    meta["workspace_members"] = json!([pkg_id("app", "0.1.0"), pkg_id("util", "0.1.0")]);
+    add_dep(&mut meta, 0, 1, "util");
+    add_dep(&mut meta, 0, 2, "serde");
+    add_dep(&mut meta, 1, 2, "serde");
+    let config = CargoConfig { members_only: true, ..CargoConfig::default() };
+    let cargo = cargo_workspace_with_config(meta, "/ws/Cargo.toml", &config);
+
+    let packages: Vec<_> = cargo.packages().map(|pkg| pkg.name(&cargo).to_string()).collect();
+    assert_eq!(packages, vec!["app", "util"]);
+
+    let ws = ProjectWorkspace::Cargo { cargo, sysroot: Sysroot::default() };
+    let graph = TestCrateGraph::new(&ws, &[]);
+    assert_eq!(graph.graph.iter().count(), 2);
+    assert_eq!(graph.deps("/ws/app/src/lib.rs"), vec!["util"]);
+    assert!(graph.deps("/ws/util/src/lib.rs").is_empty());
+}
+
+#[test]
+fn all_crate_types_are_exposed() {
+    let mut pkg = package("ffi", "0.1.0");
+    let mut lib = target("ffi", &["lib"], "/ws/ffi/src/lib.rs");
+    lib["crate_types"] = json!(["rlib", "cdylib", "staticlib"]);
+    pkg["targets"] = json!([lib]);
+    let cargo = cargo_workspace(metadata(vec![pkg]));
+
+    let tgt = cargo.packages().next().unwrap().lib_target(&cargo).unwrap();
+    assert_eq!(tgt.kind(&cargo), TargetKind::Lib);
+    assert_eq!(tgt.crate_types(&cargo), ["rlib", "cdylib", "staticlib"]);
+}
+
+#[test]
+fn detached_files_are_single_crates_depending_on_std() {
+    let dir = tempfile::tempdir().unwrap();
+    let file = dir.path().join("scratch.rs");
+    fs::write(&file, "fn main() {}").unwrap();
+    let config = CargoConfig { load_sysroot: false, ..CargoConfig::default() };
+    let file = file.canonicalize().unwrap();
+    let ws = ProjectWorkspace::discover(&file, &config).unwrap();
+    match &ws {
+        ProjectWorkspace::DetachedFile { file: it, .. } => assert_eq!(it, &file),
+        _ => panic!("expected a detached file, got {:?}", ws),
+    }
+
+    let sysroot_dir = tempfile::tempdir().unwrap();
+    for krate in &["std", "core"] {
+        let krate_dir = sysroot_dir.path().join(format!("lib{}", krate));
+        fs::create_dir_all(&krate_dir).unwrap();
+        fs::write(krate_dir.join("lib.rs"), "").unwrap();
+    }
+    let sysroot = Sysroot::load(sysroot_dir.path(), SysrootLayout::Src);
+    let ws = ProjectWorkspace::DetachedFile { file: file.clone(), sysroot };
+    let graph = TestCrateGraph::new(&ws, &[]);
+    assert_eq!(graph.graph.iter().count(), 3);
+    assert_eq!(graph.deps(file.to_str().unwrap()), vec!["std"]);
+    let root = ws.crate_root_for_file(&file).unwrap();
+    assert_eq!(root.edition, Edition::Edition2018);
+}
+
+#[test]
+fn dependency_listed_under_several_kinds_is_a_single_edge() {
+    let mut meta = metadata(vec![package("foo", "0.1.0"), package("bar", "0.1.0")]);
+    meta["packages"][0]["dependencies"] =
+        json!([dependency("bar", Value::Null), dependency("bar", json!("dev"))]);
+    add_dep(&mut meta, 0, 1, "bar");
+    add_dep(&mut meta, 0, 1, "bar");
+    let cargo = cargo_workspace(meta);
+    let foo = cargo.packages().find(|pkg| pkg.name(&cargo) == "foo").unwrap();
+    assert_eq!(foo.dependencies(&cargo).count(), 2);
+
+    let ws = ProjectWorkspace::Cargo { cargo, sysroot: Sysroot::default() };
+    let graph = TestCrateGraph::new(&ws, &[]);
+    assert_eq!(graph.deps("/ws/foo/src/lib.rs"), vec!["bar"]);
+}
+
+#[test]
+fn roots_know_their_origin() {
+    let rust_src = tempfile::tempdir().unwrap();
+    let core_dir = rust_src.path().join("libcore");
+    fs::create_dir_all(&core_dir).unwrap();
+    fs::write(core_dir.join("lib.rs"), "").unwrap();
+    let sysroot = Sysroot::load(rust_src.path(), SysrootLayout::Src);
+
+    let mut meta = metadata(vec![package("foo", "0.1.0"), registry_package("dep", "0.1.0")]);
+    meta["workspace_members"] = json!([pkg_id("foo", "0.1.0")]);
+    let ws = ProjectWorkspace::Cargo { cargo: cargo_workspace(meta), sysroot };
+
+    let origins: Vec<_> =
+        ws.to_roots().iter().map(|root| (root.path().clone(), root.origin())).collect();
+    assert_eq!(
+        origins,
+        vec![
+            (PathBuf::from("/ws/foo"), RootOrigin::Member),
+            (PathBuf::from("/ws/dep"), RootOrigin::External),
+            (core_dir, RootOrigin::Sysroot),
+        ]
+    );
+    assert!(ws
+        .to_roots()
+        .iter()
+        .all(|root| root.is_member() == (root.origin() == RootOrigin::Member)));
+}
+
+#[test]
+fn no_deps_metadata_is_parsed() {
+    // Trimmed output of `cargo metadata --no-deps` for a package with a
+    // registry dependency.
+    let meta: Value = serde_json::from_str(
+        r#"{
+            "packages": [{
+                "name": "foo",
+                "version": "0.1.0",
+                "id": "foo 0.1.0 (path+file:///ws/foo)",
+                "license": null,
+                "license_file": null,
+                "description": null,
+                "source": null,
+                "dependencies": [{
+                    "name": "serde",
+                    "source": "registry+https://github.com/rust-lang/crates.io-index",
+                    "req": "^1.0",
+                    "kind": null,
+                    "rename": null,
+                    "optional": false,
+                    "uses_default_features": true,
+                    "features": [],
+                    "target": null,
+                    "registry": null
+                }],
+                "targets": [{
+                    "kind": ["lib"],
+                    "crate_types": ["lib"],
+                    "name": "foo",
+                    "src_path": "/ws/foo/src/lib.rs",
+                    "edition": "2018"
+                }],
+                "features": {},
+                "manifest_path": "/ws/foo/Cargo.toml",
+                "metadata": null,
+                "authors": [],
+                "categories": [],
+                "keywords": [],
+                "readme": null,
+                "repository": null,
+                "edition": "2018",
+                "links": null
+            }],
+            "workspace_members": ["foo 0.1.0 (path+file:///ws/foo)"],
+            "resolve": null,
+            "target_directory": "/ws/foo/target",
+            "version": 1,
+            "workspace_root": "/ws/foo"
+        }"#,
+    )
+    .unwrap();
+    let cargo = cargo_workspace_with_config(meta, "/ws/foo/Cargo.toml", &CargoConfig::default());
+    let foo = cargo.packages().next().unwrap();
+    assert_eq!(cargo.packages().count(), 1);
+    assert_eq!(foo.dependencies(&cargo).count(), 0);
+
+    let ws = ProjectWorkspace::Cargo { cargo, sysroot: Sysroot::default() };
+    let graph = TestCrateGraph::new(&ws, &[]);
+    assert_eq!(graph.graph.iter().count(), 1);
+    assert!(graph.deps("/ws/foo/src/lib.rs").is_empty());
+}
+
+#[test]
+fn single_package_of_virtual_manifest_loads_all_members() {
+    let mut meta = metadata(vec![package("foo", "0.1.0"), package("bar", "0.1.0")]);
+    meta["resolve"] = Value::Null;
+    let config = CargoConfig { single_package: true, ..CargoConfig::default() };
+    let cargo = cargo_workspace_with_config(meta, "/ws/Cargo.toml", &config);
+
+    let packages: Vec<_> = cargo.packages().map(|pkg| pkg.name(&cargo).to_string()).collect();
+    assert_eq!(packages, vec!["foo", "bar"]);
+    assert_eq!(cargo.workspace_root, Path::new("/ws"));
+    assert_eq!(cargo.primary_package(), None);
+}
+
+#[test]
+fn dev_dependencies_are_only_visible_to_tests_benches_and_examples() {
+    let mut foo = package("foo", "0.1.0");
+    foo["targets"] = json!([
+        target("foo", &["lib"], "/ws/foo/src/lib.rs"),
+        target("foo", &["bin"], "/ws/foo/src/main.rs"),
+        target("it", &["test"], "/ws/foo/tests/it.rs"),
+        target("speed", &["bench"], "/ws/foo/benches/speed.rs"),
+        target("demo", &["example"], "/ws/foo/examples/demo.rs"),
+    ]);
+    foo["dependencies"] = json!([dependency("bar", json!("dev"))]);
+    let mut meta = metadata(vec![foo, package("bar", "0.1.0")]);
+    add_dep(&mut meta, 0, 1, "bar");
+    let ws = ProjectWorkspace::Cargo { cargo: cargo_workspace(meta), sysroot: Sysroot::default() };
+
+    let graph = TestCrateGraph::new(&ws, &[]);
+    assert!(graph.deps("/ws/foo/src/lib.rs").is_empty());
+    assert_eq!(graph.deps("/ws/foo/src/main.rs"), vec!["foo"]);
+    assert_eq!(graph.deps("/ws/foo/tests/it.rs"), vec!["bar", "foo"]);
+    assert_eq!(graph.deps("/ws/foo/benches/speed.rs"), vec!["bar", "foo"]);
+    assert_eq!(graph.deps("/ws/foo/examples/demo.rs"), vec!["bar", "foo"]);
+}
+
+#[test]
+fn unloadable_json_crate_root_is_a_problem() {
+    let project = json_project(json!({
+        "roots": ["/gen"],
+        "crates": [
+            { "root_module": "/gen/a.rs", "edition": "2018", "deps": [] },
+            { "root_module": "/gen/b.rs", "edition": "2018", "deps": [] },
+        ],
+    }));
+    let ws = ProjectWorkspace::Json { project };
+
+    let graph = TestCrateGraph::new(&ws, &["/gen/b.rs"]);
+    assert_eq!(graph.graph.iter().count(), 1);
+    assert_eq!(
+        graph.problems,
+        vec![CrateGraphProblem::CrateRootNotLoaded { root: PathBuf::from("/gen/b.rs") }]
+    );
+}
+
+#[test]
+fn only_tests_and_benches_are_compiled_with_cfg_test() {
+    let mut foo = package("foo", "0.1.0");
+    foo["targets"] = json!([
+        target("foo", &["lib"], "/ws/foo/src/lib.rs"),
+        target("foo", &["bin"], "/ws/foo/src/main.rs"),
+        target("it", &["test"], "/ws/foo/tests/it.rs"),
+        target("speed", &["bench"], "/ws/foo/benches/speed.rs"),
+        target("demo", &["example"], "/ws/foo/examples/demo.rs"),
+    ]);
+    let ws = ProjectWorkspace::Cargo {
+        cargo: cargo_workspace(metadata(vec![foo])),
+        sysroot: Sysroot::default(),
+    };
+
+    let graph = TestCrateGraph::new(&ws, &[]);
+    let cfg_test = |root| graph.graph.cfg_test(graph.crate_for_root(root));
+    assert!(!cfg_test("/ws/foo/src/lib.rs"));
+    assert!(!cfg_test("/ws/foo/src/main.rs"));
+    assert!(cfg_test("/ws/foo/tests/it.rs"));
+    assert!(cfg_test("/ws/foo/benches/speed.rs"));
+    assert!(!cfg_test("/ws/foo/examples/demo.rs"));
+}
@@ -19,6 +19,13 @@ pub fn tokenize(text: &str) -> Vec<Token> {
     }
     let mut text = text;
     let mut acc = Vec::new();
+    // rustc ignores a byte order mark. Treating it as whitespace keeps the
+    // offsets of the following tokens.
+    let bom = '\u{feff}';
+    if text.starts_with(bom) {
+        acc.push(Token { kind: WHITESPACE, len: TextUnit::from_usize(bom.len_utf8()) });
+        text = &text[bom.len_utf8()..];
+    }
     if let Some(len) = ra_rustc_lexer::strip_shebang(text) {
         acc.push(Token { kind: SHEBANG, len: TextUnit::from_usize(len) });
         text = &text[len..];
@@ -3,7 +3,11 @@
 //! By default, we include only `.rs` files, and skip some know offenders like
 //! `/target` or `/node_modules` altogether.
 //!
-//! It's also possible to add custom exclusion globs.
+//! It's also possible to add custom exclusion globs, as well as inclusion
+//! globs, which take precedence over exclusions (so that, for example,
+//! `target/generated` can be indexed even though `target` is ignored).
+
+use std::path::{Component, Path};
 
 use globset::{GlobSet, GlobSetBuilder};
 use ra_vfs::{Filter, RelativePath};
@@ -15,12 +19,19 @@ const IGNORED_FOR_NON_MEMBERS: &[&str] = &["examples/**", "tests/**", "benches/*
 
 pub struct RustPackageFilterBuilder {
     is_member: bool,
+    include: GlobSetBuilder,
+    include_prefixes: Vec<String>,
     exclude: GlobSetBuilder,
 }
 
 impl Default for RustPackageFilterBuilder {
     fn default() -> RustPackageFilterBuilder {
-        RustPackageFilterBuilder { is_member: false, exclude: GlobSetBuilder::new() }
+        RustPackageFilterBuilder {
+            is_member: false,
+            include: GlobSetBuilder::new(),
+            include_prefixes: Vec::new(),
+            exclude: GlobSetBuilder::new(),
+        }
     }
 }
 
@@ -33,8 +44,38 @@ impl RustPackageFilterBuilder {
         self.exclude.add(glob);
         self
     }
+    /// Excludes the directory at `dir`, relative to the package root. This is
+    /// useful for directories which aren't covered by default exclusions, like
+    /// a relocated `target` directory.
+    pub fn exclude_dir(self, dir: &Path) -> RustPackageFilterBuilder {
+        let components: Vec<_> = dir
+            .components()
+            .filter_map(|it| match it {
+                Component::Normal(it) => it.to_str(),
+                _ => None,
+            })
+            .collect();
+        if components.is_empty() {
+            return self;
+        }
+        let glob = format!("{}/**", components.join("/"));
+        match Glob::new(&glob) {
+            Ok(glob) => self.exclude(glob),
+            Err(_) => self,
+        }
+    }
+    /// Includes directories matching `glob` even if one of their ancestors is
+    /// excluded.
+    pub fn include(mut self, glob: Glob) -> RustPackageFilterBuilder {
+        let prefix = literal_prefix(glob.glob());
+        if !prefix.is_empty() {
+            self.include_prefixes.push(prefix);
+        }
+        self.include.add(glob);
+        self
+    }
     pub fn into_vfs_filter(self) -> Box<dyn Filter> {
-        let RustPackageFilterBuilder { is_member, mut exclude } = self;
+        let RustPackageFilterBuilder { is_member, include, include_prefixes, mut exclude } = self;
         for &glob in ALWAYS_IGNORED {
             exclude.add(Glob::new(glob).unwrap());
         }
@@ -43,17 +84,43 @@ impl RustPackageFilterBuilder {
                 exclude.add(Glob::new(glob).unwrap());
             }
         }
-        Box::new(RustPackageFilter { exclude: exclude.build().unwrap() })
+        Box::new(RustPackageFilter {
+            include: include.build().unwrap(),
+            include_prefixes,
+            exclude: exclude.build().unwrap(),
+        })
     }
 }
 
+/// Returns the leading path components of `glob` which contain no wildcards.
+fn literal_prefix(glob: &str) -> String {
+    glob.split('/')
+        .take_while(|component| !component.contains(|c| "*?[{".contains(c)))
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
 struct RustPackageFilter {
+    include: GlobSet,
+    /// Literal prefixes of `include` globs: we must walk through their
+    /// ancestors to reach the included directories.
+    include_prefixes: Vec<String>,
     exclude: GlobSet,
 }
 
+impl RustPackageFilter {
+    fn is_explicitly_included(&self, dir_path: &str) -> bool {
+        self.include.is_match(dir_path)
+            || self.include_prefixes.iter().any(|prefix| {
+                prefix.starts_with(dir_path)
+                    && (prefix.len() == dir_path.len() || prefix[dir_path.len()..].starts_with('/'))
+            })
+    }
+}
+
 impl Filter for RustPackageFilter {
     fn include_dir(&self, dir_path: &RelativePath) -> bool {
-        !self.exclude.is_match(dir_path.as_str())
+        self.is_explicitly_included(dir_path.as_str()) || !self.exclude.is_match(dir_path.as_str())
     }
 
     fn include_file(&self, file_path: &RelativePath) -> bool {
@@ -91,4 +158,30 @@ fn test_globs() {
         .into_vfs_filter();
 
     assert!(!filter.include_dir(RelativePath::new("src/llvm-project/clang")));
+
+    let filter = RustPackageFilterBuilder::default()
+        .set_member(true)
+        .exclude_dir(Path::new("build/shared-target"))
+        .into_vfs_filter();
+
+    assert!(!filter.include_dir(RelativePath::new("build/shared-target")));
+    assert!(!filter.include_dir(RelativePath::new("build/shared-target/debug")));
+    assert!(filter.include_dir(RelativePath::new("build")));
+    assert!(filter.include_dir(RelativePath::new("build/shared")));
+}
+
+#[test]
+fn test_include_inside_excluded_dir() {
+    let filter = RustPackageFilterBuilder::default()
+        .set_member(true)
+        .include(Glob::new("target/generated/**").unwrap())
+        .into_vfs_filter();
+
+    assert!(filter.include_dir(RelativePath::new("target")));
+    assert!(filter.include_dir(RelativePath::new("target/generated")));
+    assert!(filter.include_dir(RelativePath::new("target/generated/foo")));
+
+    assert!(!filter.include_dir(RelativePath::new("target/debug")));
+    assert!(!filter.include_dir(RelativePath::new("target/gen")));
+    assert!(!filter.include_dir(RelativePath::new("target/generated_other")));
 }